@@ -2,9 +2,13 @@ use crate::detections::configs;
 use crate::detections::configs::{CURRENT_EXE_PATH, TERM_SIZE};
 use crate::detections::message::{self, LEVEL_ABBR};
 use crate::detections::message::{AlertMessage, LEVEL_FULL};
+use crate::detections::rule::MatchedField;
 use crate::detections::utils::{self, format_time};
 use crate::detections::utils::{get_writable_color, write_color_buffer};
 use crate::options::profile::PROFILES;
+use crate::options::suppression_list::SUPPRESSION_LIST;
+use crate::options::throttle::Throttle;
+use crate::options::update_rules::RULES_REVISION;
 use bytesize::ByteSize;
 use chrono::{DateTime, Local, TimeZone, Utc};
 use comfy_table::modifiers::UTF8_ROUND_CORNERS;
@@ -16,6 +20,7 @@ use itertools::Itertools;
 use krapslog::{build_sparkline, build_time_markers};
 use lazy_static::lazy_static;
 use linked_hash_map::LinkedHashMap;
+use serde_json::json;
 use std::str::FromStr;
 
 use comfy_table::*;
@@ -169,7 +174,8 @@ fn _print_timeline_hist(timestamps: Vec<i64>, length: usize, side_margin_size: u
     buf_wtr.print(&wtr).ok();
 }
 
-pub fn after_fact(all_record_cnt: usize) {
+/// all_record_cntは解析したレコード数、skipped_record_cntは--timeline-startより前に書き込まれたため読み飛ばしたレコード数
+pub fn after_fact(all_record_cnt: usize, skipped_record_cnt: usize) {
     let fn_emit_csv_err = |err: Box<dyn Error>| {
         AlertMessage::alert(&format!("Failed to write CSV. {}", err)).ok();
         process::exit(1);
@@ -192,12 +198,18 @@ pub fn after_fact(all_record_cnt: usize) {
             Box::new(BufWriter::new(io::stdout()))
         };
     let color_map = set_output_color();
+    let throttle = Throttle::new(
+        configs::CONFIG.read().unwrap().args.max_per_rule,
+        configs::CONFIG.read().unwrap().args.dedup_window,
+    );
     if let Err(err) = emit_csv(
         &mut target,
         displayflag,
         color_map,
         all_record_cnt as u128,
+        skipped_record_cnt as u128,
         PROFILES.clone().unwrap_or_default(),
+        &throttle,
     ) {
         fn_emit_csv_err(Box::new(err));
     }
@@ -208,7 +220,9 @@ fn emit_csv<W: std::io::Write>(
     displayflag: bool,
     color_map: HashMap<String, Colors>,
     all_record_cnt: u128,
-    profile: LinkedHashMap<String, String>,
+    skipped_record_cnt: u128,
+    mut profile: LinkedHashMap<String, String>,
+    throttle: &Throttle,
 ) -> io::Result<()> {
     let disp_wtr = BufferWriter::stdout(ColorChoice::Always);
     let mut disp_wtr_buf = disp_wtr.buffer();
@@ -252,6 +266,84 @@ fn emit_csv<W: std::io::Write>(
     let mut timestamps: Vec<i64> = Vec::new();
     let mut plus_header = true;
     let mut detected_record_idset: HashSet<String> = HashSet::new();
+    // 検知数などの集計は、--max-per-ruleと--dedup-windowで間引く前の全ての検知を対象とする
+    for time in message::MESSAGES.clone().into_read_only().keys().sorted() {
+        let multi = message::MESSAGES.get(time).unwrap();
+        let (_, detect_infos) = multi.pair();
+        timestamps.push(_get_timestamp(time));
+        for detect_info in detect_infos.iter() {
+            if !detect_info.detail.starts_with("[condition]") {
+                detected_record_idset.insert(format!("{}_{}", time, detect_info.eventid));
+            }
+            let level_suffix = *configs::LEVELMAP
+                .get(
+                    &LEVEL_FULL
+                        .get(&detect_info.level)
+                        .unwrap_or(&"undefined".to_string())
+                        .to_uppercase(),
+                )
+                .unwrap_or(&0) as usize;
+            let time_str_date = format_time(time, true);
+
+            let mut detect_counts_by_date = detect_counts_by_date_and_level
+                .get(&detect_info.level.to_lowercase())
+                .unwrap_or_else(|| detect_counts_by_date_and_level.get("undefined").unwrap())
+                .clone();
+            *detect_counts_by_date
+                .entry(time_str_date.to_string())
+                .or_insert(0) += 1;
+            if !detected_rule_files.contains(&detect_info.rulepath) {
+                detected_rule_files.insert(detect_info.rulepath.clone());
+                unique_detect_counts_by_level[level_suffix] += 1;
+            }
+
+            let computer_rule_check_key =
+                format!("{}|{}", &detect_info.computername, &detect_info.rulepath);
+            if !detected_computer_and_rule_names.contains(&computer_rule_check_key) {
+                detected_computer_and_rule_names.insert(computer_rule_check_key);
+                let mut detect_counts_by_computer = detect_counts_by_computer_and_level
+                    .get(&detect_info.level.to_lowercase())
+                    .unwrap_or_else(|| {
+                        detect_counts_by_computer_and_level
+                            .get("undefined")
+                            .unwrap()
+                    })
+                    .clone();
+                *detect_counts_by_computer
+                    .entry(Clone::clone(&detect_info.computername))
+                    .or_insert(0) += 1;
+                detect_counts_by_computer_and_level
+                    .insert(detect_info.level.to_lowercase(), detect_counts_by_computer);
+            }
+
+            let mut detect_counts_by_rules = detect_counts_by_rule_and_level
+                .get(&detect_info.level.to_lowercase())
+                .unwrap_or_else(|| {
+                    detect_counts_by_computer_and_level
+                        .get("undefined")
+                        .unwrap()
+                })
+                .clone();
+            *detect_counts_by_rules
+                .entry(Clone::clone(&detect_info.ruletitle))
+                .or_insert(0) += 1;
+            detect_counts_by_rule_and_level
+                .insert(detect_info.level.to_lowercase(), detect_counts_by_rules);
+
+            total_detect_counts_by_level[level_suffix] += 1;
+            detect_counts_by_date_and_level
+                .insert(detect_info.level.to_lowercase(), detect_counts_by_date);
+        }
+    }
+
+    // 出力前に、ルールとコンピュータごとの出力数の上限と同一の検知の集約を適用する
+    let throttle_summary = if throttle.is_enabled() {
+        let summary = throttle.apply(&message::MESSAGES, &profile);
+        throttle.add_dedup_columns(&mut profile);
+        Some(summary)
+    } else {
+        None
+    };
     if json_output_flag {
         wtr.write_field("[")?;
     }
@@ -264,11 +356,7 @@ fn emit_csv<W: std::io::Write>(
     {
         let multi = message::MESSAGES.get(time).unwrap();
         let (_, detect_infos) = multi.pair();
-        timestamps.push(_get_timestamp(time));
         for (info_idx, detect_info) in detect_infos.iter().enumerate() {
-            if !detect_info.detail.starts_with("[condition]") {
-                detected_record_idset.insert(format!("{}_{}", time, detect_info.eventid));
-            }
             if displayflag {
                 //ヘッダーのみを出力
                 if plus_header {
@@ -300,6 +388,7 @@ fn emit_csv<W: std::io::Write>(
                     &detect_info.ext_field,
                     &profile,
                     jsonl_output_flag,
+                    &detect_info.matched_fields,
                 ))?;
                 if processed_message_cnt != message::MESSAGES._len() - 1
                     || info_idx != detect_infos.len() - 1
@@ -312,7 +401,12 @@ fn emit_csv<W: std::io::Write>(
                 // JSONL output format
                 wtr.write_field(format!(
                     "{{ {} }}",
-                    &output_json_str(&detect_info.ext_field, &profile, jsonl_output_flag)
+                    &output_json_str(
+                        &detect_info.ext_field,
+                        &profile,
+                        jsonl_output_flag,
+                        &detect_info.matched_fields
+                    )
                 ))?;
             } else {
                 // csv output format
@@ -322,65 +416,6 @@ fn emit_csv<W: std::io::Write>(
                 }
                 wtr.write_record(detect_info.ext_field.values().map(|x| x.trim()))?;
             }
-
-            let level_suffix = *configs::LEVELMAP
-                .get(
-                    &LEVEL_FULL
-                        .get(&detect_info.level)
-                        .unwrap_or(&"undefined".to_string())
-                        .to_uppercase(),
-                )
-                .unwrap_or(&0) as usize;
-            let time_str_date = format_time(time, true);
-
-            let mut detect_counts_by_date = detect_counts_by_date_and_level
-                .get(&detect_info.level.to_lowercase())
-                .unwrap_or_else(|| detect_counts_by_date_and_level.get("undefined").unwrap())
-                .clone();
-            *detect_counts_by_date
-                .entry(time_str_date.to_string())
-                .or_insert(0) += 1;
-            if !detected_rule_files.contains(&detect_info.rulepath) {
-                detected_rule_files.insert(detect_info.rulepath.clone());
-                unique_detect_counts_by_level[level_suffix] += 1;
-            }
-
-            let computer_rule_check_key =
-                format!("{}|{}", &detect_info.computername, &detect_info.rulepath);
-            if !detected_computer_and_rule_names.contains(&computer_rule_check_key) {
-                detected_computer_and_rule_names.insert(computer_rule_check_key);
-                let mut detect_counts_by_computer = detect_counts_by_computer_and_level
-                    .get(&detect_info.level.to_lowercase())
-                    .unwrap_or_else(|| {
-                        detect_counts_by_computer_and_level
-                            .get("undefined")
-                            .unwrap()
-                    })
-                    .clone();
-                *detect_counts_by_computer
-                    .entry(Clone::clone(&detect_info.computername))
-                    .or_insert(0) += 1;
-                detect_counts_by_computer_and_level
-                    .insert(detect_info.level.to_lowercase(), detect_counts_by_computer);
-            }
-
-            let mut detect_counts_by_rules = detect_counts_by_rule_and_level
-                .get(&detect_info.level.to_lowercase())
-                .unwrap_or_else(|| {
-                    detect_counts_by_computer_and_level
-                        .get("undefined")
-                        .unwrap()
-                })
-                .clone();
-            *detect_counts_by_rules
-                .entry(Clone::clone(&detect_info.ruletitle))
-                .or_insert(0) += 1;
-            detect_counts_by_rule_and_level
-                .insert(detect_info.level.to_lowercase(), detect_counts_by_rules);
-
-            total_detect_counts_by_level[level_suffix] += 1;
-            detect_counts_by_date_and_level
-                .insert(detect_info.level.to_lowercase(), detect_counts_by_date);
         }
     }
     if json_output_flag {
@@ -421,6 +456,69 @@ fn emit_csv<W: std::io::Write>(
             true,
         )
         .ok();
+        if let Some(revision) = &*RULES_REVISION {
+            write_color_buffer(
+                &disp_wtr,
+                get_writable_color(None),
+                &format!("Rules revision: {}", revision),
+                true,
+            )
+            .ok();
+        }
+
+        if let Some(throttle_summary) = &throttle_summary {
+            let args = &configs::CONFIG.read().unwrap().args;
+            if let Some(dedup_window) = args.dedup_window {
+                write_color_buffer(
+                    &disp_wtr,
+                    get_writable_color(None),
+                    &format!(
+                        "Deduplicated detections: {} (window: {}s)",
+                        throttle_summary.deduplicated_cnt, dedup_window
+                    ),
+                    true,
+                )
+                .ok();
+            }
+            if let Some(max_per_rule) = args.max_per_rule {
+                write_color_buffer(
+                    &disp_wtr,
+                    get_writable_color(None),
+                    &format!(
+                        "Throttled detections: {} (max {} per rule per computer)",
+                        throttle_summary.throttled_cnt, max_per_rule
+                    ),
+                    true,
+                )
+                .ok();
+            }
+            // 以降の集計は間引く前の全ての検知を対象とするため、出力した検知の数を別に表示する
+            let output_cnt = total_detect_counts_by_level.iter().sum::<u128>()
+                - throttle_summary.throttled_cnt
+                - throttle_summary.deduplicated_cnt;
+            write_color_buffer(
+                &disp_wtr,
+                get_writable_color(None),
+                &format!(
+                    "Output detections: {}",
+                    output_cnt.to_formatted_string(&Locale::en)
+                ),
+                true,
+            )
+            .ok();
+        }
+        if let Some(suppression_list) = SUPPRESSION_LIST.read().unwrap().as_ref() {
+            write_color_buffer(
+                &disp_wtr,
+                get_writable_color(None),
+                &format!(
+                    "Suppressed detections: {}",
+                    suppression_list.suppressed_count()
+                ),
+                true,
+            )
+            .ok();
+        }
 
         let terminal_width = match *TERM_SIZE {
             Some((Width(w), _)) => w as usize,
@@ -485,6 +583,19 @@ fn emit_csv<W: std::io::Write>(
 
         write_color_buffer(&disp_wtr, get_writable_color(None), ")", false).ok();
         println!();
+        // 読み飛ばしたレコードは解析していないため、Total eventsには含めずに別に出力する
+        if skipped_record_cnt > 0 {
+            write_color_buffer(
+                &disp_wtr,
+                get_writable_color(None),
+                &format!(
+                    "Skipped events written before the timeline start: {}",
+                    skipped_record_cnt.to_formatted_string(&Locale::en)
+                ),
+                true,
+            )
+            .ok();
+        }
         println!();
 
         _print_unique_results(
@@ -922,15 +1033,50 @@ fn _convert_valid_json_str(input: &[&str], concat_flag: bool) -> String {
     }
 }
 
+/// 条件に一致したselectionとフィールドをSelection、Field、Valueを持つオブジェクトの配列のJSON文字列に変換する関数
+fn _create_matched_fields_json(matched_fields: &[MatchedField], jsonl_output_flag: bool) -> String {
+    let values: Vec<String> = matched_fields
+        .iter()
+        .map(|matched_field| {
+            json!({
+                "Selection": matched_field.selection,
+                "Field": matched_field.key,
+                "Value": matched_field.value,
+            })
+            .to_string()
+        })
+        .collect();
+    if values.is_empty() {
+        "[]".to_string()
+    } else if jsonl_output_flag {
+        format!("[{}]", values.join(","))
+    } else {
+        format!(
+            "[\n{}\n    ]",
+            values.iter().map(|x| format!("        {}", x)).join(",\n")
+        )
+    }
+}
+
 /// JSONに出力する1検知分のオブジェクトの文字列を出力する関数
 fn output_json_str(
     ext_field: &LinkedHashMap<String, String>,
     profile: &LinkedHashMap<String, String>,
     jsonl_output_flag: bool,
+    matched_fields: &[MatchedField],
 ) -> String {
     let mut target: Vec<String> = vec![];
     for (k, v) in ext_field.iter() {
         let output_value_fmt = profile.get(k).unwrap();
+        if output_value_fmt.contains("%MatchedFields%") {
+            target.push(_create_json_output_format(
+                k,
+                &_create_matched_fields_json(matched_fields, jsonl_output_flag),
+                k.starts_with('\"'),
+                true,
+            ));
+            continue;
+        }
         let vec_data = _get_json_vec(output_value_fmt, v);
         if vec_data.is_empty() {
             let tmp_val: Vec<&str> = v.split(": ").collect();
@@ -1074,9 +1220,12 @@ mod tests {
     use crate::afterfact::_get_serialized_disp_output;
     use crate::afterfact::emit_csv;
     use crate::afterfact::format_time;
+    use crate::afterfact::output_json_str;
     use crate::detections::message;
     use crate::detections::message::DetectInfo;
+    use crate::detections::rule::MatchedField;
     use crate::options::profile::load_profile;
+    use crate::options::throttle::Throttle;
     use chrono::{Local, TimeZone, Utc};
     use hashbrown::HashMap;
     use linked_hash_map::LinkedHashMap;
@@ -1159,6 +1308,7 @@ mod tests {
                     detail: String::default(),
                     record_information: Option::Some(test_recinfo.to_string()),
                     ext_field: output_profile.clone(),
+                    matched_fields: vec![],
                 },
                 expect_time,
                 &mut profile_converter,
@@ -1198,7 +1348,16 @@ mod tests {
                 + test_attack
                 + "\n";
         let mut file: Box<dyn io::Write> = Box::new(File::create("./test_emit_csv.csv").unwrap());
-        assert!(emit_csv(&mut file, false, HashMap::new(), 1, output_profile).is_ok());
+        assert!(emit_csv(
+            &mut file,
+            false,
+            HashMap::new(),
+            1,
+            0,
+            output_profile,
+            &Throttle::default()
+        )
+        .is_ok());
         match read_to_string("./test_emit_csv.csv") {
             Err(_) => panic!("Failed to open file."),
             Ok(s) => {
@@ -1260,4 +1419,40 @@ mod tests {
         assert_eq!(_get_serialized_disp_output(&data, true), expect_header);
         assert_eq!(_get_serialized_disp_output(&data, false), expect_no_header);
     }
+
+    #[test]
+    fn test_output_json_str_matched_fields() {
+        let mut profile = LinkedHashMap::new();
+        profile.insert("RuleTitle".to_string(), "%RuleTitle%".to_string());
+        profile.insert("MatchedFields".to_string(), "%MatchedFields%".to_string());
+        let mut ext_field = LinkedHashMap::new();
+        ext_field.insert("RuleTitle".to_string(), "Test".to_string());
+        ext_field.insert(
+            "MatchedFields".to_string(),
+            "selection.Event.System.EventID: 1 ¦ keywords: whoami".to_string(),
+        );
+        let matched_fields = vec![
+            MatchedField {
+                selection: "selection".to_string(),
+                key: "Event.System.EventID".to_string(),
+                value: "1".to_string(),
+            },
+            MatchedField {
+                selection: "keywords".to_string(),
+                key: String::default(),
+                value: "whoami".to_string(),
+            },
+        ];
+        for jsonl_output_flag in [true, false] {
+            let output = output_json_str(&ext_field, &profile, jsonl_output_flag, &matched_fields);
+            let json: Value = serde_json::from_str(&format!("{{{}}}", output)).unwrap();
+            assert_eq!(json["RuleTitle"], "Test");
+            assert_eq!(json["MatchedFields"][0]["Selection"], "selection");
+            assert_eq!(json["MatchedFields"][0]["Field"], "Event.System.EventID");
+            assert_eq!(json["MatchedFields"][0]["Value"], "1");
+            assert_eq!(json["MatchedFields"][1]["Selection"], "keywords");
+            assert_eq!(json["MatchedFields"][1]["Field"], "");
+            assert_eq!(json["MatchedFields"][1]["Value"], "whoami");
+        }
+    }
 }
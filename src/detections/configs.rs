@@ -2,6 +2,9 @@ use crate::detections::message::AlertMessage;
 use crate::detections::pivot::PivotKeyword;
 use crate::detections::pivot::PIVOT_KEYWORD;
 use crate::detections::utils;
+use crate::input::evt_log::EVT_LOG_EXTENSIONS;
+use crate::input::json_log::JSON_LOG_EXTENSIONS;
+use crate::input::xml_log::XML_LOG_EXTENSIONS;
 use chrono::{DateTime, Utc};
 use clap::{App, CommandFactory, Parser};
 use hashbrown::{HashMap, HashSet};
@@ -43,8 +46,11 @@ lazy_static! {
     pub static ref IDS_REGEX: Regex =
         Regex::new(r"^[0-9a-z]{8}-[0-9a-z]{4}-[0-9a-z]{4}-[0-9a-z]{4}-[0-9a-z]{12}$").unwrap();
     pub static ref TERM_SIZE: Option<(Width, Height)> = terminal_size();
-    pub static ref TARGET_EXTENSIONS: HashSet<String> =
-        get_target_extensions(CONFIG.read().unwrap().args.evtx_file_ext.as_ref());
+    pub static ref TARGET_EXTENSIONS: HashSet<String> = get_target_extensions(
+        CONFIG.read().unwrap().args.evtx_file_ext.as_ref(),
+        CONFIG.read().unwrap().args.json_input,
+        CONFIG.read().unwrap().args.xml_input
+    );
     pub static ref CURRENT_EXE_PATH: PathBuf =
         current_exe().unwrap().parent().unwrap().to_path_buf();
     pub static ref EXCLUDE_STATUS: HashSet<String> =
@@ -83,16 +89,18 @@ pub struct Config {
     #[clap(help_heading = Some("INPUT"), short = 'f', long = "file", value_name = "FILE")]
     pub filepath: Option<PathBuf>,
 
-    /// Specify a custom rule directory or file (default: ./rules)
+    /// Specify custom rule directories, files or rule bundles (zip, tar.gz) (default: ./rules)
     #[clap(
         help_heading = Some("ADVANCED"), 
         short = 'r',
         long,
         default_value = "./rules",
         hide_default_value = true,
+        multiple_values = true,
+        multiple_occurrences = true,
         value_name = "DIRECTORY/FILE"
     )]
-    pub rules: PathBuf,
+    pub rules: Vec<PathBuf>,
 
     /// Specify custom rule config directory (default: ./rules/config)
     #[clap(
@@ -125,6 +133,10 @@ pub struct Config {
     #[clap(help_heading = Some("FILTERING"), short = 'D', long = "deep-scan")]
     pub deep_scan: bool,
 
+    /// Filter events by the event IDs and channels used in the loaded rules instead of target_event_IDs.txt
+    #[clap(help_heading = Some("FILTERING"), long = "auto-eid-filter")]
+    pub auto_eid_filter: bool,
+
     /// Enable rules marked as noisy
     #[clap(help_heading = Some("FILTERING"), short = 'n', long = "enable-noisy-rules")]
     pub enable_noisy_rules: bool,
@@ -133,6 +145,35 @@ pub struct Config {
     #[clap(help_heading = Some("OTHER-ACTIONS"), short = 'u', long = "update-rules")]
     pub update_rules: bool,
 
+    /// Update the rules from a local zip, tar.gz or git bundle file after verifying its SHA-256 hash
+    #[clap(
+        help_heading = Some("OTHER-ACTIONS"),
+        long = "update-rules-from",
+        value_name = "FILE"
+    )]
+    pub update_rules_from: Option<PathBuf>,
+
+    /// Specify the SHA-256 manifest for --update-rules-from (default: SHA256SUMS in the same folder as the file)
+    #[clap(
+        help_heading = Some("OTHER-ACTIONS"),
+        long = "manifest",
+        requires = "update-rules-from",
+        value_name = "FILE"
+    )]
+    pub manifest: Option<PathBuf>,
+
+    /// Pin the rules to a specific commit or tag of the hayabusa-rules repository (ex: --pin-rules v2.1.0)
+    #[clap(
+        help_heading = Some("OTHER-ACTIONS"),
+        long = "pin-rules",
+        value_name = "COMMIT/TAG"
+    )]
+    pub pin_rules: Option<String>,
+
+    /// Roll back the rules to the revision before the last update or pin
+    #[clap(help_heading = Some("OTHER-ACTIONS"), long = "rollback-rules")]
+    pub rollback_rules: bool,
+
     /// Minimum level for rules (default: informational)
     #[clap(
         help_heading = Some("FILTERING"), 
@@ -148,6 +189,18 @@ pub struct Config {
     #[clap(help_heading = Some("INPUT"), short = 'l', long = "live-analysis")]
     pub live_analysis: bool,
 
+    /// Scan JSON/JSONL formatted logs (.json, .jsonl) such as Winlogbeat, EvtxECmd or evtx_dump output instead of .evtx files
+    #[clap(help_heading = Some("INPUT"), long = "json-input")]
+    pub json_input: bool,
+
+    /// Scan XML formatted logs (.xml) exported by wevtutil or Event Viewer instead of .evtx files
+    #[clap(help_heading = Some("INPUT"), long = "xml-input", conflicts_with = "json-input")]
+    pub xml_input: bool,
+
+    /// Also scan event logs inside .zip, .tar, .tar.gz, .tgz and .gz archives without extracting them (ex: KAPE or Velociraptor collections)
+    #[clap(help_heading = Some("INPUT"), long = "scan-archives")]
+    pub scan_archives: bool,
+
     /// Start time of the event logs to load (ex: "2020-02-22 00:00:00 +09:00")
     #[clap(help_heading = Some("FILTERING"), long = "timeline-start", value_name = "DATE")]
     pub start_timeline: Option<String>,
@@ -205,6 +258,10 @@ pub struct Config {
     )]
     pub level_tuning: Option<Option<String>>,
 
+    /// Print the planned level changes of --level-tuning without modifying the rule files
+    #[clap(help_heading = Some("OTHER-ACTIONS"), long = "dry-run", requires = "level-tuning")]
+    pub dry_run: bool,
+
     /// Quiet mode: do not display the launch banner
     #[clap(help_heading = Some("DISPLAY-SETTINGS"), short, long)]
     pub quiet: bool,
@@ -221,6 +278,45 @@ pub struct Config {
     #[clap(help_heading = Some("OTHER-ACTIONS"), long)]
     pub contributors: bool,
 
+    /// Validate the rule files and exit with a non-zero code when errors are found
+    #[clap(help_heading = Some("OTHER-ACTIONS"), long = "validate-rules")]
+    pub validate_rules: bool,
+
+    /// Test the rules with the sample events in the rule files and exit with a non-zero code when tests fail
+    #[clap(help_heading = Some("OTHER-ACTIONS"), long = "test-rules")]
+    pub test_rules: bool,
+
+    /// Show how a rule is evaluated against a single record (ex: --debug-rule rule.yml -f file.evtx --record-id 1234)
+    #[clap(
+        help_heading = Some("OTHER-ACTIONS"),
+        long = "debug-rule",
+        value_name = "RULE_FILE",
+        requires_all = &["filepath", "debug-record-id"]
+    )]
+    pub debug_rule: Option<PathBuf>,
+
+    /// List the rules filtered by tag, level, status, channel, eid, author or title regex (ex: --list-rules tag:t1003 level:high)
+    #[clap(
+        help_heading = Some("OTHER-ACTIONS"),
+        long = "list-rules",
+        multiple_values = true,
+        min_values = 0,
+        value_name = "FIELD:VALUE"
+    )]
+    pub list_rules: Option<Vec<String>>,
+
+    /// Record ID of the event to use with --debug-rule or --rule-template
+    #[clap(help_heading = Some("OTHER-ACTIONS"), long = "record-id", value_name = "ID")]
+    pub debug_record_id: Option<u64>,
+
+    /// Create a rule template from a single record (ex: --rule-template -f file.evtx --record-id 1234 -o rule.yml)
+    #[clap(
+        help_heading = Some("OTHER-ACTIONS"),
+        long = "rule-template",
+        requires_all = &["filepath", "debug-record-id"]
+    )]
+    pub rule_template: bool,
+
     /// Specify additional target file extensions (ex: evtx_data) (ex: evtx1 evtx2)
     #[clap(help_heading = Some("ADVANCED"), long = "target-file-ext", multiple_values = true)]
     pub evtx_file_ext: Option<Vec<String>>,
@@ -229,6 +325,42 @@ pub struct Config {
     #[clap(help_heading = Some("FILTERING"), long = "exclude-status", multiple_values = true, value_name = "STATUS")]
     pub exclude_status: Option<Vec<String>>,
 
+    /// Load rules with the filters of a rule set defined in rule_sets.yaml (ex: core)
+    #[clap(help_heading = Some("FILTERING"), long = "rule-set", value_name = "NAME")]
+    pub rule_set: Option<String>,
+
+    /// Only load rules with the specified tags (ex: attack.credential_access) (ex: t1003 CredAccess)
+    #[clap(help_heading = Some("FILTERING"), long = "include-tag", multiple_values = true, value_name = "TAG")]
+    pub include_tag: Option<Vec<String>>,
+
+    /// Do not load rules with the specified tags (ex: attack.discovery)
+    #[clap(help_heading = Some("FILTERING"), long = "exclude-tag", multiple_values = true, value_name = "TAG")]
+    pub exclude_tag: Option<Vec<String>>,
+
+    /// Only load rules with the IDs listed in the file
+    #[clap(help_heading = Some("FILTERING"), long = "include-rule-ids", value_name = "FILE")]
+    pub include_rule_ids: Option<PathBuf>,
+
+    /// Do not load rules with the IDs listed in the file
+    #[clap(help_heading = Some("FILTERING"), long = "exclude-rule-ids", value_name = "FILE")]
+    pub exclude_rule_ids: Option<PathBuf>,
+
+    /// Only load rules whose author contains the specified string
+    #[clap(help_heading = Some("FILTERING"), long = "include-author", multiple_values = true, value_name = "AUTHOR")]
+    pub include_author: Option<Vec<String>>,
+
+    /// Do not load rules whose author contains the specified string
+    #[clap(help_heading = Some("FILTERING"), long = "exclude-author", multiple_values = true, value_name = "AUTHOR")]
+    pub exclude_author: Option<Vec<String>>,
+
+    /// Only load rules with the specified logsource (ex: service:security category:process_creation)
+    #[clap(help_heading = Some("FILTERING"), long = "include-logsource", multiple_values = true, value_name = "FIELD:VALUE")]
+    pub include_logsource: Option<Vec<String>>,
+
+    /// Do not load rules with the specified logsource (ex: service:sysmon)
+    #[clap(help_heading = Some("FILTERING"), long = "exclude-logsource", multiple_values = true, value_name = "FIELD:VALUE")]
+    pub exclude_logsource: Option<Vec<String>>,
+
     /// Specify output profile (minimal, standard, verbose, verbose-all-field-info, verbose-details-and-all-field-info)
     #[clap(help_heading = Some("OUTPUT"), short = 'P', long = "profile")]
     pub profile: Option<String>,
@@ -245,9 +377,82 @@ pub struct Config {
     #[clap(help_heading = Some("OUTPUT"), short = 'J', long = "jsonl", requires = "output")]
     pub jsonl_timeline: bool,
 
+    /// Output the selections and field values that matched for each detection (%MatchedFields%)
+    #[clap(help_heading = Some("OUTPUT"), long = "explain")]
+    pub explain: bool,
+
+    /// Output at most NUMBER detections per rule per computer
+    #[clap(help_heading = Some("OUTPUT"), long = "max-per-rule", value_name = "NUMBER")]
+    pub max_per_rule: Option<usize>,
+
+    /// Collapse identical detections of a rule within SECONDS into one row with Count, FirstTimestamp and LastTimestamp columns
+    #[clap(help_heading = Some("OUTPUT"), long = "dedup-window", value_name = "SECONDS")]
+    pub dedup_window: Option<u64>,
+
     /// Do not display result summary
     #[clap(help_heading = Some("DISPLAY-SETTINGS"), long = "no-summary")]
     pub no_summary: bool,
+
+    /// Apply the level tuning file when loading rules without modifying the rule files (default: ./config/level_tuning.txt)
+    #[clap(
+        help_heading = Some("ADVANCED"),
+        long = "level-overlay",
+        hide_default_value = true,
+        value_name = "FILE"
+    )]
+    pub level_overlay: Option<Option<String>>,
+
+    /// Add Channel and EventID conditions to Sigma rules based on their logsource (default: ./rules/config/logsource_mapping.yaml, or ./config/logsource_mapping.yaml if it does not exist)
+    #[clap(
+        help_heading = Some("ADVANCED"),
+        long = "logsource-mapping",
+        hide_default_value = true,
+        value_name = "FILE"
+    )]
+    pub logsource_mapping: Option<Option<String>>,
+
+    /// Specify the field mapping used to convert JSON/JSONL logs with --json-input (default: ./config/json_mapping.yaml)
+    #[clap(
+        help_heading = Some("ADVANCED"),
+        long = "json-mapping",
+        requires = "json-input",
+        value_name = "FILE"
+    )]
+    pub json_mapping: Option<PathBuf>,
+
+    /// Merge local overrides keyed by rule id into the rules when loading them (ex: --rule-overrides rule_overrides.yaml)
+    #[clap(
+        help_heading = Some("ADVANCED"),
+        long = "rule-overrides",
+        value_name = "FILE"
+    )]
+    pub rule_overrides: Option<PathBuf>,
+
+    /// Do not output detections that match the rule ids and field values in the suppression list (ex: --suppression-list suppression_list.yaml)
+    #[clap(
+        help_heading = Some("ADVANCED"),
+        long = "suppression-list",
+        value_name = "FILE"
+    )]
+    pub suppression_list: Option<PathBuf>,
+
+    /// Cache parsed rule YAML to speed up loading (default: ./cache/rule_cache.json)
+    #[clap(
+        help_heading = Some("ADVANCED"),
+        long = "rule-cache",
+        hide_default_value = true,
+        value_name = "FILE"
+    )]
+    pub rule_cache: Option<Option<PathBuf>>,
+
+    /// Measure the evaluation time of each rule and print a sorted report (ex: --rule-profiling profile.csv)
+    #[clap(
+        help_heading = Some("ADVANCED"),
+        long = "rule-profiling",
+        hide_default_value = true,
+        value_name = "FILE"
+    )]
+    pub rule_profiling: Option<Option<PathBuf>>,
 }
 
 impl ConfigReader<'_> {
@@ -409,6 +614,21 @@ impl TargetEventTime {
         self.parse_success_flag
     }
 
+    /// --timeline-startが指定されているかを返す
+    pub fn has_start_time(&self) -> bool {
+        self.start_time.is_some()
+    }
+
+    /// 最後に書き込まれたレコードの書き込み日時(レコードヘッダーのタイムスタンプ)が開始日時より前かを返す。
+    /// イベントは作成された後に書き込まれるため、書き込み日時が開始日時より前のレコードのTimeCreatedも開始日時より前となる。
+    /// 書き込み日時が終了日時より後でもTimeCreatedは対象期間に含まれる場合があるため、終了日時とは比較しない
+    pub fn is_written_before_start(&self, last_written: &DateTime<Utc>) -> bool {
+        match self.start_time {
+            Some(starttime) => *last_written < starttime,
+            None => false,
+        }
+    }
+
     pub fn is_target(&self, eventtime: &Option<DateTime<Utc>>) -> bool {
         if eventtime.is_none() {
             return true;
@@ -448,6 +668,23 @@ impl EventKeyAliasConfig {
     pub fn get_event_key_split(&self, alias: &str) -> Option<&Vec<usize>> {
         self.key_to_split_eventkey.get(alias)
     }
+
+    /// event_keyに対応するエイリアスを返す。複数ある場合はevent_keyの末尾と同じ名前のエイリアスを優先し、それ以外は辞書順で最初のものを返す
+    pub fn get_alias(&self, event_key: &str) -> Option<&String> {
+        let last_key = event_key.rsplit('.').next().unwrap_or_default();
+        self.key_to_eventkey
+            .iter()
+            .filter(|(_, v)| v.as_str() == event_key)
+            .map(|(alias, _)| alias)
+            .min_by_key(|alias| (alias.as_str() != last_key, alias.as_str()))
+    }
+
+    pub fn add_alias(&mut self, alias: &str, event_key: &str) {
+        self.key_to_eventkey
+            .insert(alias.to_owned(), event_key.to_owned());
+        let splits = event_key.split('.').map(|s| s.len()).collect();
+        self.key_to_split_eventkey.insert(alias.to_owned(), splits);
+    }
 }
 
 impl Default for EventKeyAliasConfig {
@@ -478,13 +715,7 @@ fn load_eventkey_alias(path: &str) -> EventKeyAliasConfig {
             return;
         }
 
-        config
-            .key_to_eventkey
-            .insert(alias.to_owned(), event_key.to_owned());
-        let splits = event_key.split('.').map(|s| s.len()).collect();
-        config
-            .key_to_split_eventkey
-            .insert(alias.to_owned(), splits);
+        config.add_alias(alias, event_key);
     });
     config.key_to_eventkey.shrink_to_fit();
     config
@@ -520,10 +751,21 @@ pub fn load_pivot_keywords(path: &str) {
     });
 }
 
-/// --target-file-extで追加された拡張子から、調査対象ファイルの拡張子セットを返す関数
-pub fn get_target_extensions(arg: Option<&Vec<String>>) -> HashSet<String> {
+/// 読み込むファイルの拡張子の一覧を返す。--json-inputもしくは--xml-inputが指定された場合は.evtx/.evtの代わりにJSON/JSONLもしくはXML形式のファイルを対象とする
+pub fn get_target_extensions(
+    arg: Option<&Vec<String>>,
+    json_input: bool,
+    xml_input: bool,
+) -> HashSet<String> {
     let mut target_file_extensions: HashSet<String> = convert_option_vecs_to_hs(arg);
-    target_file_extensions.insert(String::from("evtx"));
+    if json_input {
+        target_file_extensions.extend(JSON_LOG_EXTENSIONS.iter().map(|ext| ext.to_string()));
+    } else if xml_input {
+        target_file_extensions.extend(XML_LOG_EXTENSIONS.iter().map(|ext| ext.to_string()));
+    } else {
+        target_file_extensions.insert(String::from("evtx"));
+        target_file_extensions.extend(EVT_LOG_EXTENSIONS.iter().map(|ext| ext.to_string()));
+    }
     target_file_extensions
 }
 
@@ -603,7 +845,7 @@ fn load_eventcode_info(path: &str) -> EventInfoConfig {
 #[cfg(test)]
 mod tests {
     use crate::detections::configs;
-    use chrono::{DateTime, Utc};
+    use chrono::{DateTime, TimeZone, Utc};
     use hashbrown::HashSet;
 
     //     #[test]
@@ -649,12 +891,29 @@ mod tests {
         assert!(time_filter.is_target(&end_time));
     }
 
+    #[test]
+    fn test_target_event_time_range() {
+        let start = Utc.ymd(2022, 2, 22).and_hms(0, 0, 0);
+        let end = Utc.ymd(2022, 2, 23).and_hms(0, 0, 0);
+        let time_filter = configs::TargetEventTime::set(true, Some(start), Some(end));
+        let before = Utc.ymd(2022, 2, 21).and_hms(0, 0, 0);
+        let inside = Utc.ymd(2022, 2, 22).and_hms(12, 0, 0);
+        let after = Utc.ymd(2022, 2, 24).and_hms(0, 0, 0);
+        assert!(time_filter.is_written_before_start(&before));
+        assert!(!time_filter.is_written_before_start(&inside));
+        // 終了日時より後に書き込まれたレコードもTimeCreatedは対象期間に含まれる場合があるため読み飛ばさない
+        assert!(!time_filter.is_written_before_start(&after));
+        let end_only = configs::TargetEventTime::set(true, None, Some(end));
+        assert!(!end_only.has_start_time());
+        assert!(!end_only.is_written_before_start(&before));
+    }
+
     #[test]
     fn test_get_target_extensions() {
         let data = vec!["evtx_data".to_string(), "evtx_stars".to_string()];
         let arg = Some(&data);
-        let ret = configs::get_target_extensions(arg);
-        let expect: HashSet<&str> = HashSet::from(["evtx", "evtx_data", "evtx_stars"]);
+        let ret = configs::get_target_extensions(arg, false, false);
+        let expect: HashSet<&str> = HashSet::from(["evtx", "evt", "evtx_data", "evtx_stars"]);
         assert_eq!(ret.len(), expect.len());
         for contents in expect.iter() {
             assert!(ret.contains(&contents.to_string()));
@@ -663,11 +922,20 @@ mod tests {
 
     #[test]
     fn no_target_extensions() {
-        let ret = configs::get_target_extensions(None);
-        let expect: HashSet<&str> = HashSet::from(["evtx"]);
+        let ret = configs::get_target_extensions(None, false, false);
+        let expect: HashSet<&str> = HashSet::from(["evtx", "evt"]);
         assert_eq!(ret.len(), expect.len());
         for contents in expect.iter() {
             assert!(ret.contains(&contents.to_string()));
         }
     }
+
+    #[test]
+    fn test_get_json_input_extensions() {
+        let ret = configs::get_target_extensions(None, true, false);
+        let expect: HashSet<String> = HashSet::from(["json".to_string(), "jsonl".to_string()]);
+        assert_eq!(ret, expect);
+        let ret = configs::get_target_extensions(None, false, true);
+        assert_eq!(ret, HashSet::from(["xml".to_string()]));
+    }
 }
@@ -14,7 +14,8 @@ use crate::detections::message::DetectInfo;
 use crate::detections::message::ERROR_LOG_STACK;
 use crate::detections::message::{CH_CONFIG, DEFAULT_DETAILS, TAGS_CONFIG};
 use crate::detections::message::{
-    LOGONSUMMARY_FLAG, PIVOT_KEYWORD_LIST_FLAG, QUIET_ERRORS_FLAG, STATISTICS_FLAG,
+    LOGONSUMMARY_FLAG, PIVOT_KEYWORD_LIST_FLAG, QUIET_ERRORS_FLAG, RULE_PROFILING_FLAG,
+    STATISTICS_FLAG,
 };
 use crate::detections::pivot::insert_pivot_keyword;
 use crate::detections::rule;
@@ -22,13 +23,23 @@ use crate::detections::rule::AggResult;
 use crate::detections::rule::RuleNode;
 use crate::detections::utils::{get_serde_number_to_string, make_ascii_titlecase};
 use crate::filter;
+use crate::options::level_tuning::LevelTuning;
+use crate::options::logsource_mapping::LogsourceMapping;
+use crate::options::rule_cache::RuleCache;
+use crate::options::rule_overrides::RuleOverrides;
+use crate::options::rule_profiling::RuleProfiling;
+use crate::options::suppression_list::{SuppressionList, SUPPRESSION_LIST};
+use crate::options::update_rules::RULES_REVISION;
 use crate::yaml::ParseYaml;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use serde_json::Value;
 use std::fmt::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use yaml_rust::Yaml;
 
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::{runtime::Runtime, spawn, task::JoinHandle};
 
 use super::message;
@@ -67,12 +78,93 @@ impl Detection {
     // ルールファイルをパースします。
     pub fn parse_rule_files(
         level: String,
-        rulespath: &Path,
+        rulespath: &[PathBuf],
         exclude_ids: &filter::RuleExclude,
     ) -> Vec<RuleNode> {
         // ルールファイルのパースを実行
         let mut rulefile_loader = ParseYaml::new();
-        let result_readdir = rulefile_loader.read_dir(rulespath, &level, exclude_ids);
+        let rule_cache_path = configs::CONFIG.read().unwrap().args.rule_cache.clone();
+        if let Some(cache_path) = rule_cache_path {
+            let config_dir = configs::CONFIG.read().unwrap().args.config.clone();
+            rulefile_loader.rule_cache = Some(RuleCache::load(
+                &cache_path.unwrap_or_else(|| PathBuf::from("./cache/rule_cache.json")),
+                &config_dir,
+            ));
+        }
+        if let Some(mapping_path) = &configs::CONFIG.read().unwrap().args.logsource_mapping {
+            let mapping_path = LogsourceMapping::get_config_path(mapping_path);
+            match LogsourceMapping::load(&mapping_path) {
+                Ok(logsource_mapping) => {
+                    rulefile_loader.logsource_mapping = Some(logsource_mapping)
+                }
+                Err(e) => {
+                    AlertMessage::alert(&e).ok();
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(overrides_path) = &configs::CONFIG.read().unwrap().args.rule_overrides {
+            match RuleOverrides::load(overrides_path) {
+                Ok(rule_overrides) => rulefile_loader.rule_overrides = Some(rule_overrides),
+                Err(errors) => {
+                    AlertMessage::alert(&format!(
+                        "Failed to load the rule overrides file. ({})\n{}",
+                        overrides_path.display(),
+                        errors.join("\n")
+                    ))
+                    .ok();
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(overlay_path) = &configs::CONFIG.read().unwrap().args.level_overlay {
+            let overlay_path = LevelTuning::get_overlay_path(overlay_path);
+            match LevelTuning::read_tuning_file(&overlay_path) {
+                Ok(level_overlay) => rulefile_loader.level_overlay = level_overlay,
+                Err(e) => {
+                    AlertMessage::alert(&format!(
+                        "Failed to read the level overlay file. ({}) {}",
+                        overlay_path, e
+                    ))
+                    .ok();
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(suppression_path) = &configs::CONFIG.read().unwrap().args.suppression_list {
+            match SuppressionList::load(suppression_path) {
+                Ok(suppression_list) => {
+                    *SUPPRESSION_LIST.write().unwrap() = Some(suppression_list);
+                }
+                Err(errors) => {
+                    AlertMessage::alert(&format!(
+                        "Failed to load the suppression list file. ({})\n{}",
+                        suppression_path.display(),
+                        errors.join("\n")
+                    ))
+                    .ok();
+                    process::exit(1);
+                }
+            }
+        }
+        let result_readdir = rulefile_loader.read_rules(rulespath, &level, exclude_ids);
+        if let Some(rule_cache) = rulefile_loader.rule_cache.as_mut() {
+            Detection::save_rule_cache(rule_cache);
+        }
+        if let Some(rule_overrides) = rulefile_loader.rule_overrides.as_ref() {
+            for id in rule_overrides.unapplied_ids() {
+                let errmsg = format!("The rule override was not applied to any rule: {}", id);
+                if configs::CONFIG.read().unwrap().args.verbose {
+                    AlertMessage::warn(&errmsg).ok();
+                }
+                if !*QUIET_ERRORS_FLAG {
+                    ERROR_LOG_STACK
+                        .lock()
+                        .unwrap()
+                        .push(format!("[WARN] {}", errmsg));
+                }
+            }
+        }
         if result_readdir.is_err() {
             let errmsg = format!("{}", result_readdir.unwrap_err());
             if configs::CONFIG.read().unwrap().args.verbose {
@@ -86,6 +178,39 @@ impl Detection {
             }
             return vec![];
         }
+        let mut filter_rules: HashMap<String, Vec<(String, Yaml)>> = HashMap::new();
+        for (filter_path, filter_yaml) in rulefile_loader.filter_rules.iter() {
+            for id in Detection::get_filter_target_ids(filter_yaml) {
+                filter_rules
+                    .entry(id)
+                    .or_default()
+                    .push((filter_path.to_owned(), filter_yaml.to_owned()));
+            }
+        }
+        let loaded_ids: HashSet<String> = rulefile_loader
+            .files
+            .iter()
+            .filter_map(|(_, yaml)| yaml["id"].as_str().map(|id| id.to_string()))
+            .collect();
+        for (filter_path, filter_yaml) in rulefile_loader.filter_rules.iter() {
+            let target_ids = Detection::get_filter_target_ids(filter_yaml);
+            if target_ids.iter().any(|id| loaded_ids.contains(id)) {
+                continue;
+            }
+            let errmsg = format!(
+                "The filter rule was not applied to any rule. (FilePath : {})",
+                filter_path
+            );
+            if configs::CONFIG.read().unwrap().args.verbose {
+                AlertMessage::warn(&errmsg).ok();
+            }
+            if !*QUIET_ERRORS_FLAG {
+                ERROR_LOG_STACK
+                    .lock()
+                    .unwrap()
+                    .push(format!("[WARN] {}", errmsg));
+            }
+        }
         let mut parseerror_count = rulefile_loader.errorrule_count;
         let return_if_success = |mut rule: RuleNode| {
             let err_msgs_result = rule.init();
@@ -125,7 +250,14 @@ impl Detection {
         let ret = rulefile_loader
             .files
             .into_iter()
-            .map(|rule_file_tuple| rule::create_rule(rule_file_tuple.0, rule_file_tuple.1))
+            .map(|rule_file_tuple| {
+                let mut rule = rule::create_rule(rule_file_tuple.0, rule_file_tuple.1);
+                if let Some(filters) = rule.yaml["id"].as_str().and_then(|id| filter_rules.get(id))
+                {
+                    rule.filters = filters.to_owned();
+                }
+                rule
+            })
             .filter_map(return_if_success)
             .collect();
         if !*LOGONSUMMARY_FLAG {
@@ -139,6 +271,43 @@ impl Detection {
         ret
     }
 
+    /// フィルタールールのfilter.rulesに記載された対象のルールIDを取得する関数
+    fn get_filter_target_ids(filter_yaml: &Yaml) -> Vec<String> {
+        let rules = &filter_yaml["filter"]["rules"];
+        match rules.as_vec() {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| id.as_str().map(|id| id.to_string()))
+                .collect(),
+            None => rules
+                .as_str()
+                .map(|id| vec![id.to_string()])
+                .unwrap_or_default(),
+        }
+    }
+
+    /// ルールのキャッシュを保存する関数
+    fn save_rule_cache(rule_cache: &mut RuleCache) {
+        if configs::CONFIG.read().unwrap().args.verbose {
+            println!(
+                "Rule cache: {} hits, {} misses",
+                rule_cache.hit_count, rule_cache.miss_count
+            );
+        }
+        if let Err(err) = rule_cache.save() {
+            let errmsg = format!("Failed to save the rule cache. {}", err);
+            if configs::CONFIG.read().unwrap().args.verbose {
+                AlertMessage::warn(&errmsg).ok();
+            }
+            if !*QUIET_ERRORS_FLAG {
+                ERROR_LOG_STACK
+                    .lock()
+                    .unwrap()
+                    .push(format!("[WARN] {}", errmsg));
+            }
+        }
+    }
+
     // 複数のイベントレコードに対して、複数のルールを1個実行します。
     async fn execute_rules(mut self, records: Vec<EvtxRecordInfo>) -> Self {
         let records_arc = Arc::new(records);
@@ -167,7 +336,7 @@ impl Detection {
         self
     }
 
-    pub fn add_aggcondition_msges(self, rt: &Runtime) {
+    pub fn add_aggcondition_msges(&self, rt: &Runtime) {
         return rt.block_on(self.add_aggcondition_msg());
     }
 
@@ -188,7 +357,11 @@ impl Detection {
     fn execute_rule(mut rule: RuleNode, records: Arc<Vec<EvtxRecordInfo>>) -> RuleNode {
         let agg_condition = rule.has_agg_condition();
         for record_info in records.as_ref() {
-            let result = rule.select(record_info);
+            let result = if *RULE_PROFILING_FLAG {
+                Detection::select_with_profiling(&mut rule, record_info)
+            } else {
+                rule.select(record_info)
+            };
             if !result {
                 continue;
             }
@@ -207,8 +380,34 @@ impl Detection {
         rule
     }
 
+    /// ルールの評価時間、評価レコード数、検知数を計測しながらselectを実行する関数
+    fn select_with_profiling(rule: &mut RuleNode, record_info: &EvtxRecordInfo) -> bool {
+        let start = Instant::now();
+        let result = rule.select(record_info);
+        rule.profile.elapsed += start.elapsed();
+        rule.profile.evaluated_records += 1;
+        if result {
+            rule.profile.matched_records += 1;
+        }
+        result
+    }
+
+    /// --rule-profilingで計測した結果を出力する関数
+    pub fn output_rule_profiling(&self, output_path: Option<&PathBuf>) {
+        if let Err(err) = RuleProfiling::output(&self.rules, output_path) {
+            AlertMessage::alert(&err).ok();
+        }
+    }
+
     /// 条件に合致したレコードを格納するための関数
     fn insert_message(rule: &RuleNode, record_info: &EvtxRecordInfo) {
+        // --suppression-listの抑制条件に一致する検知は出力しない
+        if let Some(suppression_list) = SUPPRESSION_LIST.read().unwrap().as_ref() {
+            let rule_id = rule.yaml["id"].as_str().unwrap_or_default();
+            if suppression_list.is_suppressed(rule_id, &record_info.record) {
+                return;
+            }
+        }
         let tag_info: &Vec<String> = &Detection::get_tag_info(rule);
         let recinfo = record_info
             .record_information
@@ -245,6 +444,7 @@ impl Detection {
         let level = rule.yaml["level"].as_str().unwrap_or("-").to_string();
 
         let mut profile_converter: HashMap<String, String> = HashMap::new();
+        let mut matched_fields = vec![];
         for (_k, v) in PROFILES.as_ref().unwrap().iter() {
             let tmp = v.as_str();
             for target_profile in PRELOAD_PROFILE_REGEX.matches(tmp).into_iter() {
@@ -320,6 +520,12 @@ impl Detection {
                                 .to_string(),
                         );
                     }
+                    "%RulesRevision%" => {
+                        profile_converter.insert(
+                            "%RulesRevision%".to_string(),
+                            RULES_REVISION.as_deref().unwrap_or("-").to_string(),
+                        );
+                    }
                     "%MitreTactics%" => {
                         let tactics: &Vec<String> = &tag_info
                             .iter()
@@ -357,7 +563,21 @@ impl Detection {
                             .collect();
                         profile_converter.insert("%OtherTags%".to_string(), tags.join(" ¦ "));
                     }
-
+                    "%MatchedFields%" => {
+                        matched_fields = rule.get_matched_fields(record_info);
+                        let matched_fields: Vec<String> = matched_fields
+                            .iter()
+                            .map(|matched_field| {
+                                matched_field
+                                    .to_string()
+                                    .chars()
+                                    .filter(|&c| !c.is_control())
+                                    .collect()
+                            })
+                            .collect();
+                        profile_converter
+                            .insert("%MatchedFields%".to_string(), matched_fields.join(" ¦ "));
+                    }
                     _ => {}
                 }
             }
@@ -374,6 +594,7 @@ impl Detection {
             detail: String::default(),
             record_information: opt_record_info,
             ext_field: PROFILES.as_ref().unwrap().to_owned(),
+            matched_fields,
         };
         message::insert(
             &record_info.record,
@@ -390,6 +611,15 @@ impl Detection {
 
     /// insert aggregation condition detection message to output stack
     fn insert_agg_message(rule: &RuleNode, agg_result: AggResult) {
+        if let Some(suppression_list) = SUPPRESSION_LIST.read().unwrap().as_ref() {
+            let rule_id = rule.yaml["id"].as_str().unwrap_or_default();
+            let by_field = rule
+                .get_agg_condition()
+                .and_then(|agg_condition| agg_condition._by_field_name.as_deref());
+            if suppression_list.is_suppressed_agg(rule_id, by_field, &agg_result.key) {
+                return;
+            }
+        }
         let tag_info: &Vec<String> = &Detection::get_tag_info(rule);
         let output = Detection::create_count_output(rule, &agg_result);
         let rec_info = if LOAEDED_PROFILE_ALIAS.contains("%RecordInformation%") {
@@ -452,6 +682,12 @@ impl Detection {
                     "%EvtxFile%" => {
                         profile_converter.insert("%EvtxFile%".to_string(), "-".to_owned());
                     }
+                    "%RulesRevision%" => {
+                        profile_converter.insert(
+                            "%RulesRevision%".to_string(),
+                            RULES_REVISION.as_deref().unwrap_or("-").to_string(),
+                        );
+                    }
                     "%MitreTactics%" => {
                         let tactics: &Vec<String> = &tag_info
                             .iter()
@@ -489,6 +725,9 @@ impl Detection {
                             .collect();
                         profile_converter.insert("%OtherTags%".to_string(), tags.join(" ¦ "));
                     }
+                    "%MatchedFields%" => {
+                        profile_converter.insert("%MatchedFields%".to_string(), "-".to_owned());
+                    }
                     _ => {}
                 }
             }
@@ -503,6 +742,7 @@ impl Detection {
             detail: output,
             record_information: rec_info,
             ext_field: PROFILES.as_ref().unwrap().to_owned(),
+            matched_fields: vec![],
         };
 
         message::insert(
@@ -687,15 +927,15 @@ mod tests {
     use crate::detections::rule::AggResult;
     use crate::filter;
     use chrono::{TimeZone, Utc};
-    use std::path::Path;
+    use std::path::PathBuf;
     use yaml_rust::YamlLoader;
 
     #[test]
     fn test_parse_rule_files() {
         let level = "informational";
-        let opt_rule_path = Path::new("./test_files/rules/level_yaml");
+        let opt_rule_path = [PathBuf::from("./test_files/rules/level_yaml")];
         let cole =
-            Detection::parse_rule_files(level.to_owned(), opt_rule_path, &filter::exclude_ids());
+            Detection::parse_rule_files(level.to_owned(), &opt_rule_path, &filter::exclude_ids());
         assert_eq!(5, cole.len());
     }
 
@@ -1,10 +1,12 @@
 extern crate lazy_static;
 use crate::detections::configs;
 use crate::detections::configs::CURRENT_EXE_PATH;
+use crate::detections::rule::MatchedField;
 use crate::detections::utils;
 use crate::detections::utils::get_serde_number_to_string;
 use crate::detections::utils::write_color_buffer;
 use crate::options::profile::PROFILES;
+use crate::options::update_rules::RULES_REVISION;
 use chrono::{DateTime, Local, Utc};
 use dashmap::DashMap;
 use hashbrown::HashMap;
@@ -31,6 +33,8 @@ pub struct DetectInfo {
     pub detail: String,
     pub record_information: Option<String>,
     pub ext_field: LinkedHashMap<String, String>,
+    /// %MatchedFields%の出力時に条件に一致したselectionとフィールド。JSON出力でオブジェクトの配列として出力する
+    pub matched_fields: Vec<MatchedField>,
 }
 
 pub struct AlertMessage {}
@@ -65,6 +69,8 @@ lazy_static! {
     );
     pub static ref PIVOT_KEYWORD_LIST_FLAG: bool =
         configs::CONFIG.read().unwrap().args.pivot_keywords_list;
+    pub static ref RULE_PROFILING_FLAG: bool =
+        configs::CONFIG.read().unwrap().args.rule_profiling.is_some();
     pub static ref DEFAULT_DETAILS: HashMap<String, String> = get_default_details(
         utils::check_setting_path(&configs::CONFIG.read().unwrap().args.config, "default_details.txt", false).unwrap_or_else(|| {
             utils::check_setting_path(
@@ -152,7 +158,10 @@ pub fn insert(
     let mut tmp_converted_info: LinkedHashMap<String, String> = LinkedHashMap::new();
     for (k, v) in &detect_info.ext_field {
         let converted_reserve_info = convert_profile_reserved_info(v, profile_converter);
-        if v.contains("%RecordInformation%") || v.contains("%Details%") {
+        if v.contains("%RecordInformation%")
+            || v.contains("%Details%")
+            || v.contains("%MatchedFields%")
+        {
             tmp_converted_info.insert(k.to_owned(), converted_reserve_info);
         } else {
             tmp_converted_info.insert(
@@ -323,6 +332,9 @@ impl AlertMessage {
                 .as_bytes(),
             )
             .ok();
+        if let Some(revision) = &*RULES_REVISION {
+            writeln!(error_log_writer, "rules revision: {}", revision).ok();
+        }
         let error_logs = ERROR_LOG_STACK.lock().unwrap();
         error_logs.iter().for_each(|error_log| {
             writeln!(error_log_writer, "{}", error_log).ok();
@@ -644,6 +656,7 @@ mod tests {
                 detail: "".to_string(),
                 record_information: None,
                 ext_field: Default::default(),
+                matched_fields: vec![],
             };
             sample_detects.push((sample_event_time, detect_info, rng.gen_range(0..10)));
         }
@@ -413,7 +413,7 @@ impl ConditionCompiler {
             if let Some(select_node) = selection_node {
                 let selection_node = select_node;
                 let selection_node = Arc::clone(selection_node);
-                let ref_node = RefSelectionNode::new(selection_node);
+                let ref_node = RefSelectionNode::new(selection_name, selection_node);
                 return Result::Ok(Box::new(ref_node));
             } else {
                 let err_msg = format!("{} is not defined.", selection_name);
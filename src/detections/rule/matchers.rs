@@ -27,6 +27,9 @@ pub trait LeafMatcher: Downcast {
     /// 初期化ロジックをここに記載します。
     /// ルールファイルの書き方が間違っている等の原因により、正しくルールファイルからパースできない場合、戻り値のResult型でエラーを返してください。
     fn init(&mut self, key_list: &[String], select_value: &Yaml) -> Result<(), Vec<String>>;
+
+    /// ルールのデバッグ時に表示する、このmatcherの比較条件の説明を返す。
+    fn describe(&self) -> String;
 }
 downcast_rs::impl_downcast!(LeafMatcher);
 
@@ -70,6 +73,10 @@ impl LeafMatcher for MinlengthMatcher {
             None => false,
         }
     }
+
+    fn describe(&self) -> String {
+        format!("min_length: {}", self.min_len)
+    }
 }
 
 /// 正規表現のリストが記載されたファイルを読み取って、比較するロジックを表すクラス
@@ -127,6 +134,10 @@ impl LeafMatcher for RegexesFileMatcher {
             None => false,
         }
     }
+
+    fn describe(&self) -> String {
+        format!("regexes: {} patterns", self.regexes.len())
+    }
 }
 
 /// ファイルに列挙された文字列に一致する場合に検知するロジックを表す
@@ -184,6 +195,10 @@ impl LeafMatcher for AllowlistFileMatcher {
             None => true,
         }
     }
+
+    fn describe(&self) -> String {
+        format!("allowlist: {} patterns", self.regexes.len())
+    }
 }
 
 /// デフォルトのマッチクラス
@@ -370,6 +385,16 @@ impl LeafMatcher for DefaultMatcher {
             self.is_regex_fullmatch(event_value_str)
         }
     }
+
+    fn describe(&self) -> String {
+        if let Some(eqfield_key) = &self.eqfield_key {
+            format!("equalsfield: {}", eqfield_key)
+        } else if let Some(re) = &self.re {
+            format!("regex: {}", re.as_str())
+        } else {
+            "null (matches when the field does not exist)".to_string()
+        }
+    }
 }
 
 /// パイプ(|)で指定される要素を表すクラス。
@@ -2,14 +2,16 @@ extern crate regex;
 
 use chrono::{DateTime, Utc};
 
-use hashbrown::HashMap;
-use std::{fmt::Debug, sync::Arc, vec};
+use hashbrown::{HashMap, HashSet};
+use serde_json::Value;
+use std::{fmt::Debug, sync::Arc, time::Duration, vec};
 
 use yaml_rust::Yaml;
 
 mod matchers;
 mod selectionnodes;
-use self::selectionnodes::{LeafSelectionNode, SelectionNode};
+use self::selectionnodes::{LeafSelectionNode, NotSelectionNode, OrSelectionNode, SelectionNode};
+pub use self::selectionnodes::{MatchedField, SelectionTrace};
 mod aggregation_parser;
 use self::aggregation_parser::AggregationParseInfo;
 
@@ -18,6 +20,7 @@ mod count;
 use self::count::{AggRecordTimeInfo, TimeFrameInfo};
 
 use super::detection::EvtxRecordInfo;
+use super::utils;
 
 pub fn create_rule(rulepath: String, yaml: Yaml) -> RuleNode {
     RuleNode::new(rulepath, yaml)
@@ -27,10 +30,21 @@ pub fn create_rule(rulepath: String, yaml: Yaml) -> RuleNode {
 pub struct RuleNode {
     pub rulepath: String,
     pub yaml: Yaml,
+    pub profile: RuleProfile,
+    /// このルールのIDを対象とするフィルタールールのファイルパスと内容
+    pub filters: Vec<(String, Yaml)>,
     detection: DetectionNode,
     countdata: HashMap<String, Vec<AggRecordTimeInfo>>,
 }
 
+/// --rule-profiling指定時にルール毎の評価時間、評価レコード数、検知数を保持する構造体
+#[derive(Debug, Clone, Default)]
+pub struct RuleProfile {
+    pub elapsed: Duration,
+    pub evaluated_records: u64,
+    pub matched_records: u64,
+}
+
 impl Debug for RuleNode {
     fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Result::Ok(())
@@ -45,6 +59,8 @@ impl RuleNode {
         RuleNode {
             rulepath: rule_path,
             yaml: yaml_data,
+            profile: RuleProfile::default(),
+            filters: vec![],
             detection: DetectionNode::new(),
             countdata: HashMap::new(),
         }
@@ -54,7 +70,7 @@ impl RuleNode {
         let mut errmsgs: Vec<String> = vec![];
 
         // detection node initialization
-        let detection_result = self.detection.init(&self.yaml["detection"]);
+        let detection_result = self.detection.init(&self.yaml["detection"], &self.filters);
         if let Err(err_detail) = detection_result {
             errmsgs.extend(err_detail);
         }
@@ -86,6 +102,23 @@ impl RuleNode {
         ret.append(&mut count::aggregation_condition_select(self));
         ret
     }
+    /// 条件に一致したselectionとフィールドの一覧を返す関数。重複した内容は1つにまとめる
+    pub fn get_matched_fields(&self, event_record: &EvtxRecordInfo) -> Vec<MatchedField> {
+        let mut matched_fields = self
+            .trace(event_record)
+            .map(|trace| trace.get_matched_fields())
+            .unwrap_or_default();
+        let mut exist_fields = HashSet::new();
+        matched_fields.retain(|matched_field| exist_fields.insert(matched_field.clone()));
+        matched_fields
+    }
+    /// conditionの評価過程をノード毎に返す関数。conditionが無い場合はNoneを返す
+    pub fn trace(&self, event_record: &EvtxRecordInfo) -> Option<SelectionTrace> {
+        self.detection
+            .condition
+            .as_ref()
+            .map(|condition| condition.trace(event_record))
+    }
     pub fn check_exist_countdata(&self) -> bool {
         !self.countdata.is_empty()
     }
@@ -98,6 +131,13 @@ impl RuleNode {
     }
 }
 
+/// フィルタールールのfilterをパースし、エラーがあればエラーメッセージを返す
+pub fn validate_filter(filter_yaml: &Yaml) -> Result<(), Vec<String>> {
+    DetectionNode::new()
+        .init_filter(0, &filter_yaml["filter"])
+        .map(|_| ())
+}
+
 // RuleNodeのdetectionに定義されているキーの一覧を取得する。
 pub fn get_detection_keys(node: &RuleNode) -> Vec<String> {
     let mut ret = vec![];
@@ -125,6 +165,106 @@ pub fn get_detection_keys(node: &RuleNode) -> Vec<String> {
     ret
 }
 
+/// ルールが検知しうるEventIDとChannelの一覧。Noneの場合は条件による制約がないことを表す
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RuleTargets {
+    pub eventids: Option<HashSet<String>>,
+    pub channels: Option<HashSet<String>>,
+}
+
+impl RuleTargets {
+    /// 全てのレコードを対象とする(制約がない)状態を作成する
+    pub fn unconstrained() -> RuleTargets {
+        RuleTargets {
+            eventids: None,
+            channels: None,
+        }
+    }
+
+    /// 複数ルールの対象を合わせる。どちらかに制約がない場合は制約なしとなる
+    pub fn union(&mut self, other: &RuleTargets) {
+        let union_set =
+            |a: &mut Option<HashSet<String>>, b: &Option<HashSet<String>>| match (a.as_mut(), b) {
+                (Some(a_set), Some(b_set)) => a_set.extend(b_set.iter().cloned()),
+                _ => *a = None,
+            };
+        union_set(&mut self.eventids, &other.eventids);
+        union_set(&mut self.channels, &other.channels);
+    }
+
+    /// レコードのEventIDとChannelが対象に含まれるかを判定する。値が取得できない場合は対象とする
+    pub fn is_target(&self, record: &Value) -> bool {
+        if let Some(eventids) = &self.eventids {
+            let eid = utils::get_event_value(&utils::get_event_id_key(), record)
+                .and_then(utils::value_to_string);
+            if let Some(eid) = eid {
+                if !eventids.contains(&eid) {
+                    return false;
+                }
+            }
+        }
+        if let Some(channels) = &self.channels {
+            let channel = utils::get_event_value("Event.System.Channel", record)
+                .and_then(utils::value_to_string);
+            if let Some(channel) = channel {
+                if !channels.contains(&channel.to_lowercase()) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+/// RuleNodeのconditionを解析し、ルールが検知しうるEventIDとChannelの一覧を取得する。
+/// AND条件は積集合、OR条件は全ての子ノードに制約がある場合のみ和集合とし、NOT条件は制約なしとして扱う。
+pub fn get_rule_targets(node: &RuleNode) -> RuleTargets {
+    let condition = match &node.detection.condition {
+        Some(condition) => condition,
+        None => return RuleTargets::unconstrained(),
+    };
+    RuleTargets {
+        eventids: collect_target_values(condition.as_ref(), &utils::get_event_id_key(), false),
+        channels: collect_target_values(condition.as_ref(), "Event.System.Channel", true),
+    }
+}
+
+fn collect_target_values(
+    node: &dyn SelectionNode,
+    event_key: &str,
+    ignore_case: bool,
+) -> Option<HashSet<String>> {
+    if let Some(leaf) = node.downcast_ref::<LeafSelectionNode>() {
+        if utils::resolve_event_key(leaf.get_key()) != event_key {
+            return None;
+        }
+        return leaf.get_exact_value().map(|value| {
+            let value = if ignore_case {
+                value.to_lowercase()
+            } else {
+                value
+            };
+            HashSet::from([value])
+        });
+    }
+    if node.is::<NotSelectionNode>() {
+        return None;
+    }
+    if node.is::<OrSelectionNode>() {
+        let mut ret = HashSet::new();
+        for child in node.get_childs() {
+            ret.extend(collect_target_values(child, event_key, ignore_case)?);
+        }
+        return Some(ret);
+    }
+
+    // AndSelectionNodeとRefSelectionNodeは子ノードの制約の積集合を取る
+    node.get_childs()
+        .into_iter()
+        .filter_map(|child| collect_target_values(child, event_key, ignore_case))
+        .reduce(|acc, values| acc.intersection(&values).cloned().collect())
+}
+
 /// Ruleファイルのdetectionを表すノード
 struct DetectionNode {
     pub name_to_selection: HashMap<String, Arc<Box<dyn SelectionNode>>>,
@@ -143,7 +283,11 @@ impl DetectionNode {
         }
     }
 
-    fn init(&mut self, detection_yaml: &Yaml) -> Result<(), Vec<String>> {
+    fn init(
+        &mut self,
+        detection_yaml: &Yaml,
+        filters: &[(String, Yaml)],
+    ) -> Result<(), Vec<String>> {
         // selection nodeの初期化
         self.parse_name_to_selection(detection_yaml)?;
 
@@ -180,6 +324,25 @@ impl DetectionNode {
             self.condition = Option::Some(compile_result.unwrap());
         }
 
+        // フィルタールールのconditionをルールのconditionとANDで結合する
+        if !filters.is_empty() && self.condition.is_some() {
+            let mut and_node = selectionnodes::AndSelectionNode::new();
+            and_node.child_nodes.push(self.condition.take().unwrap());
+            for (index, (filter_path, filter_yaml)) in filters.iter().enumerate() {
+                match self.init_filter(index, &filter_yaml["filter"]) {
+                    Ok(filter_node) => and_node.child_nodes.push(filter_node),
+                    Err(filter_errs) => {
+                        err_msgs.push(format!(
+                            "Failed to apply the filter rule. (FilePath : {})",
+                            filter_path
+                        ));
+                        err_msgs.extend(filter_errs);
+                    }
+                }
+            }
+            self.condition = Option::Some(Box::new(and_node));
+        }
+
         // aggregation condition(conditionのパイプ以降の部分)をパース
         let agg_compiler = aggregation_parser::AggegationConditionCompiler::new();
         let compile_result = agg_compiler.compile(condition_str);
@@ -205,6 +368,51 @@ impl DetectionNode {
         condition.select(event_record)
     }
 
+    /// フィルタールールのselectionとconditionをパースする。
+    /// conditionが無い場合、selectionが一つだけならそのselectionのNOT条件とする
+    fn init_filter(
+        &mut self,
+        index: usize,
+        filter_yaml: &Yaml,
+    ) -> Result<Box<dyn SelectionNode>, Vec<String>> {
+        let mut filter_hash = match filter_yaml.as_hash() {
+            Some(filter_hash) => filter_hash.clone(),
+            None => return Result::Err(vec!["Filter node was not found.".to_string()]),
+        };
+        filter_hash.remove(&Yaml::String("rules".to_string()));
+        let mut filter_node = DetectionNode::new();
+        filter_node.parse_name_to_selection(&Yaml::Hash(filter_hash))?;
+
+        let condition_str = match filter_yaml["condition"].as_str() {
+            Some(cond_str) => cond_str.to_string(),
+            None => {
+                let mut keys = filter_node.name_to_selection.keys();
+                if keys.len() >= 2 {
+                    return Result::Err(vec![
+                        "There is no condition node under filter.".to_string()
+                    ]);
+                }
+                format!("not {}", keys.next().unwrap())
+            }
+        };
+        if condition_parser::RE_PIPE.is_match(&condition_str) {
+            return Result::Err(vec![
+                "The aggregation condition cannot be used in filter rules.".to_string(),
+            ]);
+        }
+        let compiler = condition_parser::ConditionCompiler::new();
+        let condition = compiler
+            .compile_condition(condition_str, &filter_node.name_to_selection)
+            .map_err(|err_msg| vec![err_msg])?;
+
+        // get_detection_keysでフィルタールールのフィールドも取得できるよう、ルールのselectionと重複しない名前で追加する
+        for (name, selection) in filter_node.name_to_selection.into_iter() {
+            self.name_to_selection
+                .insert(format!("hayabusa_filter{}_{}", index, name), selection);
+        }
+        Result::Ok(condition)
+    }
+
     /// selectionノードをパースします。
     fn parse_name_to_selection(&mut self, detection_yaml: &Yaml) -> Result<(), Vec<String>> {
         let detection_hash = detection_yaml.as_hash();
@@ -970,4 +1178,251 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_get_rule_targets() {
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection1:
+                Event.System.Channel: Security
+                Event.System.EventID:
+                    - 4624
+                    - 4625
+            selection2:
+                Event.System.EventID: 4625
+                Event.System.Computer: DESKTOP-ICHIICHI
+            filter:
+                Event.System.EventID: 4648
+            condition: selection1 and selection2 and not filter
+        "#;
+        let targets = super::get_rule_targets(&parse_rule_from_str(rule_str));
+        assert_eq!(
+            targets.eventids,
+            Some(hashbrown::HashSet::from(["4625".to_string()]))
+        );
+        assert_eq!(
+            targets.channels,
+            Some(hashbrown::HashSet::from(["security".to_string()]))
+        );
+
+        // OR条件の片方にEventIDの制約がない場合やワイルドカードが使われている場合は制約なしとする
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection1:
+                Event.System.EventID: 4624
+            selection2:
+                Event.System.EventID|startswith: '46'
+            selection3:
+                Event.System.Channel: 'Microsoft-Windows-Sysmon/*'
+            condition: (selection1 or selection2) and selection3
+        "#;
+        let targets = super::get_rule_targets(&parse_rule_from_str(rule_str));
+        assert_eq!(targets, super::RuleTargets::unconstrained());
+    }
+
+    #[test]
+    fn test_get_matched_fields() {
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection:
+                Event.System.EventID: 1
+            selection_img:
+                - Event.EventData.Image|endswith: 'cmd.exe'
+                - Event.EventData.Image|endswith: 'powershell.exe'
+            selection_cmd:
+                Event.EventData.CommandLine|contains: 'whoami'
+            filter:
+                Event.EventData.User: SYSTEM
+            condition: selection and (selection_img or selection_cmd) and not filter
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        let record_json_str = r#"
+        {
+            "Event": {
+                "System": {"EventID": 1},
+                "EventData": {"Image": "C:\\Windows\\System32\\cmd.exe", "CommandLine": "cmd.exe /c whoami", "User": "user01"}
+            }
+        }"#;
+        let record: serde_json::Value = serde_json::from_str(record_json_str).unwrap();
+        let keys = detections::rule::get_detection_keys(&rule_node);
+        let recinfo = utils::create_rec_info(record, "testpath".to_owned(), &keys);
+        let matched_fields: Vec<String> = rule_node
+            .get_matched_fields(&recinfo)
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        assert_eq!(
+            matched_fields,
+            vec![
+                "selection.Event.System.EventID: 1",
+                "selection_img.Event.EventData.Image|endswith: C:\\Windows\\System32\\cmd.exe",
+                "selection_cmd.Event.EventData.CommandLine|contains: cmd.exe /c whoami",
+            ]
+        );
+
+        // 条件に一致しない場合は何も返さない
+        let record_json_str = r#"
+        {
+            "Event": {
+                "System": {"EventID": 1},
+                "EventData": {"Image": "C:\\Windows\\System32\\cmd.exe", "User": "SYSTEM"}
+            }
+        }"#;
+        let record: serde_json::Value = serde_json::from_str(record_json_str).unwrap();
+        let recinfo = utils::create_rec_info(record, "testpath".to_owned(), &keys);
+        assert!(rule_node.get_matched_fields(&recinfo).is_empty());
+    }
+
+    #[test]
+    fn test_trace() {
+        let rule_str = r#"
+        enabled: true
+        detection:
+            selection:
+                Event.System.EventID: 1
+                Event.EventData.CommandLine|contains: 'whoami'
+            filter:
+                Event.EventData.User: SYSTEM
+            condition: selection and not filter
+        "#;
+        let rule_node = parse_rule_from_str(rule_str);
+        let record_json_str = r#"
+        {
+            "Event": {
+                "System": {"EventID": 1},
+                "EventData": {"User": "user01"}
+            }
+        }"#;
+        let record: serde_json::Value = serde_json::from_str(record_json_str).unwrap();
+        let keys = detections::rule::get_detection_keys(&rule_node);
+        let recinfo = utils::create_rec_info(record, "testpath".to_owned(), &keys);
+        let trace = rule_node.trace(&recinfo).unwrap();
+        assert!(!trace.result);
+        assert_eq!(trace.label, "AND");
+
+        // selectionはCommandLineが存在しないため一致しない
+        let selection = &trace.children[0];
+        assert_eq!(selection.label, "selection");
+        assert!(!selection.result);
+        let leaves = &selection.children[0].children;
+        assert!(leaves[0].result);
+        assert!(!leaves[1].result);
+        assert_eq!(leaves[1].label, "Event.EventData.CommandLine|contains");
+        assert!(leaves[1]
+            .details
+            .contains(&"value: (the field does not exist in the record)".to_string()));
+
+        // filterは値が一致しないため、notの評価結果は一致となる
+        let not_filter = &trace.children[1];
+        assert_eq!(not_filter.label, "NOT");
+        assert!(not_filter.result);
+        let filter_leaf = &not_filter.children[0].children[0];
+        assert!(!filter_leaf.result);
+        assert!(filter_leaf.details.contains(&"value: user01".to_string()));
+        assert!(filter_leaf
+            .details
+            .contains(&"reason: the value does not match the pattern".to_string()));
+    }
+
+    #[test]
+    fn test_rule_targets_union_and_is_target() {
+        let mut targets = super::RuleTargets {
+            eventids: Some(hashbrown::HashSet::from(["4624".to_string()])),
+            channels: Some(hashbrown::HashSet::from(["security".to_string()])),
+        };
+        targets.union(&super::RuleTargets {
+            eventids: Some(hashbrown::HashSet::from(["1".to_string()])),
+            channels: None,
+        });
+        assert_eq!(
+            targets.eventids,
+            Some(hashbrown::HashSet::from([
+                "4624".to_string(),
+                "1".to_string()
+            ]))
+        );
+        assert_eq!(targets.channels, None);
+
+        let record: serde_json::Value = serde_json::from_str(
+            r#"{"Event": {"System": {"EventID": 4624, "Channel": "Security"}}}"#,
+        )
+        .unwrap();
+        assert!(targets.is_target(&record));
+        let record: serde_json::Value = serde_json::from_str(
+            r#"{"Event": {"System": {"EventID": 4625, "Channel": "Security"}}}"#,
+        )
+        .unwrap();
+        assert!(!targets.is_target(&record));
+    }
+
+    #[test]
+    fn test_filter_rule() {
+        let rule_str = r#"
+        id: 5b0b75dc-9190-4047-b9a8-14164cee8a31
+        detection:
+            selection:
+                Event.System.EventID: 1
+                Event.EventData.CommandLine|contains: 'whoami'
+            condition: selection
+        "#;
+        let filter_str = r#"
+        title: Filter Backup Software
+        filter:
+            rules:
+                - 5b0b75dc-9190-4047-b9a8-14164cee8a31
+            selection:
+                Event.EventData.User: backup_admin
+        "#;
+        let mut rule_node = create_rule(
+            "testpath".to_string(),
+            YamlLoader::load_from_str(rule_str).unwrap()[0].clone(),
+        );
+        rule_node.filters = vec![(
+            "filterpath".to_string(),
+            YamlLoader::load_from_str(filter_str).unwrap()[0].clone(),
+        )];
+        assert!(rule_node.init().is_ok());
+        // フィルタールールのフィールドもレコードから取得する対象となる
+        let keys = detections::rule::get_detection_keys(&rule_node);
+        assert!(keys.contains(&"Event.EventData.User".to_string()));
+
+        let mut is_match = |user: &str| {
+            let record_json_str = format!(
+                r#"{{"Event": {{"System": {{"EventID": 1}}, "EventData": {{"CommandLine": "whoami /all", "User": "{}"}}}}}}"#,
+                user
+            );
+            let record: serde_json::Value = serde_json::from_str(&record_json_str).unwrap();
+            let recinfo = utils::create_rec_info(record, "testpath".to_owned(), &keys);
+            rule_node.select(&recinfo)
+        };
+        assert!(is_match("user01"));
+        assert!(!is_match("backup_admin"));
+
+        // aggregation conditionを含むフィルタールールはエラーとなる
+        let filter_str = r#"
+        filter:
+            rules: 5b0b75dc-9190-4047-b9a8-14164cee8a31
+            selection:
+                Event.EventData.User: backup_admin
+            condition: not selection | count() > 1
+        "#;
+        let mut rule_node = create_rule(
+            "testpath".to_string(),
+            YamlLoader::load_from_str(rule_str).unwrap()[0].clone(),
+        );
+        rule_node.filters = vec![(
+            "filterpath".to_string(),
+            YamlLoader::load_from_str(filter_str).unwrap()[0].clone(),
+        )];
+        assert_eq!(
+            rule_node.init().unwrap_err(),
+            vec![
+                "Failed to apply the filter rule. (FilePath : filterpath)",
+                "The aggregation condition cannot be used in filter rules.",
+            ]
+        );
+    }
 }
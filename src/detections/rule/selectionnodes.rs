@@ -1,4 +1,4 @@
-use crate::detections::{detection::EvtxRecordInfo, utils};
+use crate::detections::{configs, detection::EvtxRecordInfo, utils};
 use downcast_rs::Downcast;
 use std::{sync::Arc, vec};
 use yaml_rust::Yaml;
@@ -22,9 +22,90 @@ pub trait SelectionNode: Downcast {
 
     // 子孫ノードを取得する(グラフ理論のdescendantと同じ意味)
     fn get_descendants(&self) -> Vec<&dyn SelectionNode>;
+
+    // 子孫ノードも含めた評価結果を返す。--explainで一致したフィールドを取得する場合とルールのデバッグで使用する
+    // 末端ノードの判定はselectを使い、AND/ORの結果が確定した後も全ての子ノードを評価する
+    fn trace(&self, event_record: &EvtxRecordInfo) -> SelectionTrace;
 }
 downcast_rs::impl_downcast!(SelectionNode);
 
+/// SelectionTraceのノードの種類
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectionTraceKind {
+    And,
+    Or,
+    Not,
+    /// conditionで参照されたselection。labelがselectionの名前になる
+    Selection,
+    Leaf,
+}
+
+/// ノード毎の評価結果を保持する構造体
+#[derive(Debug, Clone)]
+pub struct SelectionTrace {
+    pub kind: SelectionTraceKind,
+    pub label: String,
+    pub result: bool,
+    /// 末端ノードの比較条件やレコードの値などの情報
+    pub details: Vec<String>,
+    /// 末端ノードが条件に一致した場合の、一致したフィールドと値
+    pub matched_field: Option<MatchedField>,
+    pub children: Vec<SelectionTrace>,
+}
+
+impl SelectionTrace {
+    /// 条件に一致した理由となったselectionとフィールドの一覧を返す。
+    /// 一致しなかったノードと、否定された条件(NOT)の配下のノードは一致した理由にならないため含めない
+    pub fn get_matched_fields(&self) -> Vec<MatchedField> {
+        let mut matched_fields = vec![];
+        self.collect_matched_fields("", &mut matched_fields);
+        matched_fields
+    }
+
+    fn collect_matched_fields(&self, selection: &str, matched_fields: &mut Vec<MatchedField>) {
+        if !self.result {
+            return;
+        }
+        match self.kind {
+            SelectionTraceKind::Not => {}
+            SelectionTraceKind::Leaf => {
+                if let Some(matched_field) = &self.matched_field {
+                    matched_fields.push(MatchedField {
+                        selection: selection.to_string(),
+                        ..matched_field.clone()
+                    });
+                }
+            }
+            SelectionTraceKind::Selection => self
+                .children
+                .iter()
+                .for_each(|child| child.collect_matched_fields(&self.label, matched_fields)),
+            SelectionTraceKind::And | SelectionTraceKind::Or => self
+                .children
+                .iter()
+                .for_each(|child| child.collect_matched_fields(selection, matched_fields)),
+        }
+    }
+}
+
+/// 検知したレコードで条件に一致したselectionとフィールドの情報
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MatchedField {
+    pub selection: String,
+    pub key: String,
+    pub value: String,
+}
+
+impl std::fmt::Display for MatchedField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}: {}", self.selection, self.value)
+        } else {
+            write!(f, "{}.{}: {}", self.selection, self.key, self.value)
+        }
+    }
+}
+
 /// detection - selection配下でAND条件を表すノード
 pub struct AndSelectionNode {
     pub child_nodes: Vec<Box<dyn SelectionNode>>,
@@ -45,6 +126,26 @@ impl SelectionNode for AndSelectionNode {
             .all(|child_node| child_node.select(event_record))
     }
 
+    fn trace(&self, event_record: &EvtxRecordInfo) -> SelectionTrace {
+        // conditionのパース時に作られる子ノードが1つだけのノードは、出力が冗長になるため省略する
+        if self.child_nodes.len() == 1 {
+            return self.child_nodes[0].trace(event_record);
+        }
+        let children: Vec<SelectionTrace> = self
+            .child_nodes
+            .iter()
+            .map(|child_node| child_node.trace(event_record))
+            .collect();
+        SelectionTrace {
+            kind: SelectionTraceKind::And,
+            label: "AND".to_string(),
+            result: children.iter().all(|child| child.result),
+            details: vec![],
+            matched_field: None,
+            children,
+        }
+    }
+
     fn init(&mut self) -> Result<(), Vec<String>> {
         let err_msgs = self
             .child_nodes
@@ -115,6 +216,26 @@ impl SelectionNode for OrSelectionNode {
             .any(|child_node| child_node.select(event_record))
     }
 
+    fn trace(&self, event_record: &EvtxRecordInfo) -> SelectionTrace {
+        // conditionのパース時に作られる子ノードが1つだけのノードは、出力が冗長になるため省略する
+        if self.child_nodes.len() == 1 {
+            return self.child_nodes[0].trace(event_record);
+        }
+        let children: Vec<SelectionTrace> = self
+            .child_nodes
+            .iter()
+            .map(|child_node| child_node.trace(event_record))
+            .collect();
+        SelectionTrace {
+            kind: SelectionTraceKind::Or,
+            label: "OR".to_string(),
+            result: children.iter().any(|child| child.result),
+            details: vec![],
+            matched_field: None,
+            children,
+        }
+    }
+
     fn init(&mut self) -> Result<(), Vec<String>> {
         let err_msgs = self
             .child_nodes
@@ -181,6 +302,18 @@ impl SelectionNode for NotSelectionNode {
         !self.node.select(event_record)
     }
 
+    fn trace(&self, event_record: &EvtxRecordInfo) -> SelectionTrace {
+        let child = self.node.trace(event_record);
+        SelectionTrace {
+            kind: SelectionTraceKind::Not,
+            label: "NOT".to_string(),
+            result: !child.result,
+            details: vec![],
+            matched_field: None,
+            children: vec![child],
+        }
+    }
+
     fn init(&mut self) -> Result<(), Vec<String>> {
         Result::Ok(())
     }
@@ -200,12 +333,17 @@ pub struct RefSelectionNode {
     // そこでArcを使って、DetectionNodeのname_2_nodeとRefSelectionNodeのselection_nodeで所有権を共有する。
     // RcじゃなくてArcなのはマルチスレッド対応のため
     selection_node: Arc<Box<dyn SelectionNode>>,
+    selection_name: String,
 }
 
 impl RefSelectionNode {
-    pub fn new(select_node: Arc<Box<dyn SelectionNode>>) -> RefSelectionNode {
+    pub fn new(
+        selection_name: String,
+        select_node: Arc<Box<dyn SelectionNode>>,
+    ) -> RefSelectionNode {
         RefSelectionNode {
             selection_node: select_node,
+            selection_name,
         }
     }
 }
@@ -215,6 +353,18 @@ impl SelectionNode for RefSelectionNode {
         self.selection_node.select(event_record)
     }
 
+    fn trace(&self, event_record: &EvtxRecordInfo) -> SelectionTrace {
+        let child = self.selection_node.trace(event_record);
+        SelectionTrace {
+            kind: SelectionTraceKind::Selection,
+            label: self.selection_name.to_owned(),
+            result: child.result,
+            details: vec![],
+            matched_field: None,
+            children: vec![child],
+        }
+    }
+
     fn init(&mut self) -> Result<(), Vec<String>> {
         Result::Ok(())
     }
@@ -268,6 +418,22 @@ impl LeafSelectionNode {
         keys
     }
 
+    /// パイプやワイルドカードを含まない完全一致の条件の場合、その値を返す。それ以外の場合はNoneを返す
+    pub fn get_exact_value(&self) -> Option<String> {
+        if self.key_list.len() != 1 || self.key_list[0].contains('|') {
+            return None;
+        }
+        let value = match &self.select_value {
+            Yaml::Integer(i) => i.to_string(),
+            Yaml::String(s) => s.to_owned(),
+            _ => return None,
+        };
+        if value.contains(['*', '?', '\\']) {
+            return None;
+        }
+        Some(value)
+    }
+
     fn _create_key(&self) -> String {
         if self.key_list.is_empty() {
             return String::default();
@@ -288,6 +454,29 @@ impl LeafSelectionNode {
         record.get_value(self.get_key())
     }
 
+    /// 条件に一致した場合に出力するフィールドと値を取得する。selectionの名前は参照元のノードで設定する
+    fn get_matched_field(&self, event_record: &EvtxRecordInfo) -> MatchedField {
+        // キーワード検索の場合はレコード全体ではなく一致したキーワードを出力する
+        let value = if self.key_list.is_empty() {
+            match &self.select_value {
+                Yaml::String(s) => s.to_owned(),
+                Yaml::Integer(i) => i.to_string(),
+                Yaml::Real(r) => r.to_owned(),
+                Yaml::Boolean(b) => b.to_string(),
+                _ => String::default(),
+            }
+        } else {
+            self.get_event_value(event_record)
+                .cloned()
+                .unwrap_or_default()
+        };
+        MatchedField {
+            selection: String::default(),
+            key: self.key_list.join("."),
+            value,
+        }
+    }
+
     /// matchers::LeafMatcherの一覧を取得する。
     /// 上から順番に調べて、一番始めに一致したMatcherが適用される
     fn get_matchers(&self) -> Vec<Box<dyn matchers::LeafMatcher>> {
@@ -378,6 +567,53 @@ impl SelectionNode for LeafSelectionNode {
             .is_match(event_value, event_record);
     }
 
+    fn trace(&self, event_record: &EvtxRecordInfo) -> SelectionTrace {
+        let result = self.select(event_record);
+        let mut details = vec![];
+        if let Some(matcher) = &self.matcher {
+            details.push(format!("pattern: {}", matcher.describe()));
+        }
+        if self.key_list.is_empty() {
+            details.push("field: (all fields of the record)".to_string());
+        } else {
+            let key = self.get_key();
+            details.push(match configs::EVENTKEY_ALIAS.get_event_key(key) {
+                Some(event_key) => format!("field: {} -> {} (eventkey_alias.txt)", key, event_key),
+                None if !key.contains('.') => format!(
+                    "field: {} -> {} (not defined in eventkey_alias.txt)",
+                    key,
+                    utils::resolve_event_key(key)
+                ),
+                None => format!("field: {}", key),
+            });
+            match self.get_event_value(event_record) {
+                Some(value) => {
+                    details.push(format!("value: {}", value));
+                    if !result {
+                        details.push("reason: the value does not match the pattern".to_string());
+                    }
+                }
+                None => details.push("value: (the field does not exist in the record)".to_string()),
+            }
+        }
+        SelectionTrace {
+            kind: SelectionTraceKind::Leaf,
+            label: if self.key_list.is_empty() {
+                "(keyword)".to_string()
+            } else {
+                self.key_list.join(".")
+            },
+            result,
+            details,
+            matched_field: if result {
+                Some(self.get_matched_field(event_record))
+            } else {
+                None
+            },
+            children: vec![],
+        }
+    }
+
     fn init(&mut self) -> Result<(), Vec<String>> {
         let match_key_list = self.key_list.clone();
         let matchers = self.get_matchers();
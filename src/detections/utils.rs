@@ -18,7 +18,7 @@ use chrono::{DateTime, TimeZone, Utc};
 use regex::Regex;
 use serde_json::Value;
 use std::cmp::Ordering;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufRead, BufReader};
@@ -26,6 +26,7 @@ use std::str;
 use std::string::String;
 use std::vec;
 use termcolor::{BufferWriter, ColorSpec, WriteColor};
+use yaml_rust::{Yaml, YamlLoader};
 
 use super::detection::EvtxRecordInfo;
 
@@ -159,6 +160,17 @@ pub fn get_serde_number_to_string(value: &serde_json::Value) -> Option<String> {
     }
 }
 
+/// ルールで指定されたキーをaliasを考慮してイベントのキー(ex. Event.System.EventID)に変換する
+pub fn resolve_event_key(key: &str) -> String {
+    if let Some(event_key) = configs::EVENTKEY_ALIAS.get_event_key(key) {
+        event_key.to_string()
+    } else if !key.contains('.') {
+        "Event.EventData.".to_string() + key
+    } else {
+        key.to_string()
+    }
+}
+
 pub fn get_event_value<'a>(key: &str, event_value: &'a Value) -> Option<&'a Value> {
     if key.is_empty() {
         return Option::None;
@@ -386,6 +398,41 @@ pub fn check_setting_path(base_path: &Path, path: &str, ignore_err: bool) -> Opt
     }
 }
 
+/// -cで指定したrules/configのフォルダにあるfile_nameのパスを返す。存在しない場合は実行ファイルのフォルダからdefault_pathを参照するパスを返す
+pub fn get_rules_config_path(file_name: &str, default_path: &str) -> PathBuf {
+    check_setting_path(
+        &configs::CONFIG.read().unwrap().args.config,
+        file_name,
+        false,
+    )
+    .unwrap_or_else(|| {
+        check_setting_path(&CURRENT_EXE_PATH.to_path_buf(), default_path, true).unwrap()
+    })
+}
+
+/// 設定ファイルの内容を読み込む。file_kindはエラーメッセージに表示するファイルの種類
+pub fn read_config_file(path: &Path, file_kind: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| {
+        format!(
+            "Failed to read the {} file. ({}) {}",
+            file_kind,
+            path.display(),
+            e
+        )
+    })
+}
+
+/// 定義の一覧を記載したYAMLをパースし、最初のドキュメントの各定義を返す。ドキュメントが無い場合は空の一覧を返す
+pub fn parse_yaml_list(content: &str, file_kind: &str) -> Result<Vec<Yaml>, String> {
+    let docs = YamlLoader::load_from_str(content)
+        .map_err(|e| format!("Failed to parse the {} file. {}", file_kind, e))?;
+    match docs.into_iter().next() {
+        Some(Yaml::Array(entries)) => Ok(entries),
+        None => Ok(vec![]),
+        Some(_) => Err(format!("The {} file must be a list.", file_kind)),
+    }
+}
+
 /// rule configのファイルの所在を確認する関数。
 pub fn check_rule_config() -> Result<(), String> {
     // rules/configのフォルダが存在するかを確認する
@@ -682,4 +729,17 @@ mod tests {
             "fake"
         );
     }
+
+    #[test]
+    fn test_parse_yaml_list() {
+        assert_eq!(utils::parse_yaml_list("- a\n- b", "test").unwrap().len(), 2);
+        assert!(utils::parse_yaml_list("", "test").unwrap().is_empty());
+        assert_eq!(
+            utils::parse_yaml_list("a: b", "test").unwrap_err(),
+            "The test file must be a list."
+        );
+        assert!(utils::parse_yaml_list("- [a", "test")
+            .unwrap_err()
+            .starts_with("Failed to parse the test file."));
+    }
 }
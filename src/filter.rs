@@ -2,10 +2,15 @@ use crate::detections::configs;
 use crate::detections::message::AlertMessage;
 use crate::detections::message::ERROR_LOG_STACK;
 use crate::detections::message::QUIET_ERRORS_FLAG;
-use hashbrown::HashMap;
+use crate::detections::message::TAGS_CONFIG;
+use crate::detections::utils;
+use hashbrown::{HashMap, HashSet};
 use regex::Regex;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process;
+use yaml_rust::{Yaml, YamlLoader};
 
 #[derive(Debug)]
 pub struct DataFilterRule {
@@ -16,14 +21,236 @@ pub struct DataFilterRule {
 #[derive(Clone, Debug)]
 pub struct RuleExclude {
     pub no_use_rule: HashMap<String, String>,
+    /// 指定されたタグのいずれかを持つルールのみ読み込む
+    pub include_tags: Vec<String>,
+    /// 指定されたタグのいずれかを持つルールは読み込まない
+    pub exclude_tags: Vec<String>,
+    /// 指定された場合、ファイルに記載されたIDのルールのみ読み込む
+    pub include_rule_ids: Option<HashSet<String>>,
+    pub exclude_rule_ids: HashSet<String>,
+    /// 作者に指定された文字列のいずれかを含むルールのみ読み込む
+    pub include_authors: Vec<String>,
+    pub exclude_authors: Vec<String>,
+    /// logsourceの(項目, 値)の組。同じ項目の条件はOR、異なる項目の条件はANDで判定する
+    pub include_logsources: Vec<(String, String)>,
+    pub exclude_logsources: Vec<(String, String)>,
+    /// ルールセットで指定された最低レベル
+    pub min_level: Option<String>,
+    /// 指定された場合、いずれかのステータスのルールのみ読み込む
+    pub include_statuses: Vec<String>,
+    pub exclude_statuses: Vec<String>,
+    /// ルールセットで指定されたステータス、タグ、ルールID、作者、logsourceの読み込み対象の条件。
+    /// コマンドラインで指定された読み込み対象の条件と、両方の条件を満たすルールのみ読み込む
+    pub rule_set_includes: Option<Box<RuleExclude>>,
 }
 
 impl RuleExclude {
     pub fn default() -> RuleExclude {
         RuleExclude {
             no_use_rule: HashMap::new(),
+            include_tags: vec![],
+            exclude_tags: vec![],
+            include_rule_ids: None,
+            exclude_rule_ids: HashSet::new(),
+            include_authors: vec![],
+            exclude_authors: vec![],
+            include_logsources: vec![],
+            exclude_logsources: vec![],
+            min_level: None,
+            include_statuses: vec![],
+            exclude_statuses: vec![],
+            rule_set_includes: None,
         }
     }
+
+    /// rule_sets.yamlから指定された名前のルールセットを読み込み、条件を追加する。
+    /// 除外の条件はコマンドラインの条件に追加し、読み込み対象の条件はコマンドラインの条件とは別に判定する
+    pub fn load_rule_set(&mut self, path: &str, name: &str) -> Result<(), String> {
+        let content = utils::read_config_file(Path::new(path), "rule set")?;
+        let docs = YamlLoader::load_from_str(&content)
+            .map_err(|e| format!("Failed to parse the rule set file. ({}) {}", path, e))?;
+        let rule_sets = docs.first().and_then(|doc| doc.as_hash()).ok_or_else(|| {
+            format!(
+                "The rule set file does not define any rule sets. ({})",
+                path
+            )
+        })?;
+        let rule_set = rule_sets
+            .get(&Yaml::String(name.to_string()))
+            .ok_or_else(|| {
+                let names: Vec<&str> = rule_sets.keys().filter_map(|k| k.as_str()).collect();
+                format!(
+                    "The rule set {} is not defined in {}. Available rule sets: {}",
+                    name,
+                    path,
+                    names.join(", ")
+                )
+            })?;
+
+        if let Some(level) = rule_set["min-level"].as_str() {
+            if !configs::LEVELMAP.contains_key(&level.to_uppercase()) {
+                return Err(format!(
+                    "Invalid min-level in the rule set {}: {}",
+                    name, level
+                ));
+            }
+            self.min_level = Some(level.to_uppercase());
+        }
+        self.exclude_statuses
+            .extend(get_str_list(&rule_set["exclude-status"]));
+        self.exclude_tags
+            .extend(get_str_list(&rule_set["exclude-tags"]));
+        self.exclude_rule_ids
+            .extend(get_str_list(&rule_set["exclude-ids"]));
+        self.exclude_authors
+            .extend(get_str_list(&rule_set["exclude-authors"]));
+        let mut includes = RuleExclude::default();
+        includes.include_statuses = get_str_list(&rule_set["include-status"]);
+        includes.include_tags = get_str_list(&rule_set["include-tags"]);
+        let include_ids = get_str_list(&rule_set["include-ids"]);
+        if !include_ids.is_empty() {
+            includes.include_rule_ids = Some(include_ids.into_iter().collect());
+        }
+        includes.include_authors = get_str_list(&rule_set["include-authors"]);
+        includes.include_logsources =
+            parse_logsource_filters(Some(&get_str_list(&rule_set["include-logsources"])));
+        self.rule_set_includes = Some(Box::new(includes));
+        self.exclude_logsources
+            .extend(parse_logsource_filters(Some(&get_str_list(
+                &rule_set["exclude-logsources"],
+            ))));
+        Ok(())
+    }
+
+    /// レベル、ステータス、タグ、ルールID、作者、logsourceの条件で読み込み対象外となるルールの場合にtrueを返す
+    pub fn is_filtered(&self, yaml: &Yaml) -> bool {
+        if matches!(&self.rule_set_includes, Some(includes) if includes.is_filtered(yaml)) {
+            return true;
+        }
+        if let Some(min_level) = &self.min_level {
+            let level = yaml["level"]
+                .as_str()
+                .unwrap_or("informational")
+                .to_uppercase();
+            if configs::LEVELMAP.get(&level).unwrap_or(&1)
+                < configs::LEVELMAP.get(min_level).unwrap_or(&1)
+            {
+                return true;
+            }
+        }
+        let status = yaml["status"].as_str().unwrap_or_default();
+        if (!self.include_statuses.is_empty()
+            && !self
+                .include_statuses
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(status)))
+            || self
+                .exclude_statuses
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(status))
+        {
+            return true;
+        }
+
+        let tags: Vec<String> = yaml["tags"]
+            .as_vec()
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|tag| tag.as_str().map(|s| s.to_lowercase()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let has_tag = |filters: &[String]| {
+            filters
+                .iter()
+                .any(|filter| tags.iter().any(|tag| is_match_tag(tag, filter)))
+        };
+        if (!self.include_tags.is_empty() && !has_tag(&self.include_tags))
+            || has_tag(&self.exclude_tags)
+        {
+            return true;
+        }
+
+        let id = yaml["id"].as_str().unwrap_or_default();
+        if matches!(&self.include_rule_ids, Some(ids) if !ids.contains(id))
+            || self.exclude_rule_ids.contains(id)
+        {
+            return true;
+        }
+
+        let author = yaml["author"].as_str().unwrap_or_default().to_lowercase();
+        let has_author = |filters: &[String]| {
+            filters
+                .iter()
+                .any(|filter| author.contains(&filter.to_lowercase()))
+        };
+        if (!self.include_authors.is_empty() && !has_author(&self.include_authors))
+            || has_author(&self.exclude_authors)
+        {
+            return true;
+        }
+
+        (!self.include_logsources.is_empty()
+            && !is_match_logsource(&yaml["logsource"], &self.include_logsources))
+            || (!self.exclude_logsources.is_empty()
+                && is_match_logsource(&yaml["logsource"], &self.exclude_logsources))
+    }
+}
+
+/// ルールのタグが指定されたタグに一致するかを判定する。
+/// attack.を省略した指定(t1003)、サブテクニック(attack.t1003.001)、mitre_tactics.txtの略称(CredAccess)も一致とする
+pub fn is_match_tag(tag: &str, filter: &str) -> bool {
+    let tag = tag.to_lowercase();
+    let filter = filter.to_lowercase();
+    if matches!(TAGS_CONFIG.get(&tag), Some(abbr) if abbr.to_lowercase() == filter) {
+        return true;
+    }
+    let tag = tag.strip_prefix("attack.").unwrap_or(&tag);
+    let filter = filter.strip_prefix("attack.").unwrap_or(&filter);
+    tag == filter || tag.starts_with(&format!("{}.", filter))
+}
+
+/// logsourceの条件の全ての項目で、いずれかの値が一致する場合にtrueを返す
+fn is_match_logsource(logsource: &Yaml, filters: &[(String, String)]) -> bool {
+    filters.iter().all(|(field, _)| {
+        filters
+            .iter()
+            .filter(|(f, _)| f == field)
+            .any(|(_, value)| {
+                matches!(logsource[field.as_str()].as_str(), Some(v) if v.eq_ignore_ascii_case(value))
+            })
+    })
+}
+
+/// 文字列もしくは文字列の配列で記載された値を取得する
+fn get_str_list(yaml: &Yaml) -> Vec<String> {
+    match yaml {
+        Yaml::String(s) => vec![s.to_owned()],
+        Yaml::Array(arr) => arr
+            .iter()
+            .filter_map(|v| v.as_str().map(|s| s.to_string()))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// "<項目>:<値>"形式で指定されたlogsourceの条件をパースする
+fn parse_logsource_filters(filters: Option<&Vec<String>>) -> Vec<(String, String)> {
+    filters
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|filter| match filter.split_once(':') {
+            Some((field, value)) => Some((field.trim().to_lowercase(), value.trim().to_string())),
+            None => {
+                AlertMessage::warn(&format!(
+                    "Ignored the logsource filter: {}. Please specify the filter as <field>:<value>. (ex: service:security)",
+                    filter
+                ))
+                .ok();
+                None
+            }
+        })
+        .collect()
 }
 
 pub fn exclude_ids() -> RuleExclude {
@@ -51,9 +278,64 @@ pub fn exclude_ids() -> RuleExclude {
             .display()
     ));
 
+    let args = &configs::CONFIG.read().unwrap().args;
+    exclude_ids.include_tags = args.include_tag.clone().unwrap_or_default();
+    exclude_ids.exclude_tags = args.exclude_tag.clone().unwrap_or_default();
+    // 指定されたIDファイルが読み込めない場合に全ルールが除外されたり、除外されるべきルールが実行されることを防ぐため終了する
+    if let Some(path) = &args.include_rule_ids {
+        match read_ids(&path.display().to_string()) {
+            Ok(ids) => exclude_ids.include_rule_ids = Some(ids.into_iter().collect()),
+            Err(err) => {
+                AlertMessage::alert(&err).ok();
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(path) = &args.exclude_rule_ids {
+        match read_ids(&path.display().to_string()) {
+            Ok(ids) => exclude_ids.exclude_rule_ids = ids.into_iter().collect(),
+            Err(err) => {
+                AlertMessage::alert(&err).ok();
+                process::exit(1);
+            }
+        }
+    }
+    exclude_ids.include_authors = args.include_author.clone().unwrap_or_default();
+    exclude_ids.exclude_authors = args.exclude_author.clone().unwrap_or_default();
+    exclude_ids.include_logsources = parse_logsource_filters(args.include_logsource.as_ref());
+    exclude_ids.exclude_logsources = parse_logsource_filters(args.exclude_logsource.as_ref());
+
+    if let Some(rule_set) = &args.rule_set {
+        let rule_set_path = utils::get_rules_config_path("rule_sets.yaml", "config/rule_sets.yaml");
+        if let Err(err) = exclude_ids.load_rule_set(&rule_set_path.display().to_string(), rule_set)
+        {
+            AlertMessage::alert(&err).ok();
+            process::exit(1);
+        }
+    }
+
     exclude_ids
 }
 
+/// ルールIDを1行ずつ記載したファイルからIDを読み込む。#以降はコメントとして扱う
+fn read_ids(filename: &str) -> Result<Vec<String>, String> {
+    let f = File::open(filename)
+        .map_err(|e| format!("Failed to read the rule ID file. ({}) {}", filename, e))?;
+    Ok(BufReader::new(f)
+        .lines()
+        .map_while(Result::ok)
+        .map(|line| {
+            line.split('#')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        })
+        // 空行は無視する。IDの検証
+        .filter(|id| !id.is_empty() && configs::IDS_REGEX.is_match(id))
+        .collect())
+}
+
 impl RuleExclude {
     fn insert_ids(&mut self, filename: &str) {
         let f = File::open(filename);
@@ -82,3 +364,140 @@ impl RuleExclude {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use yaml_rust::YamlLoader;
+
+    const RULE: &str = r#"
+title: Mimikatz
+id: 0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e
+author: Yamato Security
+tags:
+    - attack.credential_access
+    - attack.t1003.001
+logsource:
+    product: windows
+    service: sysmon
+    category: process_creation
+"#;
+
+    #[test]
+    fn test_is_filtered() {
+        let rule = YamlLoader::load_from_str(RULE).unwrap()[0].clone();
+        let filtered = |set: &dyn Fn(&mut RuleExclude)| {
+            let mut exclude = RuleExclude::default();
+            set(&mut exclude);
+            exclude.is_filtered(&rule)
+        };
+        assert!(!filtered(&|_| {}));
+        assert!(!filtered(&|e| e.include_tags = vec!["T1003".to_string()]));
+        assert!(filtered(
+            &|e| e.include_tags = vec!["attack.discovery".to_string()]
+        ));
+        assert!(filtered(
+            &|e| e.exclude_tags = vec!["attack.credential_access".to_string()]
+        ));
+        assert!(filtered(&|e| e.include_rule_ids = Some(HashSet::new())));
+        assert!(filtered(&|e| {
+            e.exclude_rule_ids = HashSet::from(["0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e".to_string()])
+        }));
+        assert!(!filtered(
+            &|e| e.include_authors = vec!["yamato".to_string()]
+        ));
+        assert!(filtered(&|e| e.exclude_authors = vec!["yamato".to_string()]));
+        assert!(!filtered(&|e| {
+            e.include_logsources = vec![
+                ("service".to_string(), "security".to_string()),
+                ("service".to_string(), "Sysmon".to_string()),
+                ("category".to_string(), "process_creation".to_string()),
+            ]
+        }));
+        assert!(filtered(&|e| {
+            e.include_logsources = vec![
+                ("service".to_string(), "sysmon".to_string()),
+                ("category".to_string(), "network_connection".to_string()),
+            ]
+        }));
+        assert!(filtered(&|e| {
+            e.exclude_logsources = vec![("service".to_string(), "sysmon".to_string())]
+        }));
+    }
+
+    #[test]
+    fn test_load_rule_set() {
+        let mut exclude = RuleExclude::default();
+        exclude.exclude_tags = vec!["attack.discovery".to_string()];
+        exclude
+            .load_rule_set("test_files/config/rule_sets.yaml", "core")
+            .unwrap();
+        assert_eq!(exclude.min_level, Some("HIGH".to_string()));
+        assert_eq!(exclude.exclude_statuses, vec!["experimental"]);
+        let includes = exclude.rule_set_includes.as_ref().unwrap();
+        assert_eq!(includes.include_tags, vec!["attack.credential_access"]);
+        // コマンドラインで指定した条件も残る
+        assert_eq!(exclude.exclude_tags, vec!["attack.discovery"]);
+        assert!(exclude
+            .exclude_rule_ids
+            .contains("0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e"));
+        assert_eq!(
+            includes.include_logsources,
+            vec![("service".to_string(), "security".to_string())]
+        );
+
+        let is_filtered =
+            |rule: &str| exclude.is_filtered(&YamlLoader::load_from_str(rule).unwrap()[0]);
+        let rule = "level: high\nstatus: test\ntags: [attack.credential_access]\nlogsource: {service: security}";
+        assert!(!is_filtered(rule));
+        assert!(is_filtered(&rule.replace("high", "medium")));
+        assert!(is_filtered(&rule.replace("test", "experimental")));
+
+        // コマンドラインの読み込み対象のタグとルールセットのタグは、両方に一致するルールのみ読み込む
+        let mut exclude = RuleExclude::default();
+        exclude.include_tags = vec!["attack.t1003".to_string()];
+        exclude
+            .load_rule_set("test_files/config/rule_sets.yaml", "core")
+            .unwrap();
+        let is_filtered =
+            |rule: &str| exclude.is_filtered(&YamlLoader::load_from_str(rule).unwrap()[0]);
+        let rule = "level: high\ntags: [attack.credential_access, attack.t1003.001]\nlogsource: {service: security}";
+        assert!(!is_filtered(rule));
+        assert!(is_filtered(&rule.replace(", attack.t1003.001", "")));
+        assert!(is_filtered(
+            &rule.replace("attack.credential_access, ", "attack.discovery, ")
+        ));
+
+        let err = RuleExclude::default()
+            .load_rule_set("test_files/config/rule_sets.yaml", "unknown")
+            .unwrap_err();
+        assert!(err.ends_with("Available rule sets: core"));
+    }
+
+    #[test]
+    fn test_read_ids() {
+        let path = std::env::temp_dir().join("hayabusa_test_read_ids.txt");
+        fs::write(
+            &path,
+            "# comment\n0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e # mimikatz\n\ninvalid\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_ids(&path.display().to_string()).unwrap(),
+            vec!["0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e"]
+        );
+        fs::remove_file(&path).unwrap();
+        // 存在しないファイルは空のリストではなくエラーとする
+        assert!(read_ids(&path.display().to_string()).is_err());
+    }
+
+    #[test]
+    fn test_is_match_tag() {
+        assert!(is_match_tag("attack.t1003.001", "t1003"));
+        assert!(is_match_tag("attack.t1003.001", "attack.t1003.001"));
+        assert!(!is_match_tag("attack.t1003", "t1003.001"));
+        assert!(!is_match_tag("attack.t10030", "t1003"));
+        assert!(is_match_tag("attack.credential_access", "CredAccess"));
+    }
+}
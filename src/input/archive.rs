@@ -0,0 +1,194 @@
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// アーカイブの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveType {
+    Zip,
+    Tar,
+    TarGz,
+    /// tarではないgzipで圧縮された1つのファイル
+    Gz,
+}
+
+impl ArchiveType {
+    /// ファイル名の拡張子からアーカイブの種類を判定する
+    pub fn from_path(path: &Path) -> Option<ArchiveType> {
+        let filename = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        if filename.ends_with(".zip") {
+            Some(ArchiveType::Zip)
+        } else if filename.ends_with(".tar") {
+            Some(ArchiveType::Tar)
+        } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+            Some(ArchiveType::TarGz)
+        } else if filename.ends_with(".gz") {
+            Some(ArchiveType::Gz)
+        } else {
+            None
+        }
+    }
+}
+
+/// アーカイブのファイルかを判定する
+pub fn is_archive(path: &Path) -> bool {
+    path.is_file() && ArchiveType::from_path(path).is_some()
+}
+
+/// アーカイブに含まれるファイルのうち、is_targetがtrueを返すファイルのパスと内容を順にfに渡す。
+/// アーカイブ全体は展開せず、対象のファイルのみを1つずつメモリ上に読み込む
+pub fn for_each_archive_file<P, F>(path: &Path, is_target: P, mut f: F) -> Result<(), String>
+where
+    P: Fn(&str) -> bool,
+    F: FnMut(String, Vec<u8>),
+{
+    let archive_type = ArchiveType::from_path(path)
+        .ok_or_else(|| format!("Unsupported archive format. ({})", path.display()))?;
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    match archive_type {
+        ArchiveType::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i).map_err(|e| e.to_string())?;
+                if !entry.is_file() || !is_target(entry.name()) {
+                    continue;
+                }
+                let name = entry.name().to_string();
+                let mut content = vec![];
+                entry
+                    .read_to_end(&mut content)
+                    .map_err(|e| format!("{}: {}", name, e))?;
+                f(name, content);
+            }
+        }
+        ArchiveType::Tar => {
+            for_each_tar_file(tar::Archive::new(BufReader::new(file)), is_target, f)?;
+        }
+        ArchiveType::TarGz => {
+            for_each_tar_file(
+                tar::Archive::new(GzDecoder::new(BufReader::new(file))),
+                is_target,
+                f,
+            )?;
+        }
+        ArchiveType::Gz => {
+            // Security.evtx.gzのように、拡張子の.gzを除いたファイル名とする
+            let name = path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            if is_target(&name) {
+                let mut content = vec![];
+                GzDecoder::new(BufReader::new(file))
+                    .read_to_end(&mut content)
+                    .map_err(|e| format!("{}: {}", name, e))?;
+                f(name, content);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn for_each_tar_file<R, P, F>(
+    mut archive: tar::Archive<R>,
+    is_target: P,
+    mut f: F,
+) -> Result<(), String>
+where
+    R: Read,
+    P: Fn(&str) -> bool,
+    F: FnMut(String, Vec<u8>),
+{
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map_err(|e| e.to_string())?
+            .to_string_lossy()
+            .to_string();
+        // 対象ではないファイルの内容は読み込まずに次のエントリに進む
+        if !is_target(&name) {
+            continue;
+        }
+        let mut content = vec![];
+        entry
+            .read_to_end(&mut content)
+            .map_err(|e| format!("{}: {}", name, e))?;
+        f(name, content);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_type() {
+        assert_eq!(
+            ArchiveType::from_path(Path::new("collection.ZIP")),
+            Some(ArchiveType::Zip)
+        );
+        assert_eq!(
+            ArchiveType::from_path(Path::new("collection.tar")),
+            Some(ArchiveType::Tar)
+        );
+        assert_eq!(
+            ArchiveType::from_path(Path::new("collection.tar.gz")),
+            Some(ArchiveType::TarGz)
+        );
+        assert_eq!(
+            ArchiveType::from_path(Path::new("collection.tgz")),
+            Some(ArchiveType::TarGz)
+        );
+        assert_eq!(
+            ArchiveType::from_path(Path::new("Security.evtx.gz")),
+            Some(ArchiveType::Gz)
+        );
+        assert_eq!(ArchiveType::from_path(Path::new("Security.evtx")), None);
+        assert!(!is_archive(Path::new("test_files/archive/not_found.zip")));
+    }
+
+    #[test]
+    fn test_for_each_archive_file() {
+        for path in [
+            "test_files/archive/collection.zip",
+            "test_files/archive/collection.tar.gz",
+            "test_files/archive/Security.evtx.gz",
+        ] {
+            let mut files = vec![];
+            for_each_archive_file(
+                Path::new(path),
+                |name| name.ends_with(".evtx"),
+                |name, content| files.push((name, content)),
+            )
+            .unwrap();
+            assert_eq!(files.len(), 1, "{}", path);
+            assert!(files[0].0.ends_with("Security.evtx"), "{}", path);
+            assert_eq!(files[0].1, b"ElfFile\0".to_vec(), "{}", path);
+        }
+        let mut files = vec![];
+        for_each_archive_file(
+            Path::new("test_files/archive/collection.zip"),
+            |_| true,
+            |name, _| files.push(name),
+        )
+        .unwrap();
+        assert_eq!(
+            files,
+            vec![
+                "C/Windows/System32/winevt/Logs/Security.evtx",
+                "Collection.log"
+            ]
+        );
+    }
+}
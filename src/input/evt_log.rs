@@ -0,0 +1,414 @@
+use crate::input::xml_log::to_json_value;
+use chrono::{TimeZone, Utc};
+use serde_json::{json, Map, Value};
+use std::io::Read;
+use std::path::Path;
+
+/// Windows XP/2003の.evt形式のイベントログの拡張子
+pub const EVT_LOG_EXTENSIONS: [&str; 1] = ["evt"];
+
+/// ファイルヘッダー(ELF_LOGFILE_HEADER)とレコード(EVENTLOGRECORD)のシグネチャ
+const SIGNATURE: &[u8; 4] = b"LfLe";
+/// ファイルヘッダーのサイズ
+const HEADER_SIZE: usize = 0x30;
+/// レコードの固定長部分のサイズ。この後にソース名、コンピューター名、SID、文字列、データが続く
+const RECORD_FIXED_SIZE: usize = 0x38;
+/// 循環バッファの末尾で次のレコードが入りきらない場合に埋められる値
+const PADDING: u32 = 0x27;
+
+/// .evtにはチャンネル名が記録されていないため、ファイル名からチャンネル名を決める
+const CHANNEL_NAMES: [(&str, &str); 6] = [
+    ("appevent", "Application"),
+    ("secevent", "Security"),
+    ("sysevent", "System"),
+    ("dnsevent", "DNS Server"),
+    ("ntds", "Directory Service"),
+    ("ntfrs", "File Replication Service"),
+];
+
+/// ログオン成功(528, 540)のイベントの挿入文字列の名前。logon-summaryやルールで使えるように.evtxの4624と同じ名前とする
+const LOGON_SUCCESS_FIELDS: [&str; 15] = [
+    "TargetUserName",
+    "TargetDomainName",
+    "TargetLogonId",
+    "LogonType",
+    "LogonProcessName",
+    "AuthenticationPackageName",
+    "WorkstationName",
+    "LogonGuid",
+    "SubjectUserName",
+    "SubjectDomainName",
+    "SubjectLogonId",
+    "ProcessId",
+    "TransmittedServices",
+    "IpAddress",
+    "IpPort",
+];
+
+/// ログオン失敗(529-537, 539)のイベントの挿入文字列の名前。.evtxの4625と同じ名前とする
+const LOGON_FAILURE_FIELDS: [&str; 13] = [
+    "TargetUserName",
+    "TargetDomainName",
+    "LogonType",
+    "LogonProcessName",
+    "AuthenticationPackageName",
+    "WorkstationName",
+    "SubjectUserName",
+    "SubjectDomainName",
+    "SubjectLogonId",
+    "ProcessId",
+    "TransmittedServices",
+    "IpAddress",
+    "IpPort",
+];
+
+/// ログオン成功のイベントID
+pub const LOGON_SUCCESS_EVENT_IDS: [u32; 2] = [528, 540];
+/// ログオン失敗のイベントID
+pub const LOGON_FAILURE_EVENT_IDS: [u32; 10] = [529, 530, 531, 532, 533, 534, 535, 536, 537, 539];
+
+/// .evt形式のファイルかを拡張子から判定する
+pub fn is_evt_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| EVT_LOG_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// ファイル名からチャンネル名を取得する。既定のファイル名以外の場合は拡張子を除いたファイル名とする
+pub fn get_channel_name(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    CHANNEL_NAMES
+        .iter()
+        .find(|(file_name, _)| stem.eq_ignore_ascii_case(file_name))
+        .map(|(_, channel)| channel.to_string())
+        .unwrap_or(stem)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    bytes
+        .get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// NULL終端のUTF-16LEの文字列を読み込み、文字列と次の文字列の開始位置を返す
+fn read_utf16z(bytes: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut units = vec![];
+    let mut pos = offset;
+    loop {
+        let unit = read_u16(bytes, pos)?;
+        pos += 2;
+        if unit == 0 {
+            break;
+        }
+        units.push(unit);
+    }
+    Some((String::from_utf16_lossy(&units), pos))
+}
+
+/// バイナリ形式のSIDをS-1-5-18のような文字列に変換する
+fn sid_to_string(sid: &[u8]) -> Option<String> {
+    let revision = *sid.first()?;
+    let sub_authority_cnt = *sid.get(1)? as usize;
+    let authority = sid
+        .get(2..8)?
+        .iter()
+        .fold(0u64, |acc, b| (acc << 8) | *b as u64);
+    let mut ret = format!("S-{}-{}", revision, authority);
+    for i in 0..sub_authority_cnt {
+        ret.push_str(&format!("-{}", read_u32(sid, 8 + i * 4)?));
+    }
+    Some(ret)
+}
+
+/// .evtxのレコードと同様に、EventTypeからLevelとKeywordsを決める
+fn get_level_and_keywords(event_type: u16) -> (u64, &'static str) {
+    match event_type {
+        // EVENTLOG_ERROR_TYPE
+        0x1 => (2, "0x80000000000000"),
+        // EVENTLOG_WARNING_TYPE
+        0x2 => (3, "0x80000000000000"),
+        // EVENTLOG_AUDIT_SUCCESS
+        0x8 => (0, "0x8020000000000000"),
+        // EVENTLOG_AUDIT_FAILURE
+        0x10 => (0, "0x8010000000000000"),
+        _ => (4, "0x80000000000000"),
+    }
+}
+
+/// 挿入文字列をEventDataに変換する。ログオンのイベントは.evtxと同じ名前を付け、それ以外は名前の無い<Data>と同様に扱う
+fn to_event_data(provider: &str, event_id: u32, strings: Vec<String>) -> Map<String, Value> {
+    let to_value = |s: String| {
+        if s.is_empty() {
+            Value::Null
+        } else {
+            to_json_value(s)
+        }
+    };
+    let field_names: &[&str] = if provider != "Security" {
+        &[]
+    } else if LOGON_SUCCESS_EVENT_IDS.contains(&event_id) {
+        &LOGON_SUCCESS_FIELDS
+    } else if LOGON_FAILURE_EVENT_IDS.contains(&event_id) {
+        &LOGON_FAILURE_FIELDS
+    } else {
+        &[]
+    };
+    let mut event_data = Map::new();
+    if !field_names.is_empty() && strings.len() <= field_names.len() {
+        for (name, s) in field_names.iter().zip(strings) {
+            event_data.insert(name.to_string(), to_value(s));
+        }
+    } else if strings.len() == 1 {
+        event_data.insert("Data".to_string(), to_value(strings[0].clone()));
+    } else if !strings.is_empty() {
+        event_data.insert(
+            "Data".to_string(),
+            Value::Array(strings.into_iter().map(to_value).collect()),
+        );
+    }
+    event_data
+}
+
+/// 1つのレコード(EVENTLOGRECORD)をEvtxParserと同じ形式のJSONに変換する
+fn parse_record(record: &[u8], channel: &str) -> Result<Value, String> {
+    let invalid = || "The record is corrupted.".to_string();
+    let field = |offset: usize| read_u32(record, offset).ok_or_else(invalid);
+    let record_number = field(0x08)?;
+    let time_generated = field(0x0C)?;
+    let event_id = field(0x14)?;
+    let event_type = read_u16(record, 0x18).ok_or_else(invalid)?;
+    let string_cnt = read_u16(record, 0x1A).ok_or_else(invalid)? as usize;
+    let category = read_u16(record, 0x1C).ok_or_else(invalid)?;
+    let string_offset = field(0x24)? as usize;
+    let sid_length = field(0x28)? as usize;
+    let sid_offset = field(0x2C)? as usize;
+    let data_length = field(0x30)? as usize;
+    let data_offset = field(0x34)? as usize;
+
+    let (provider, pos) = read_utf16z(record, RECORD_FIXED_SIZE).ok_or_else(invalid)?;
+    let (computer, _) = read_utf16z(record, pos).ok_or_else(invalid)?;
+    let mut strings = vec![];
+    let mut pos = string_offset;
+    for _ in 0..string_cnt {
+        let (s, next) = read_utf16z(record, pos).ok_or_else(invalid)?;
+        strings.push(s);
+        pos = next;
+    }
+    let timestamp = Utc
+        .timestamp_opt(time_generated as i64, 0)
+        .single()
+        .ok_or_else(invalid)?;
+
+    // EventIDの上位16bitはQualifiersとして扱う
+    let code = event_id & 0xFFFF;
+    let qualifiers = event_id >> 16;
+    let (level, keywords) = get_level_and_keywords(event_type);
+    let mut system = json!({
+        "Provider_attributes": {"Name": provider},
+        "EventID": code,
+        "Level": level,
+        "Task": category,
+        "Keywords": keywords,
+        "TimeCreated_attributes": {
+            "SystemTime": timestamp.format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string()
+        },
+        "EventRecordID": record_number,
+        "Channel": channel,
+        "Computer": computer,
+    });
+    if qualifiers != 0 {
+        system["EventID_attributes"] = json!({ "Qualifiers": qualifiers });
+    }
+    if sid_length > 0 {
+        let sid = record
+            .get(sid_offset..sid_offset + sid_length)
+            .and_then(sid_to_string)
+            .ok_or_else(invalid)?;
+        system["Security_attributes"] = json!({ "UserID": sid });
+    }
+
+    let mut event_data = to_event_data(&provider, code, strings);
+    if data_length > 0 {
+        let data = record
+            .get(data_offset..data_offset + data_length)
+            .ok_or_else(invalid)?;
+        let binary: String = data.iter().map(|b| format!("{:02X}", b)).collect();
+        event_data.insert("Binary".to_string(), Value::String(binary));
+    }
+    let mut event = json!({ "System": system });
+    if !event_data.is_empty() {
+        event["EventData"] = Value::Object(event_data);
+    }
+    Ok(json!({ "Event": event }))
+}
+
+/// .evt形式のイベントログのレコードを古い順に読み込み、EvtxParserと同じ形式のJSONに変換するイテレータ
+pub struct EvtLogReader {
+    /// ヘッダーの最も古いレコードの位置から循環バッファを1周分並べたデータ
+    data: Vec<u8>,
+    pos: usize,
+    channel: String,
+}
+
+impl Iterator for EvtLogReader {
+    type Item = Result<Value, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let length = read_u32(&self.data, self.pos)? as usize;
+            if length as u32 == PADDING {
+                self.pos += 4;
+                continue;
+            }
+            // 終端のレコード(ELF_EOF_RECORD)や未使用の領域に到達した場合は終了する
+            if self.data.get(self.pos + 4..self.pos + 8)? != SIGNATURE || length < RECORD_FIXED_SIZE
+            {
+                return None;
+            }
+            let record = self.data.get(self.pos..self.pos + length);
+            let record = match record {
+                Some(record) if read_u32(record, length - 4) == Some(length as u32) => record,
+                _ => {
+                    self.pos = self.data.len();
+                    return Some(Err("The record is truncated.".to_string()));
+                }
+            };
+            self.pos += length;
+            return Some(parse_record(record, &self.channel));
+        }
+    }
+}
+
+/// Windows XP/2003の.evt形式のイベントログを読み込み、レコードを順に返す。pathはチャンネル名の判定に使用する
+pub fn read_evt_log<R: Read>(mut reader: R, path: &Path) -> Result<EvtLogReader, String> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    if bytes.len() < HEADER_SIZE || &bytes[4..8] != SIGNATURE {
+        return Err("The file is not a valid evt file.".to_string());
+    }
+    // レコードは循環バッファに書き込まれるため、最も古いレコードの位置からファイルの末尾までと、ヘッダーの直後から最も古いレコードの位置までを連結する
+    let start = read_u32(&bytes, 0x10).unwrap_or_default() as usize;
+    let start = if (HEADER_SIZE..bytes.len()).contains(&start) {
+        start
+    } else {
+        HEADER_SIZE
+    };
+    let mut data = bytes[start..].to_vec();
+    data.extend_from_slice(&bytes[HEADER_SIZE..start]);
+    Ok(EvtLogReader {
+        data,
+        pos: 0,
+        channel: get_channel_name(path),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn read_records(path: &str) -> Vec<Value> {
+        read_evt_log(File::open(path).unwrap(), Path::new(path))
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_read_evt_log() {
+        let records = read_records("test_files/evt/SecEvent.Evt");
+        assert_eq!(records.len(), 3);
+        let system = &records[0]["Event"]["System"];
+        assert_eq!(system["EventID"], 528);
+        assert_eq!(system["EventRecordID"], 1);
+        assert_eq!(system["Channel"], "Security");
+        assert_eq!(system["Computer"], "XPHOST");
+        assert_eq!(system["Provider_attributes"]["Name"], "Security");
+        assert_eq!(
+            system["TimeCreated_attributes"]["SystemTime"],
+            "2004-08-01T10:00:00.000000Z"
+        );
+        assert_eq!(system["Level"], 0);
+        assert_eq!(system["Keywords"], "0x8020000000000000");
+        assert_eq!(system["Security_attributes"]["UserID"], "S-1-5-18");
+        let event_data = &records[0]["Event"]["EventData"];
+        assert_eq!(event_data["TargetUserName"], "admin");
+        assert_eq!(event_data["LogonType"], 2);
+        assert_eq!(event_data["IpAddress"], "127.0.0.1");
+
+        // ログオン失敗のイベントは4625と同じ名前とする
+        assert_eq!(records[1]["Event"]["System"]["EventID"], 529);
+        assert_eq!(
+            records[1]["Event"]["System"]["Keywords"],
+            "0x8010000000000000"
+        );
+        assert!(records[1]["Event"]["System"]["Security_attributes"].is_null());
+        assert_eq!(
+            records[1]["Event"]["EventData"]["WorkstationName"],
+            "ATTACKER"
+        );
+        assert_eq!(
+            records[1]["Event"]["EventData"]["IpAddress"],
+            "192.168.1.10"
+        );
+
+        // それ以外のイベントは名前の無い<Data>と同様に配列とする
+        let event_data = &records[2]["Event"]["EventData"];
+        assert_eq!(event_data["Data"][0], "%%1797");
+        assert_eq!(event_data["Data"][4], "Administrator");
+        assert!(event_data["Data"][6].is_null());
+        assert_eq!(event_data["Binary"], "01AB");
+    }
+
+    #[test]
+    fn test_read_wrapped_evt_log() {
+        // 循環バッファの末尾をまたいだレコードも読み込める
+        let records = read_records("test_files/evt/AppEvent.Evt");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["Event"]["System"]["EventRecordID"], 5);
+        assert_eq!(records[0]["Event"]["System"]["Channel"], "Application");
+        assert_eq!(records[0]["Event"]["System"]["Level"], 2);
+        assert_eq!(records[0]["Event"]["EventData"]["Data"][0], "app.exe");
+        assert_eq!(records[1]["Event"]["System"]["EventID"], 1002);
+        assert_eq!(
+            records[1]["Event"]["System"]["EventID_attributes"]["Qualifiers"],
+            16384
+        );
+        assert_eq!(records[1]["Event"]["EventData"]["Data"], "app.exe");
+    }
+
+    #[test]
+    fn test_read_invalid_evt_log() {
+        assert!(read_evt_log(&b"ElfFile\0"[..], Path::new("Security.evt")).is_err());
+        let mut bytes = std::fs::read("test_files/evt/SecEvent.Evt").unwrap();
+        bytes.truncate(0x100);
+        let records: Vec<Result<Value, String>> =
+            read_evt_log(bytes.as_slice(), Path::new("SecEvent.Evt"))
+                .unwrap()
+                .collect();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_err());
+    }
+
+    #[test]
+    fn test_get_channel_name() {
+        assert_eq!(get_channel_name(Path::new("SysEvent.Evt")), "System");
+        assert_eq!(
+            get_channel_name(Path::new("Internet Explorer.evt")),
+            "Internet Explorer"
+        );
+        assert!(is_evt_file(Path::new("SecEvent.Evt")));
+        assert!(!is_evt_file(Path::new("Security.evtx")));
+    }
+}
@@ -0,0 +1,135 @@
+use chrono::{DateTime, TimeZone, Utc};
+
+/// evtxファイルのチャンクのサイズ
+pub const EVTX_CHUNK_SIZE: usize = 65536;
+/// チャンク内で最初のレコードが格納されている位置
+const EVTX_CHUNK_RECORDS_OFFSET: usize = 512;
+/// レコードヘッダー(signature, size, record id, timestamp)のサイズ
+const EVTX_RECORD_HEADER_SIZE: usize = 24;
+const EVTX_CHUNK_SIGNATURE: &[u8] = b"ElfChnk\0";
+const EVTX_RECORD_SIGNATURE: &[u8] = &[0x2a, 0x2a, 0x00, 0x00];
+/// 1601/01/01から1970/01/01までの秒数
+const FILETIME_UNIX_EPOCH_DIFF_SECS: i64 = 11_644_473_600;
+
+/// チャンク内のレコードのタイムスタンプの範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvtxTimeRange {
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    pub record_cnt: usize,
+}
+
+impl EvtxTimeRange {
+    fn merge(self, other: EvtxTimeRange) -> EvtxTimeRange {
+        EvtxTimeRange {
+            first: self.first.min(other.first),
+            last: self.last.max(other.last),
+            record_cnt: self.record_cnt + other.record_cnt,
+        }
+    }
+}
+
+/// FILETIME(1601/01/01からの100ナノ秒単位の経過時間)をDateTimeに変換する
+pub fn filetime_to_datetime(filetime: u64) -> Option<DateTime<Utc>> {
+    let secs = (filetime / 10_000_000) as i64 - FILETIME_UNIX_EPOCH_DIFF_SECS;
+    let nsecs = ((filetime % 10_000_000) * 100) as u32;
+    Utc.timestamp_opt(secs, nsecs).single()
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// チャンクのデータ(ヘッダーを含む64KB)から各レコードヘッダーのタイムスタンプを読み取り、範囲を返す。
+/// BinXMLのパースは行わないため、レコードをパースするよりも高速に判定できる。
+pub fn get_chunk_time_range(chunk: &[u8]) -> Option<EvtxTimeRange> {
+    if !chunk.starts_with(EVTX_CHUNK_SIGNATURE) {
+        return None;
+    }
+    // ヘッダーの空き領域の開始位置より後ろにはレコードは存在しない
+    let free_space_offset = read_u32(chunk, 48)
+        .map(|offset| (offset as usize).min(chunk.len()))
+        .unwrap_or(chunk.len());
+
+    let mut ret: Option<EvtxTimeRange> = None;
+    let mut offset = EVTX_CHUNK_RECORDS_OFFSET;
+    while offset + EVTX_RECORD_HEADER_SIZE <= free_space_offset {
+        if &chunk[offset..offset + 4] != EVTX_RECORD_SIGNATURE {
+            break;
+        }
+        let size = read_u32(chunk, offset + 4).unwrap_or_default() as usize;
+        if size < EVTX_RECORD_HEADER_SIZE || offset + size > chunk.len() {
+            break;
+        }
+        if let Some(timestamp) = read_u64(chunk, offset + 16).and_then(filetime_to_datetime) {
+            let range = EvtxTimeRange {
+                first: timestamp,
+                last: timestamp,
+                record_cnt: 1,
+            };
+            ret = Some(match ret {
+                Some(r) => r.merge(range),
+                None => range,
+            });
+        }
+        offset += size;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime_to_filetime(dt: DateTime<Utc>) -> u64 {
+        ((dt.timestamp() + FILETIME_UNIX_EPOCH_DIFF_SECS) as u64) * 10_000_000
+            + (dt.timestamp_subsec_nanos() / 100) as u64
+    }
+
+    /// 指定したタイムスタンプのレコードヘッダーを持つチャンクを作成する
+    fn create_chunk(timestamps: &[DateTime<Utc>]) -> Vec<u8> {
+        let mut chunk = vec![0_u8; EVTX_CHUNK_SIZE];
+        chunk[..8].copy_from_slice(EVTX_CHUNK_SIGNATURE);
+        let mut offset = EVTX_CHUNK_RECORDS_OFFSET;
+        for (i, timestamp) in timestamps.iter().enumerate() {
+            let size = 64_usize;
+            chunk[offset..offset + 4].copy_from_slice(EVTX_RECORD_SIGNATURE);
+            chunk[offset + 4..offset + 8].copy_from_slice(&(size as u32).to_le_bytes());
+            chunk[offset + 8..offset + 16].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+            chunk[offset + 16..offset + 24]
+                .copy_from_slice(&datetime_to_filetime(*timestamp).to_le_bytes());
+            offset += size;
+        }
+        chunk[48..52].copy_from_slice(&(offset as u32).to_le_bytes());
+        chunk
+    }
+
+    #[test]
+    fn test_filetime_to_datetime() {
+        // 2022-02-22 22:00:00.1234567 UTC
+        let filetime = 132_900_408_001_234_567_u64;
+        let expect = Utc.ymd(2022, 2, 22).and_hms_nano(22, 0, 0, 123_456_700);
+        assert_eq!(filetime_to_datetime(filetime), Some(expect));
+        assert_eq!(datetime_to_filetime(expect), filetime);
+    }
+
+    #[test]
+    fn test_get_chunk_time_range() {
+        let t1 = Utc.ymd(2022, 2, 22).and_hms(10, 0, 0);
+        let t2 = Utc.ymd(2022, 2, 21).and_hms(10, 0, 0);
+        let t3 = Utc.ymd(2022, 2, 23).and_hms(10, 0, 0);
+        let range = get_chunk_time_range(&create_chunk(&[t1, t2, t3])).unwrap();
+        assert_eq!(range.first, t2);
+        assert_eq!(range.last, t3);
+        assert_eq!(range.record_cnt, 3);
+
+        assert_eq!(get_chunk_time_range(&create_chunk(&[])), None);
+        assert_eq!(get_chunk_time_range(&[0_u8; EVTX_CHUNK_SIZE]), None);
+    }
+}
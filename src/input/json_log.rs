@@ -0,0 +1,427 @@
+use crate::detections::configs::{self, CURRENT_EXE_PATH};
+use crate::detections::utils;
+use serde_json::{Map, Value};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use yaml_rust::Yaml;
+
+/// --json-inputで読み込むファイルの拡張子
+pub const JSON_LOG_EXTENSIONS: [&str; 2] = ["json", "jsonl"];
+
+/// JSONのオブジェクトの配列からフィールド名として扱うキー
+const DATA_NAME_KEYS: [&str; 2] = ["@Name", "Name"];
+/// JSONのオブジェクトの配列からフィールドの値として扱うキー
+const DATA_VALUE_KEYS: [&str; 3] = ["#text", "Value", "text"];
+/// エラーメッセージに表示するファイルの種類
+const JSON_MAPPING_FILE_KIND: &str = "JSON mapping";
+
+/// json_mapping.yamlの1つの定義
+#[derive(Debug, Clone)]
+struct JsonLogFormat {
+    name: String,
+    /// ドキュメントがこの定義の形式であると判定するために、全て存在する必要があるフィールド
+    detect: Vec<String>,
+    /// Event配下のオブジェクト名と、中身をコピーする元のフィールド
+    copy: Vec<(String, String)>,
+    /// Event配下のフィールド名と、値を取得する元のフィールド
+    fields: Vec<(String, String)>,
+}
+
+/// --json-inputでJSON/JSONL形式のログの各ドキュメントを.evtxのレコードと同じEvent.System/Event.EventDataの形式に変換する設定
+#[derive(Debug, Default)]
+pub struct JsonLogMapping {
+    formats: Vec<JsonLogFormat>,
+}
+
+impl JsonLogMapping {
+    /// --json-mappingで指定されたファイルのパスを返す。ファイルの指定がない場合はconfig/json_mapping.yamlを返す
+    pub fn get_config_path() -> String {
+        match &configs::CONFIG.read().unwrap().args.json_mapping {
+            Some(path) => path.display().to_string(),
+            None => utils::check_setting_path(
+                &CURRENT_EXE_PATH.to_path_buf(),
+                "config/json_mapping.yaml",
+                true,
+            )
+            .unwrap()
+            .display()
+            .to_string(),
+        }
+    }
+
+    /// json_mapping.yamlを読み込む
+    pub fn load(path: &str) -> Result<JsonLogMapping, String> {
+        let content = utils::read_config_file(Path::new(path), JSON_MAPPING_FILE_KIND)?;
+        JsonLogMapping::parse(&content).map_err(|e| format!("{} ({})", e, path))
+    }
+
+    /// 定義の一覧をパースする。各定義には判定に使うフィールドと、コピーもしくは変換するフィールドが必須
+    pub fn parse(content: &str) -> Result<JsonLogMapping, String> {
+        let mut ret = JsonLogMapping::default();
+        let formats = utils::parse_yaml_list(content, JSON_MAPPING_FILE_KIND)?;
+        for (i, format) in formats.iter().enumerate() {
+            let name = format["name"]
+                .as_str()
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| format!("#{}", i + 1));
+            let detect: Vec<String> = match &format["detect"] {
+                Yaml::String(s) => vec![s.to_string()],
+                Yaml::Array(fields) => fields
+                    .iter()
+                    .filter_map(|f| f.as_str().map(|s| s.to_string()))
+                    .collect(),
+                _ => vec![],
+            };
+            if detect.is_empty() {
+                return Err(format!(
+                    "The mapping {} does not have the fields to detect the format.",
+                    name
+                ));
+            }
+            let copy = JsonLogMapping::parse_pairs(&format["copy"], &name, "copy")?;
+            let fields = JsonLogMapping::parse_pairs(&format["fields"], &name, "fields")?;
+            if copy.is_empty() && fields.is_empty() {
+                return Err(format!(
+                    "The mapping {} does not have the fields to convert.",
+                    name
+                ));
+            }
+            ret.formats.push(JsonLogFormat {
+                name,
+                detect,
+                copy,
+                fields,
+            });
+        }
+        Ok(ret)
+    }
+
+    fn parse_pairs(yaml: &Yaml, name: &str, key: &str) -> Result<Vec<(String, String)>, String> {
+        match yaml {
+            Yaml::BadValue => Ok(vec![]),
+            Yaml::Hash(pairs) => pairs
+                .iter()
+                .map(
+                    |(target, source)| match (target.as_str(), source.as_str()) {
+                        (Some(target), Some(source)) => {
+                            Ok((target.to_string(), source.to_string()))
+                        }
+                        _ => Err(format!(
+                            "The {} of the mapping {} must be a mapping of field names.",
+                            key, name
+                        )),
+                    },
+                )
+                .collect(),
+            _ => Err(format!(
+                "The {} of the mapping {} must be a mapping of field names.",
+                key, name
+            )),
+        }
+    }
+
+    /// 1つのドキュメントを{"Event": {"System": {...}, "EventData": {...}}}の形式に変換する。
+    /// 上から順にdetectの全てのフィールドを持つ最初の定義を使い、一致する定義がない場合はNoneを返す
+    pub fn convert(&self, doc: &Value) -> Option<Value> {
+        let format = self.formats.iter().find(|format| {
+            format
+                .detect
+                .iter()
+                .all(|field| get_json_value(doc, field).is_some())
+        })?;
+        let mut event = Map::new();
+        for (target, source) in &format.copy {
+            if let Some(Value::Object(obj)) = get_json_value(doc, source).map(to_object) {
+                set_json_value(&mut event, target, Value::Object(obj));
+            }
+        }
+        for (target, source) in &format.fields {
+            match get_json_value(doc, source) {
+                Some(Value::Null) | None => {}
+                Some(value) => set_json_value(&mut event, target, value),
+            }
+        }
+        let mut ret = Map::new();
+        ret.insert("Event".to_string(), Value::Object(event));
+        Some(Value::Object(ret))
+    }
+
+    /// 読み込んだ定義の名前の一覧
+    pub fn format_names(&self) -> Vec<&str> {
+        self.formats.iter().map(|f| f.name.as_str()).collect()
+    }
+}
+
+/// JSON文字列の場合はパースした値を返す
+fn parse_json_string(value: &Value) -> Option<Value> {
+    match value {
+        Value::String(s) if s.trim_start().starts_with(['{', '[']) => serde_json::from_str(s).ok(),
+        _ => None,
+    }
+}
+
+/// ドット区切りのフィールド名の値を取得する。
+/// "winlog.event_id"のようにドットを含むキーと、値がJSON文字列のフィールド(EvtxECmdのPayloadなど)の中も辿る
+pub fn get_json_value(value: &Value, path: &str) -> Option<Value> {
+    if let Some(parsed) = parse_json_string(value) {
+        return get_json_value(&parsed, path);
+    }
+    let obj = value.as_object()?;
+    if let Some(v) = obj.get(path) {
+        return Some(v.clone());
+    }
+    let ret = path.match_indices('.').find_map(|(i, _)| {
+        obj.get(&path[..i])
+            .and_then(|child| get_json_value(child, &path[i + 1..]))
+    });
+    if ret.is_some() {
+        return ret;
+    }
+    // "winlog.event_data.TargetUserName"のように平坦化されたキーは、前方一致するキーをまとめたオブジェクトとする
+    let prefix = format!("{}.", path);
+    let children: Map<String, Value> = obj
+        .iter()
+        .filter_map(|(k, v)| k.strip_prefix(&prefix).map(|k| (k.to_string(), v.clone())))
+        .collect();
+    if children.is_empty() {
+        None
+    } else {
+        Some(Value::Object(children))
+    }
+}
+
+/// ドット区切りのフィールド名に値を設定する。途中のオブジェクトが無い場合は作成する
+fn set_json_value(obj: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        None => {
+            obj.insert(path.to_string(), value);
+        }
+        Some((key, rest)) => {
+            let child = obj
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+            set_json_value(child.as_object_mut().unwrap(), rest, value);
+        }
+    }
+}
+
+/// コピーする値をオブジェクトに変換する。
+/// [{"@Name": "SubjectUserName", "#text": "admin"}]のような名前と値の配列はフィールド名と値のオブジェクトに変換する
+fn to_object(value: Value) -> Value {
+    if let Some(parsed) = parse_json_string(&value) {
+        return to_object(parsed);
+    }
+    let get_name = |obj: &Map<String, Value>| {
+        DATA_NAME_KEYS.iter().find_map(|key| {
+            obj.get(*key)
+                .and_then(|v| v.as_str())
+                .map(|s| s.to_string())
+        })
+    };
+    let get_value = |obj: &Map<String, Value>| {
+        DATA_VALUE_KEYS
+            .iter()
+            .find_map(|key| obj.get(*key).cloned())
+            .unwrap_or_else(|| Value::String(String::default()))
+    };
+    match value {
+        Value::Object(obj) => match get_name(&obj) {
+            // 要素が1つの場合は配列ではなくオブジェクトになる
+            Some(name) => {
+                let mut ret = Map::new();
+                ret.insert(name, get_value(&obj));
+                Value::Object(ret)
+            }
+            None => Value::Object(obj),
+        },
+        Value::Array(items) => {
+            let mut ret = Map::new();
+            for (i, item) in items.iter().enumerate() {
+                match item
+                    .as_object()
+                    .and_then(|obj| get_name(obj).map(|n| (n, obj)))
+                {
+                    Some((name, obj)) => {
+                        ret.insert(name, get_value(obj));
+                    }
+                    // 名前の無い値はevtxと同様にDataというフィールドとする
+                    None if items.len() == 1 => {
+                        ret.insert("Data".to_string(), item.clone());
+                    }
+                    None => {
+                        ret.insert(format!("Data[{}]", i + 1), item.clone());
+                    }
+                }
+            }
+            Value::Object(ret)
+        }
+        other => other,
+    }
+}
+
+/// JSON/JSONL形式のファイルもしくはアーカイブ内のファイルの内容を読み込み、ドキュメントを順に返す。
+/// 1行に1ドキュメントのJSONL、ドキュメントの配列、複数のドキュメントを連結したファイルに対応する
+pub fn read_json_log<R: Read>(reader: R) -> impl Iterator<Item = Result<Value, String>> {
+    let docs = serde_json::Deserializer::from_reader(BufReader::new(reader)).into_iter::<Value>();
+    let mut failed = false;
+    docs.take_while(move |doc| {
+        // serde_jsonはエラーの後に次のドキュメントの先頭から読み直せないため、最初のエラーまでを返す
+        let ret = !failed;
+        failed = doc.is_err();
+        ret
+    })
+    .flat_map(|doc| -> Vec<Result<Value, String>> {
+        match doc {
+            Ok(Value::Array(docs)) => docs.into_iter().map(Ok).collect(),
+            Ok(doc) => vec![Ok(doc)],
+            Err(e) => vec![Err(e.to_string())],
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs::File;
+
+    fn load_mapping() -> JsonLogMapping {
+        JsonLogMapping::load("./config/json_mapping.yaml").unwrap()
+    }
+
+    #[test]
+    fn test_convert_winlogbeat() {
+        let doc = json!({
+            "@timestamp": "2022-10-18T01:02:03.456Z",
+            "winlog": {
+                "event_id": "4625",
+                "channel": "Security",
+                "computer_name": "WKS01",
+                "record_id": 1234,
+                "provider_name": "Microsoft-Windows-Security-Auditing",
+                "event_data": {"TargetUserName": "admin", "LogonType": "3"}
+            }
+        });
+        let event = load_mapping().convert(&doc).unwrap();
+        assert_eq!(event["Event"]["System"]["EventID"], "4625");
+        assert_eq!(event["Event"]["System"]["Channel"], "Security");
+        assert_eq!(event["Event"]["System"]["Computer"], "WKS01");
+        assert_eq!(event["Event"]["System"]["EventRecordID"], 1234);
+        assert_eq!(
+            event["Event"]["System"]["Provider_attributes"]["Name"],
+            "Microsoft-Windows-Security-Auditing"
+        );
+        assert_eq!(
+            event["Event"]["System"]["TimeCreated_attributes"]["SystemTime"],
+            "2022-10-18T01:02:03.456Z"
+        );
+        assert_eq!(event["Event"]["EventData"]["TargetUserName"], "admin");
+        // ドットを含むキーに平坦化されたドキュメントも変換できる
+        let flattened = json!({
+            "@timestamp": "2022-10-18T01:02:03.456Z",
+            "winlog.event_id": "4625",
+            "winlog.channel": "Security",
+            "winlog.event_data.TargetUserName": "admin"
+        });
+        let event = load_mapping().convert(&flattened).unwrap();
+        assert_eq!(event["Event"]["System"]["EventID"], "4625");
+        assert_eq!(event["Event"]["EventData"]["TargetUserName"], "admin");
+    }
+
+    #[test]
+    fn test_convert_evtxecmd() {
+        let doc = json!({
+            "PayloadData1": "Target: WKS01\\admin",
+            "Payload": "{\"EventData\":{\"Data\":[{\"@Name\":\"TargetUserName\",\"#text\":\"admin\"},{\"@Name\":\"LogonType\",\"#text\":\"3\"}]}}",
+            "Channel": "Security",
+            "Provider": "Microsoft-Windows-Security-Auditing",
+            "EventId": 4625,
+            "EventRecordId": "1234",
+            "Computer": "WKS01",
+            "TimeCreated": "2022-10-18T01:02:03.4567890+00:00"
+        });
+        let event = load_mapping().convert(&doc).unwrap();
+        assert_eq!(event["Event"]["System"]["EventID"], 4625);
+        assert_eq!(event["Event"]["System"]["EventRecordID"], "1234");
+        assert_eq!(event["Event"]["EventData"]["TargetUserName"], "admin");
+        assert_eq!(event["Event"]["EventData"]["LogonType"], "3");
+        assert!(utils::str_time_to_datetime(
+            event["Event"]["System"]["TimeCreated_attributes"]["SystemTime"]
+                .as_str()
+                .unwrap()
+        )
+        .is_some());
+    }
+
+    #[test]
+    fn test_convert_evtx_dump() {
+        let doc = json!({
+            "Event": {
+                "#attributes": {"xmlns": "http://schemas.microsoft.com/win/2004/08/events/event"},
+                "System": {
+                    "Provider": {"#attributes": {"Name": "Service Control Manager"}},
+                    "EventID": {"#attributes": {"Qualifiers": 16384}, "#text": 7040},
+                    "TimeCreated": {"#attributes": {"SystemTime": "2022-10-18T01:02:03.456789Z"}},
+                    "EventRecordID": 1234,
+                    "Channel": "System",
+                    "Computer": "WKS01"
+                },
+                "EventData": {"param1": "Windows Update", "param4": "wuauserv"}
+            }
+        });
+        let event = load_mapping().convert(&doc).unwrap();
+        assert_eq!(event["Event"]["System"]["EventID"], 7040);
+        assert_eq!(event["Event"]["System"]["Channel"], "System");
+        assert_eq!(
+            event["Event"]["System"]["Provider_attributes"]["Name"],
+            "Service Control Manager"
+        );
+        assert_eq!(
+            event["Event"]["System"]["TimeCreated_attributes"]["SystemTime"],
+            "2022-10-18T01:02:03.456789Z"
+        );
+        assert_eq!(event["Event"]["EventData"]["param4"], "wuauserv");
+        assert!(load_mapping()
+            .convert(&json!({"message": "test"}))
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_invalid_json_mapping() {
+        assert!(JsonLogMapping::parse("name: test").is_err());
+        assert_eq!(
+            JsonLogMapping::parse("- name: test\n  fields:\n      System.EventID: event_id")
+                .unwrap_err(),
+            "The mapping test does not have the fields to detect the format."
+        );
+        assert_eq!(
+            JsonLogMapping::parse("- name: test\n  detect: event_id").unwrap_err(),
+            "The mapping test does not have the fields to convert."
+        );
+        assert!(JsonLogMapping::parse("- detect: event_id\n  copy: EventData").is_err());
+        assert_eq!(
+            load_mapping().format_names(),
+            vec!["evtx_dump", "winlogbeat", "evtxecmd"]
+        );
+    }
+
+    #[test]
+    fn test_read_json_log() {
+        let docs: Vec<Result<Value, String>> =
+            read_json_log(File::open("./test_files/json/winlogbeat.jsonl").unwrap()).collect();
+        assert_eq!(docs.len(), 3);
+        assert!(docs.iter().all(|doc| doc.is_ok()));
+        let docs: Vec<Result<Value, String>> =
+            read_json_log(File::open("./test_files/json/evtxecmd_array.json").unwrap()).collect();
+        assert_eq!(docs.len(), 2);
+        // パースに失敗した以降のドキュメントは読み込まない
+        let docs: Vec<Result<Value, String>> =
+            read_json_log(&b"{\"a\": 1}\n{invalid\n{\"b\": 2}"[..]).collect();
+        assert_eq!(docs.len(), 2);
+        assert!(docs[0].is_ok());
+        assert!(docs[1].is_err());
+    }
+}
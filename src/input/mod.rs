@@ -0,0 +1,5 @@
+pub mod archive;
+pub mod evt_log;
+pub mod evtx_range;
+pub mod json_log;
+pub mod xml_log;
@@ -0,0 +1,372 @@
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::{Map, Value};
+use std::io::{Cursor, Read};
+
+/// --xml-inputで読み込むファイルの拡張子
+pub const XML_LOG_EXTENSIONS: [&str; 1] = ["xml"];
+
+/// 1つのレコードを表す要素の名前
+const EVENT_ELEMENT_NAME: &str = "Event";
+/// イベントビューアーの「名前を付けて保存」で追加される、evtxのレコードには含まれない要素の名前
+const RENDERING_INFO_ELEMENT_NAME: &str = "RenderingInfo";
+
+/// 変換中の要素
+#[derive(Debug, Default)]
+struct XmlElement {
+    name: String,
+    attributes: Map<String, Value>,
+    children: Map<String, Value>,
+    has_children: bool,
+    text: String,
+}
+
+impl XmlElement {
+    fn new<B: std::io::BufRead>(
+        start: &BytesStart,
+        reader: &Reader<B>,
+    ) -> Result<XmlElement, String> {
+        let mut attributes = Map::new();
+        for attribute in start.attributes() {
+            let attribute = attribute.map_err(|e| e.to_string())?;
+            let value = attribute
+                .unescape_and_decode_value(reader)
+                .map_err(|e| e.to_string())?;
+            attributes.insert(
+                String::from_utf8_lossy(attribute.key).to_string(),
+                to_json_value(value),
+            );
+        }
+        Ok(XmlElement {
+            name: String::from_utf8_lossy(start.local_name()).to_string(),
+            attributes,
+            ..Default::default()
+        })
+    }
+
+    /// 子要素の値を追加する。同じ名前の要素が複数ある場合は配列とする
+    fn insert_child(&mut self, key: String, value: Value) {
+        self.has_children = true;
+        match self.children.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(current) => {
+                let first = current.take();
+                *current = Value::Array(vec![first, value]);
+            }
+            None => {
+                self.children.insert(key, value);
+            }
+        }
+    }
+
+    /// 要素の値を返す。子要素を持つ場合はオブジェクト、持たない場合はテキストとする
+    fn into_value(self) -> Value {
+        if self.has_children {
+            Value::Object(self.children)
+        } else if self.text.is_empty() {
+            Value::Null
+        } else {
+            to_json_value(self.text)
+        }
+    }
+
+    /// 閉じた要素を親要素に追加する。
+    /// EvtxParserのseparate_json_attributes(true)と同様に、属性は"{要素名}_attributes"として親要素に追加し、
+    /// Name属性を持つData要素はNameの値をフィールド名とする
+    fn close_into(mut self, parent: &mut XmlElement) {
+        if self.name == "Data" {
+            if let Some(Value::String(name)) = self.attributes.remove("Name") {
+                parent.insert_child(name, self.into_value());
+                return;
+            }
+        }
+        let name = self.name.to_owned();
+        if !self.attributes.is_empty() {
+            let attributes = std::mem::take(&mut self.attributes);
+            parent.insert_child(format!("{}_attributes", name), Value::Object(attributes));
+            // 属性のみを持つ要素は値を追加しない
+            if !self.has_children && self.text.is_empty() {
+                return;
+            }
+        }
+        parent.insert_child(name, self.into_value());
+    }
+}
+
+/// テキストを値に変換する。evtxのレコードと同様に、整数の値は数値とする
+pub(crate) fn to_json_value(text: String) -> Value {
+    if let Ok(n) = text.parse::<u64>() {
+        if n.to_string() == text {
+            return Value::from(n);
+        }
+    }
+    if let Ok(n) = text.parse::<i64>() {
+        if n.to_string() == text {
+            return Value::from(n);
+        }
+    }
+    Value::String(text)
+}
+
+/// ファイルの内容をUTF-8の文字列に変換する。イベントビューアーで保存したXMLはUTF-16のため、BOMを元に変換する
+fn decode_xml(bytes: Vec<u8>) -> Result<String, String> {
+    let utf16 = |bytes: &[u8], le: bool| -> Result<String, String> {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|b| {
+                if le {
+                    u16::from_le_bytes([b[0], b[1]])
+                } else {
+                    u16::from_be_bytes([b[0], b[1]])
+                }
+            })
+            .collect();
+        String::from_utf16(&units).map_err(|e| e.to_string())
+    };
+    match bytes.as_slice() {
+        [0xFF, 0xFE, rest @ ..] => utf16(rest, true),
+        [0xFE, 0xFF, rest @ ..] => utf16(rest, false),
+        [b'<', 0x00, ..] => utf16(&bytes, true),
+        [0xEF, 0xBB, 0xBF, rest @ ..] => {
+            String::from_utf8(rest.to_vec()).map_err(|e| e.to_string())
+        }
+        _ => String::from_utf8(bytes).map_err(|e| e.to_string()),
+    }
+}
+
+/// XML形式のイベントログから<Event>要素を順に読み込み、EvtxParserと同じ形式のJSONに変換するイテレータ
+pub struct XmlLogReader {
+    reader: Reader<Cursor<Vec<u8>>>,
+    buf: Vec<u8>,
+    /// 変換中の<Event>要素と、その子孫の要素
+    stack: Vec<XmlElement>,
+    /// 読み飛ばしている要素の深さ
+    skip_depth: usize,
+    finished: bool,
+}
+
+impl XmlLogReader {
+    pub fn new(content: String) -> XmlLogReader {
+        let mut reader = Reader::from_reader(Cursor::new(content.into_bytes()));
+        reader.check_end_names(true);
+        XmlLogReader {
+            reader,
+            buf: vec![],
+            stack: vec![],
+            skip_depth: 0,
+            finished: false,
+        }
+    }
+
+    fn open_element(&mut self, start: &BytesStart) -> Result<(), String> {
+        if self.skip_depth > 0 {
+            self.skip_depth += 1;
+            return Ok(());
+        }
+        let name = start.local_name();
+        if self.stack.is_empty() && name != EVENT_ELEMENT_NAME.as_bytes() {
+            // <Events>などの<Event>の外側の要素は無視する
+            return Ok(());
+        }
+        if self.stack.len() == 1 && name == RENDERING_INFO_ELEMENT_NAME.as_bytes() {
+            self.skip_depth = 1;
+            return Ok(());
+        }
+        let element = XmlElement::new(start, &self.reader)?;
+        self.stack.push(element);
+        Ok(())
+    }
+
+    /// 要素を閉じる。<Event>要素を閉じた場合は変換したレコードを返す
+    fn close_element(&mut self) -> Option<Value> {
+        if self.skip_depth > 0 {
+            self.skip_depth -= 1;
+            return None;
+        }
+        let element = self.stack.pop()?;
+        match self.stack.last_mut() {
+            Some(parent) => {
+                element.close_into(parent);
+                None
+            }
+            None => {
+                let mut root = XmlElement::default();
+                element.close_into(&mut root);
+                Some(Value::Object(root.children))
+            }
+        }
+    }
+
+    fn read_next(&mut self) -> Result<Option<Value>, String> {
+        loop {
+            self.buf.clear();
+            let event = self
+                .reader
+                .read_event(&mut self.buf)
+                .map_err(|e| e.to_string())?
+                .into_owned();
+            match event {
+                Event::Start(start) => self.open_element(&start)?,
+                Event::Empty(start) => {
+                    self.open_element(&start)?;
+                    if let Some(record) = self.close_element() {
+                        return Ok(Some(record));
+                    }
+                }
+                Event::End(_) => {
+                    if let Some(record) = self.close_element() {
+                        return Ok(Some(record));
+                    }
+                }
+                Event::Text(text) if self.skip_depth == 0 => {
+                    if let Some(element) = self.stack.last_mut() {
+                        let text = text
+                            .unescape_and_decode(&self.reader)
+                            .map_err(|e| e.to_string())?;
+                        element.text.push_str(&text);
+                    }
+                }
+                Event::CData(cdata) if self.skip_depth == 0 => {
+                    if let Some(element) = self.stack.last_mut() {
+                        element
+                            .text
+                            .push_str(&String::from_utf8_lossy(&cdata.into_inner()));
+                    }
+                }
+                Event::Eof => {
+                    if !self.stack.is_empty() {
+                        return Err("The last Event element is not closed.".to_string());
+                    }
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Iterator for XmlLogReader {
+    type Item = Result<Value, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match self.read_next() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                // 要素の対応が崩れた後のレコードは正しく読み込めないため、エラーを返した後は読み込まない
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// wevtutil qe /f:xmlの出力や、イベントビューアーでXML形式で保存したファイルの内容を読み込み、レコードを順に返す
+pub fn read_xml_log<R: Read>(mut reader: R) -> Result<XmlLogReader, String> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+    Ok(XmlLogReader::new(decode_xml(bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs::File;
+
+    #[test]
+    fn test_read_xml_log() {
+        let records: Vec<Value> = XmlLogReader::new(
+            r#"<Event xmlns='http://schemas.microsoft.com/win/2004/08/events/event'><System><Provider Name='Microsoft-Windows-Security-Auditing' Guid='{54849625-5478-4994-a5ba-3e3b0328c30d}'/><EventID>4625</EventID><Version>0</Version><Level>0</Level><Task>12544</Task><Keywords>0x8010000000000000</Keywords><TimeCreated SystemTime='2022-10-18T01:02:03.4567890Z'/><EventRecordID>1001</EventRecordID><Correlation/><Execution ProcessID='644' ThreadID='708'/><Channel>Security</Channel><Computer>WKS01</Computer><Security/></System><EventData><Data Name='TargetUserName'>admin</Data><Data Name='LogonType'>3</Data><Data Name='IpAddress'>-</Data><Data Name='Empty'></Data><Data Name='CommandLine'>cmd.exe /c &quot;whoami&quot; &amp; exit </Data></EventData></Event><Event xmlns='http://schemas.microsoft.com/win/2004/08/events/event'><System><Provider Name='Service Control Manager'/><EventID Qualifiers='16384'>7040</EventID><TimeCreated SystemTime='2022-10-18T01:02:04.0000000Z'/><Channel>System</Channel></System><EventData><Data>Windows Update</Data><Data>auto start</Data><Data>demand start</Data><Binary>770075006100750073006500720076002F0034000000</Binary></EventData></Event>"#
+                .to_string(),
+        )
+        .map(|record| record.unwrap())
+        .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0],
+            json!({
+                "Event_attributes": {"xmlns": "http://schemas.microsoft.com/win/2004/08/events/event"},
+                "Event": {
+                    "System": {
+                        "Provider_attributes": {
+                            "Name": "Microsoft-Windows-Security-Auditing",
+                            "Guid": "{54849625-5478-4994-a5ba-3e3b0328c30d}"
+                        },
+                        "EventID": 4625,
+                        "Version": 0,
+                        "Level": 0,
+                        "Task": 12544,
+                        "Keywords": "0x8010000000000000",
+                        "TimeCreated_attributes": {"SystemTime": "2022-10-18T01:02:03.4567890Z"},
+                        "EventRecordID": 1001,
+                        "Correlation": null,
+                        "Execution_attributes": {"ProcessID": 644, "ThreadID": 708},
+                        "Channel": "Security",
+                        "Computer": "WKS01",
+                        "Security": null
+                    },
+                    "EventData": {
+                        "TargetUserName": "admin",
+                        "LogonType": 3,
+                        "IpAddress": "-",
+                        "Empty": null,
+                        "CommandLine": "cmd.exe /c \"whoami\" & exit "
+                    }
+                }
+            })
+        );
+        assert_eq!(records[1]["Event"]["System"]["EventID"], 7040);
+        assert_eq!(
+            records[1]["Event"]["System"]["EventID_attributes"]["Qualifiers"],
+            16384
+        );
+        assert_eq!(
+            records[1]["Event"]["EventData"]["Data"],
+            json!(["Windows Update", "auto start", "demand start"])
+        );
+        assert_eq!(
+            records[1]["Event"]["EventData"]["Binary"],
+            "770075006100750073006500720076002F0034000000"
+        );
+    }
+
+    #[test]
+    fn test_read_event_viewer_xml() {
+        // イベントビューアーで保存したUTF-16のファイルは<Events>の中に<Event>があり、RenderingInfoを含む
+        let records: Vec<Value> =
+            read_xml_log(File::open("./test_files/xml/event_viewer.xml").unwrap())
+                .unwrap()
+                .map(|record| record.unwrap())
+                .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["Event"]["System"]["EventID"], 4625);
+        assert_eq!(records[0]["Event"]["EventData"]["TargetUserName"], "admin");
+        assert!(records[0]["Event"].get("RenderingInfo").is_none());
+        assert_eq!(
+            records[1]["Event"]["UserData"]["LogFileCleared"]["SubjectUserName"],
+            "admin"
+        );
+    }
+
+    #[test]
+    fn test_read_invalid_xml_log() {
+        let mut records = XmlLogReader::new(
+            "<Event><System><EventID>1</EventID></System></Event><Event><System></Event>"
+                .to_string(),
+        );
+        assert!(records.next().unwrap().is_ok());
+        assert!(records.next().unwrap().is_err());
+        assert!(records.next().is_none());
+        assert!(XmlLogReader::new("<Event><System>".to_string())
+            .next()
+            .unwrap()
+            .is_err());
+    }
+}
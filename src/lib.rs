@@ -1,6 +1,7 @@
 pub mod afterfact;
 pub mod detections;
 pub mod filter;
+pub mod input;
 pub mod notify;
 pub mod omikuji;
 pub mod options;
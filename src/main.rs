@@ -5,43 +5,55 @@ extern crate serde_derive;
 
 use bytesize::ByteSize;
 use chrono::{DateTime, Datelike, Local};
-use evtx::{EvtxParser, ParserSettings};
+use evtx::{EvtxChunkData, EvtxParser, ParserSettings, SerializedEvtxRecord};
 use hashbrown::{HashMap, HashSet};
+use hayabusa::detections::configs::CURRENT_EXE_PATH;
 use hayabusa::detections::configs::{load_pivot_keywords, TargetEventTime, TARGET_EXTENSIONS};
-use hayabusa::detections::configs::{CONFIG, CURRENT_EXE_PATH};
 use hayabusa::detections::detection::{self, EvtxRecordInfo};
 use hayabusa::detections::message::{
-    AlertMessage, ERROR_LOG_PATH, ERROR_LOG_STACK, LOGONSUMMARY_FLAG, PIVOT_KEYWORD_LIST_FLAG,
-    QUIET_ERRORS_FLAG, STATISTICS_FLAG,
+    self, AlertMessage, ERROR_LOG_PATH, ERROR_LOG_STACK, LOGONSUMMARY_FLAG,
+    PIVOT_KEYWORD_LIST_FLAG, QUIET_ERRORS_FLAG, STATISTICS_FLAG,
 };
 use hayabusa::detections::pivot::PivotKeyword;
 use hayabusa::detections::pivot::PIVOT_KEYWORD;
-use hayabusa::detections::rule::{get_detection_keys, RuleNode};
+use hayabusa::detections::rule::{get_detection_keys, get_rule_targets, RuleNode, RuleTargets};
+use hayabusa::input::archive;
+use hayabusa::input::evt_log;
+use hayabusa::input::evtx_range;
+use hayabusa::input::json_log::{self, JsonLogMapping};
+use hayabusa::input::xml_log;
 use hayabusa::omikuji::Omikuji;
 use hayabusa::options::profile::PROFILES;
-use hayabusa::options::{level_tuning::LevelTuning, update_rules::UpdateRules};
+use hayabusa::options::update_rules::RULES_REVISION;
+use hayabusa::options::{
+    debug_rule::DebugRule, level_tuning::LevelTuning, list_rules::ListRules,
+    rule_template::RuleTemplate, test_rules::TestRules, update_rules::UpdateRules,
+    validate_rules::ValidateRules,
+};
 use hayabusa::{afterfact::after_fact, detections::utils};
 use hayabusa::{detections::configs, timeline::timelines::Timeline};
 use hayabusa::{detections::utils::write_color_buffer, filter};
 use hhmmss::Hhmmss;
+use itertools::Itertools;
 use pbr::ProgressBar;
 use serde_json::Value;
 use std::ffi::{OsStr, OsString};
 use std::fmt::Display;
 use std::fmt::Write as _;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Cursor, Read, Seek, Write};
+use std::ops::AddAssign;
 use std::path::Path;
 use std::sync::Arc;
 use std::{
     env,
     fs::{self, File},
     path::PathBuf,
-    vec,
+    process, vec,
 };
 use termcolor::{BufferWriter, Color, ColorChoice};
 use tokio::runtime::Runtime;
 use tokio::spawn;
-use tokio::task::JoinHandle;
+use tokio::task::{spawn_blocking, JoinHandle};
 
 #[cfg(target_os = "windows")]
 use is_elevated::is_elevated;
@@ -55,9 +67,28 @@ fn main() {
     app.rt.shutdown_background();
 }
 
+/// 解析するレコードのイテレータ。パースに失敗したレコードはエラーメッセージを返す
+type EventRecords<'a> = Box<dyn Iterator<Item = Result<SerializedEvtxRecord<Value>, String>> + 'a>;
+
+/// 解析したレコード数と、--timeline-startより前に書き込まれたため読み飛ばしたレコード数
+#[derive(Debug, Default, Clone, Copy)]
+struct RecordCount {
+    scanned: usize,
+    skipped: usize,
+}
+
+impl AddAssign for RecordCount {
+    fn add_assign(&mut self, other: RecordCount) {
+        self.scanned += other.scanned;
+        self.skipped += other.skipped;
+    }
+}
+
 pub struct App {
     rt: Runtime,
     rule_keys: Vec<String>,
+    rule_targets: RuleTargets,
+    json_mapping: Option<JsonLogMapping>,
 }
 
 impl Default for App {
@@ -71,6 +102,8 @@ impl App {
         App {
             rt: utils::create_tokio_runtime(),
             rule_keys: Vec::new(),
+            rule_targets: RuleTargets::unconstrained(),
+            json_mapping: None,
         }
     }
 
@@ -117,9 +150,21 @@ impl App {
             return;
         }
 
+        // rulesフォルダを変更するオプションは、対象のフォルダが曖昧にならないように-rの複数指定を受け付けない
+        let rules_cnt = configs::CONFIG.read().unwrap().args.rules.len();
+        if rules_cnt > 1 && UpdateRules::is_rules_changing_option() {
+            AlertMessage::alert(
+                "--update-rules, --update-rules-from, --pin-rules, --rollback-rules and --level-tuning can only be used with a single rules directory (-r).",
+            )
+            .ok();
+            println!();
+            return;
+        }
         if configs::CONFIG.read().unwrap().args.update_rules {
             match UpdateRules::update_rules(
-                configs::CONFIG.read().unwrap().args.rules.to_str().unwrap(),
+                configs::CONFIG.read().unwrap().args.rules[0]
+                    .to_str()
+                    .unwrap(),
             ) {
                 Ok(output) => {
                     if output != "You currently have the latest rules." {
@@ -139,6 +184,46 @@ impl App {
             println!();
             return;
         }
+        if configs::CONFIG.read().unwrap().args.pin_rules.is_some()
+            || configs::CONFIG.read().unwrap().args.rollback_rules
+        {
+            let args = &configs::CONFIG.read().unwrap().args;
+            let rule_path = args.rules[0].to_str().unwrap();
+            let result = match &args.pin_rules {
+                Some(revision) => UpdateRules::pin_rules(rule_path, revision),
+                None => UpdateRules::rollback_rules(rule_path),
+            };
+            if let Err(e) = result {
+                AlertMessage::alert(&format!("Failed to change the rules revision. {}", e)).ok();
+            }
+            println!();
+            return;
+        }
+        if let Some(archive_path) = &configs::CONFIG.read().unwrap().args.update_rules_from {
+            let args = &configs::CONFIG.read().unwrap().args;
+            match UpdateRules::update_rules_from_archive(
+                args.rules[0].to_str().unwrap(),
+                archive_path,
+                args.manifest.as_deref(),
+            ) {
+                Ok(output) => {
+                    if output != "You currently have the latest rules." {
+                        write_color_buffer(
+                            &BufferWriter::stdout(ColorChoice::Always),
+                            None,
+                            "Rules updated successfully.",
+                            true,
+                        )
+                        .ok();
+                    }
+                }
+                Err(e) => {
+                    AlertMessage::alert(&format!("Failed to update rules. {}", e)).ok();
+                }
+            }
+            println!();
+            return;
+        }
         // 実行時のexeファイルのパスをベースに変更する必要があるためデフォルトの値であった場合はそのexeファイルと同一階層を探すようにする
         if !CURRENT_EXE_PATH.join("config").exists() && !Path::new("./config").exists() {
             AlertMessage::alert(
@@ -155,9 +240,12 @@ impl App {
         }
 
         // カレントディレクトリ以外からの実行の際にrulesオプションの指定がないとエラーが発生することを防ぐための処理
-        if configs::CONFIG.read().unwrap().args.rules == Path::new("./rules") {
+        if configs::CONFIG.read().unwrap().args.rules == [Path::new("./rules")] {
             configs::CONFIG.write().unwrap().args.rules =
-                utils::check_setting_path(&CURRENT_EXE_PATH.to_path_buf(), "rules", true).unwrap();
+                vec![
+                    utils::check_setting_path(&CURRENT_EXE_PATH.to_path_buf(), "rules", true)
+                        .unwrap(),
+                ];
         }
         // rule configのフォルダ、ファイルを確認してエラーがあった場合は終了とする
         if let Err(e) = utils::check_rule_config() {
@@ -214,7 +302,51 @@ impl App {
             println!();
         }
 
-        if configs::CONFIG.read().unwrap().args.live_analysis {
+        if let Some(rule_path) = &configs::CONFIG.read().unwrap().args.debug_rule {
+            let evtx_path = configs::CONFIG
+                .read()
+                .unwrap()
+                .args
+                .filepath
+                .clone()
+                .unwrap();
+            let record_id = configs::CONFIG
+                .read()
+                .unwrap()
+                .args
+                .debug_record_id
+                .unwrap();
+            if let Err(err) = DebugRule::run(rule_path, &evtx_path, record_id) {
+                AlertMessage::alert(&err).ok();
+                process::exit(1);
+            }
+            return;
+        } else if configs::CONFIG.read().unwrap().args.rule_template {
+            let evtx_path = configs::CONFIG
+                .read()
+                .unwrap()
+                .args
+                .filepath
+                .clone()
+                .unwrap();
+            let record_id = configs::CONFIG
+                .read()
+                .unwrap()
+                .args
+                .debug_record_id
+                .unwrap();
+            let output_path = configs::CONFIG.read().unwrap().args.output.clone();
+            if let Err(err) = RuleTemplate::run(
+                &evtx_path,
+                record_id,
+                output_path.as_deref(),
+                &configs::EVENTKEY_ALIAS,
+            ) {
+                AlertMessage::alert(&err).ok();
+                process::exit(1);
+            }
+            return;
+        } else if configs::CONFIG.read().unwrap().args.live_analysis {
             let live_analysis_list = self.collect_liveanalysis_files();
             if live_analysis_list.is_none() {
                 return;
@@ -229,32 +361,43 @@ impl App {
                 .ok();
                 return;
             }
-            if !TARGET_EXTENSIONS.contains(
-                filepath
-                    .extension()
-                    .unwrap_or_else(|| OsStr::new("."))
-                    .to_str()
-                    .unwrap(),
-            ) || filepath
-                .as_path()
-                .file_stem()
-                .unwrap_or_else(|| OsStr::new("."))
-                .to_str()
-                .unwrap()
-                .trim()
-                .starts_with('.')
+            // --scan-archivesが指定された場合はアーカイブのファイルも受け付ける
+            let is_archive =
+                configs::CONFIG.read().unwrap().args.scan_archives && archive::is_archive(filepath);
+            if !is_archive
+                && (!App::is_target_extension(filepath)
+                    || filepath
+                        .as_path()
+                        .file_stem()
+                        .unwrap_or_else(|| OsStr::new("."))
+                        .to_str()
+                        .unwrap()
+                        .trim()
+                        .starts_with('.'))
             {
-                AlertMessage::alert(
-                    "--filepath only accepts .evtx files. Hidden files are ignored.",
-                )
-                .ok();
+                let args = &configs::CONFIG.read().unwrap().args;
+                let errmsg = if args.json_input {
+                    "--filepath only accepts .json or .jsonl files with --json-input. Hidden files are ignored."
+                } else if args.xml_input {
+                    "--filepath only accepts .xml files with --xml-input. Hidden files are ignored."
+                } else {
+                    "--filepath only accepts .evtx or .evt files. Hidden files are ignored."
+                };
+                AlertMessage::alert(errmsg).ok();
                 return;
             }
             self.analysis_files(vec![PathBuf::from(filepath)], &time_filter);
         } else if let Some(directory) = &configs::CONFIG.read().unwrap().args.directory {
             let evtx_files = self.collect_evtxfiles(directory.as_os_str().to_str().unwrap());
             if evtx_files.is_empty() {
-                AlertMessage::alert("No .evtx files were found.").ok();
+                let args = &configs::CONFIG.read().unwrap().args;
+                if args.json_input {
+                    AlertMessage::alert("No .json or .jsonl files were found.").ok();
+                } else if args.xml_input {
+                    AlertMessage::alert("No .xml files were found.").ok();
+                } else {
+                    AlertMessage::alert("No .evtx files were found.").ok();
+                }
                 return;
             }
             self.analysis_files(evtx_files, &time_filter);
@@ -269,36 +412,16 @@ impl App {
                 .level_tuning
                 .clone()
                 .unwrap();
-            let level_tuning_config_path = match level_tuning_val {
-                Some(path) => path.to_owned(),
-                _ => utils::check_setting_path(
-                    &CONFIG.read().unwrap().args.config,
-                    "level_tuning.txt",
-                    false,
-                )
-                .unwrap_or_else(|| {
-                    utils::check_setting_path(
-                        &CURRENT_EXE_PATH.to_path_buf(),
-                        "rules/config/level_tuning.txt",
-                        true,
-                    )
-                    .unwrap()
-                })
-                .display()
-                .to_string(),
-            };
+            let level_tuning_config_path = LevelTuning::get_config_path(level_tuning_val);
 
             if Path::new(&level_tuning_config_path).exists() {
                 if let Err(err) = LevelTuning::run(
                     &level_tuning_config_path,
-                    configs::CONFIG
-                        .read()
-                        .unwrap()
-                        .args
-                        .rules
+                    configs::CONFIG.read().unwrap().args.rules[0]
                         .as_os_str()
                         .to_str()
                         .unwrap(),
+                    configs::CONFIG.read().unwrap().args.dry_run,
                 ) {
                     AlertMessage::alert(&err).ok();
                 }
@@ -309,6 +432,52 @@ impl App {
                 .ok();
             }
             return;
+        } else if let Some(filters) = &configs::CONFIG.read().unwrap().args.list_rules {
+            let args = &configs::CONFIG.read().unwrap().args;
+            let result = ListRules::run(&args.rules, filters).and_then(|list| {
+                list.output(
+                    args.output.as_deref(),
+                    args.json_timeline,
+                    args.jsonl_timeline,
+                )
+            });
+            if let Err(err) = result {
+                AlertMessage::alert(&err).ok();
+                process::exit(1);
+            }
+            return;
+        } else if configs::CONFIG.read().unwrap().args.test_rules {
+            let rules_path = configs::CONFIG.read().unwrap().args.rules.clone();
+            match TestRules::run(&rules_path) {
+                Ok(result) => {
+                    result.output();
+                    println!();
+                    if result.failed_count() > 0 {
+                        process::exit(1);
+                    }
+                }
+                Err(err) => {
+                    AlertMessage::alert(&err).ok();
+                    process::exit(1);
+                }
+            }
+            return;
+        } else if configs::CONFIG.read().unwrap().args.validate_rules {
+            let rules_path = configs::CONFIG.read().unwrap().args.rules.clone();
+            match ValidateRules::validate_paths(&rules_path, &configs::EVENTKEY_ALIAS) {
+                Ok(result) => {
+                    result.output();
+                    println!();
+                    if result.error_count() > 0 {
+                        process::exit(1);
+                    }
+                }
+                Err(err) => {
+                    AlertMessage::alert(&err).ok();
+                    process::exit(1);
+                }
+            }
+            return;
         } else {
             write_color_buffer(
                 &BufferWriter::stdout(ColorChoice::Always),
@@ -466,17 +635,9 @@ impl App {
                     ret.extend(subdir_ret);
                     Option::Some(())
                 });
-            } else if TARGET_EXTENSIONS.contains(
-                path.extension()
-                    .unwrap_or_else(|| OsStr::new(""))
-                    .to_str()
-                    .unwrap(),
-            ) && !path
-                .file_stem()
-                .unwrap_or_else(|| OsStr::new("."))
-                .to_str()
-                .unwrap()
-                .starts_with('.')
+            } else if App::is_target_file(&path)
+                || (configs::CONFIG.read().unwrap().args.scan_archives
+                    && archive::is_archive(&path))
             {
                 ret.push(path);
             }
@@ -485,6 +646,26 @@ impl App {
         ret
     }
 
+    /// 拡張子が解析対象かを判定する。Windows XP/2003の.Evtのような大文字を含む拡張子も対象とする
+    fn is_target_extension(path: &Path) -> bool {
+        let ext = path
+            .extension()
+            .unwrap_or_else(|| OsStr::new(""))
+            .to_string_lossy();
+        TARGET_EXTENSIONS.contains(ext.as_ref()) || TARGET_EXTENSIONS.contains(&ext.to_lowercase())
+    }
+
+    /// 拡張子が解析対象で、ドットから始まる隠しファイルではないかを判定する
+    fn is_target_file(path: &Path) -> bool {
+        App::is_target_extension(path)
+            && !path
+                .file_stem()
+                .unwrap_or_else(|| OsStr::new("."))
+                .to_str()
+                .unwrap()
+                .starts_with('.')
+    }
+
     fn print_contributors(&self) {
         match fs::read_to_string(
             utils::check_setting_path(&CURRENT_EXE_PATH.to_path_buf(), "contributors.txt", true)
@@ -526,7 +707,20 @@ impl App {
             total_file_size += ByteSize::b(meta.unwrap().len());
         }
         println!("Total file size: {}", total_file_size.to_string_as(false));
+        if let Some(revision) = &*RULES_REVISION {
+            println!("Rules revision: {}", revision);
+        }
         println!();
+        if configs::CONFIG.read().unwrap().args.json_input {
+            let json_mapping_path = JsonLogMapping::get_config_path();
+            match JsonLogMapping::load(&json_mapping_path) {
+                Ok(json_mapping) => self.json_mapping = Some(json_mapping),
+                Err(err) => {
+                    AlertMessage::alert(&err).ok();
+                    return;
+                }
+            }
+        }
         println!("Loading detections rules. Please wait.");
         println!();
 
@@ -547,14 +741,20 @@ impl App {
         let mut pb = ProgressBar::new(evtx_files.len() as u64);
         pb.show_speed = false;
         self.rule_keys = self.get_all_keys(&rule_files);
+        self.rule_targets = self.get_all_targets(&rule_files);
         let mut detection = detection::Detection::new(rule_files);
-        let mut total_records: usize = 0;
+        let mut total_records = RecordCount::default();
+        let scan_archives = configs::CONFIG.read().unwrap().args.scan_archives;
         for evtx_file in evtx_files {
             if configs::CONFIG.read().unwrap().args.verbose {
                 println!("Checking target evtx FilePath: {:?}", &evtx_file);
             }
-            let cnt_tmp: usize;
-            (detection, cnt_tmp) = self.analysis_file(evtx_file, detection, time_filter);
+            let cnt_tmp: RecordCount;
+            (detection, cnt_tmp) = if scan_archives && archive::is_archive(&evtx_file) {
+                self.analysis_archive(evtx_file, detection, time_filter)
+            } else {
+                self.analysis_file(evtx_file, detection, time_filter)
+            };
             total_records += cnt_tmp;
             pb.inc();
         }
@@ -566,7 +766,10 @@ impl App {
         println!();
         detection.add_aggcondition_msges(&self.rt);
         if !(*STATISTICS_FLAG || *LOGONSUMMARY_FLAG || *PIVOT_KEYWORD_LIST_FLAG) {
-            after_fact(total_records);
+            after_fact(total_records.scanned, total_records.skipped);
+        }
+        if let Some(profiling_path) = &configs::CONFIG.read().unwrap().args.rule_profiling {
+            detection.output_rule_profiling(profiling_path.as_ref());
         }
     }
 
@@ -574,20 +777,175 @@ impl App {
     fn analysis_file(
         &self,
         evtx_filepath: PathBuf,
+        detection: detection::Detection,
+        time_filter: &TargetEventTime,
+    ) -> (detection::Detection, RecordCount) {
+        let file = match File::open(&evtx_filepath) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("{}", e);
+                return (detection, RecordCount::default());
+            }
+        };
+        self.analysis_reader(
+            file,
+            &evtx_filepath.display().to_string(),
+            detection,
+            time_filter,
+        )
+    }
+
+    /// --scan-archivesが指定された場合に、アーカイブを展開せずに含まれている対象のファイルを1ファイルずつ解析する。
+    /// %EvtxFile%にはアーカイブのパスにアーカイブ内のパスを連結したパスを出力する
+    fn analysis_archive(
+        &self,
+        archive_path: PathBuf,
+        detection: detection::Detection,
+        time_filter: &TargetEventTime,
+    ) -> (detection::Detection, RecordCount) {
+        let mut detection = Some(detection);
+        let mut record_cnt = RecordCount::default();
+        let result = archive::for_each_archive_file(
+            &archive_path,
+            |name| App::is_target_file(Path::new(name)),
+            |name, content| {
+                let path = format!("{}/{}", archive_path.display(), name);
+                if configs::CONFIG.read().unwrap().args.verbose {
+                    println!("Checking target evtx FilePath: {:?}", &path);
+                }
+                let (ret, cnt) = self.analysis_reader(
+                    Cursor::new(content),
+                    &path,
+                    detection.take().unwrap(),
+                    time_filter,
+                );
+                detection = Some(ret);
+                record_cnt += cnt;
+            },
+        );
+        if let Err(e) = result {
+            let errmsg = format!(
+                "Failed to read the archive file. ArchiveFile:{} Error:{}",
+                archive_path.display(),
+                e
+            );
+            if configs::CONFIG.read().unwrap().args.verbose {
+                AlertMessage::alert(&errmsg).ok();
+            }
+            if !*QUIET_ERRORS_FLAG {
+                ERROR_LOG_STACK
+                    .lock()
+                    .unwrap()
+                    .push(format!("[ERROR] {}", errmsg));
+            }
+        }
+        (detection.unwrap(), record_cnt)
+    }
+
+    /// 1ファイル分のイベントログをreaderから読み込んで解析する。pathは%EvtxFile%に出力するパス
+    fn analysis_reader<R: Read + Seek>(
+        &self,
+        reader: R,
+        path: &str,
+        detection: detection::Detection,
+        time_filter: &TargetEventTime,
+    ) -> (detection::Detection, RecordCount) {
+        // --json-input、--xml-inputが指定された場合や.evtのファイルはevtxのパーサーを使わずにファイルを読み込む
+        if self.json_mapping.is_some()
+            || configs::CONFIG.read().unwrap().args.xml_input
+            || evt_log::is_evt_file(Path::new(path))
+        {
+            return match self.read_exported_log(reader, path) {
+                Ok(records) => {
+                    let (detection, record_cnt) =
+                        self.detect_records(records, path, detection, time_filter);
+                    (
+                        detection,
+                        RecordCount {
+                            scanned: record_cnt,
+                            skipped: 0,
+                        },
+                    )
+                }
+                Err(e) => {
+                    let errmsg = format!(
+                        "Failed to read the event file. EventFile:{} Error:{}",
+                        path, e
+                    );
+                    if configs::CONFIG.read().unwrap().args.verbose {
+                        AlertMessage::alert(&errmsg).ok();
+                    }
+                    if !*QUIET_ERRORS_FLAG {
+                        ERROR_LOG_STACK
+                            .lock()
+                            .unwrap()
+                            .push(format!("[ERROR] {}", errmsg));
+                    }
+                    (detection, RecordCount::default())
+                }
+            };
+        }
+
+        let mut parser = match EvtxParser::from_read_seek(reader) {
+            Ok(parser) => parser.with_configuration(App::create_parser_settings()),
+            Err(e) => {
+                eprintln!("{}", e);
+                return (detection, RecordCount::default());
+            }
+        };
+        // --timeline-startが指定された場合は、レコードヘッダーのタイムスタンプから開始日時より前に書き込まれたチャンクを読み飛ばす
+        if !time_filter.has_start_time() {
+            let records: EventRecords = Box::new(
+                parser
+                    .records_json_value()
+                    .map(|record| record.map_err(|e| e.to_string())),
+            );
+            let (detection, record_cnt) =
+                self.detect_records(records, path, detection, time_filter);
+            return (
+                detection,
+                RecordCount {
+                    scanned: record_cnt,
+                    skipped: 0,
+                },
+            );
+        }
+        let mut skipped_record_cnt = 0;
+        let mut parsed_chunk_cnt = 0;
+        let records: EventRecords = Box::new(App::records_in_time_range(
+            &mut parser,
+            &self.rt,
+            time_filter,
+            &mut skipped_record_cnt,
+            &mut parsed_chunk_cnt,
+        ));
+        let (detection, record_cnt) = self.detect_records(records, path, detection, time_filter);
+        if parsed_chunk_cnt == 0
+            && skipped_record_cnt > 0
+            && configs::CONFIG.read().unwrap().args.verbose
+        {
+            println!("Skipped evtx file written before the start time: {}", path);
+        }
+        (
+            detection,
+            RecordCount {
+                scanned: record_cnt,
+                skipped: skipped_record_cnt,
+            },
+        )
+    }
+
+    /// 読み込んだレコードをMAX_DETECT_RECORDS件ずつtimelineとルールの検知に渡す。
+    fn detect_records(
+        &self,
+        mut records: EventRecords,
+        path: &str,
         mut detection: detection::Detection,
         time_filter: &TargetEventTime,
     ) -> (detection::Detection, usize) {
-        let path = evtx_filepath.display();
-        let parser = self.evtx_to_jsons(evtx_filepath.clone());
         let mut record_cnt = 0;
-        if parser.is_none() {
-            return (detection, record_cnt);
-        }
-
         let mut tl = Timeline::new();
-        let mut parser = parser.unwrap();
-        let mut records = parser.records_json_value();
-
+        let is_evt = evt_log::is_evt_file(Path::new(path));
         loop {
             let mut records_per_detect = vec![];
             while records_per_detect.len() < MAX_DETECT_RECORDS {
@@ -618,16 +976,18 @@ impl App {
                     continue;
                 }
 
-                // target_eventids.txtでイベントIDベースでフィルタする。
+                // target_eventids.txtもしくは読み込んだルールのEventIDとChannelでフィルタする。
                 let data = record_result.as_ref().unwrap().data.clone();
-                if !self._is_target_event_id(&data)
+                if !self.is_target_event(&data, is_evt)
                     && !configs::CONFIG.read().unwrap().args.deep_scan
                 {
                     continue;
                 }
 
                 // EventID側の条件との条件の混同を防ぐため時間でのフィルタリングの条件分岐を分離した
-                let timestamp = record_result.unwrap().timestamp;
+                // 出力する日時と同じTimeCreatedで判定し、TimeCreatedが無い場合はレコードヘッダーのタイムスタンプを使用する
+                let timestamp = message::get_event_time(&data)
+                    .unwrap_or_else(|| record_result.unwrap().timestamp);
                 if !time_filter.is_target(&Some(timestamp)) {
                     continue;
                 }
@@ -659,6 +1019,127 @@ impl App {
         (detection, record_cnt)
     }
 
+    /// チャンク単位でレコードを読み込み、開始日時より前に書き込まれたレコードのみのチャンクはパースせずに読み飛ばす。
+    /// 読み飛ばしたレコード数はskipped_record_cntに、パースしたチャンク数はparsed_chunk_cntに加算する。
+    fn records_in_time_range<'a, T: Read + Seek>(
+        parser: &'a mut EvtxParser<T>,
+        rt: &'a Runtime,
+        time_filter: &'a TargetEventTime,
+        skipped_record_cnt: &'a mut usize,
+        parsed_chunk_cnt: &'a mut usize,
+    ) -> impl Iterator<Item = Result<SerializedEvtxRecord<Value>, String>> + 'a {
+        let settings = Arc::new(App::create_parser_settings());
+        let mut chunks = parser.chunks();
+        std::iter::from_fn(move || {
+            // EvtxParserと同様に複数のチャンクを並列にパースするため、CPUのコア数分のチャンクをまとめて読み込む
+            let mut target_chunks = vec![];
+            while target_chunks.len() < num_cpus::get() {
+                match chunks.next() {
+                    Some(Ok(chunk_data)) => {
+                        if let Some(range) = evtx_range::get_chunk_time_range(&chunk_data.data) {
+                            if time_filter.is_written_before_start(&range.last) {
+                                *skipped_record_cnt += range.record_cnt;
+                                continue;
+                            }
+                        }
+                        target_chunks.push(Ok(chunk_data));
+                    }
+                    Some(Err(e)) => target_chunks.push(Err(e.to_string())),
+                    None => break,
+                }
+            }
+            if target_chunks.is_empty() {
+                return None;
+            }
+            *parsed_chunk_cnt += target_chunks.len();
+            Some(rt.block_on(App::parse_chunks(target_chunks, Arc::clone(&settings))))
+        })
+        .flatten()
+    }
+
+    /// チャンクを並列にパースし、チャンクの順にレコードを返す
+    async fn parse_chunks(
+        chunks: Vec<Result<EvtxChunkData, String>>,
+        settings: Arc<ParserSettings>,
+    ) -> Vec<Result<SerializedEvtxRecord<Value>, String>> {
+        let threads: Vec<JoinHandle<_>> = chunks
+            .into_iter()
+            .map(|chunk| {
+                let settings = Arc::clone(&settings);
+                spawn_blocking(move || {
+                    let mut chunk_data = match chunk {
+                        Ok(chunk_data) => chunk_data,
+                        Err(e) => return vec![Err(e)],
+                    };
+                    match chunk_data.parse(settings) {
+                        Ok(mut chunk) => chunk
+                            .iter()
+                            .map(|record| {
+                                record
+                                    .and_then(|record| record.into_json_value())
+                                    .map_err(|e| e.to_string())
+                            })
+                            .collect(),
+                        Err(e) => vec![Err(e.to_string())],
+                    }
+                })
+            })
+            .collect();
+
+        let mut ret = vec![];
+        for thread in threads.into_iter() {
+            ret.extend(thread.await.unwrap());
+        }
+        ret
+    }
+
+    /// --json-inputもしくは--xml-inputで指定された形式のファイルや.evtのファイルを読み込み、.evtxのレコードと同じ形式に変換する。
+    /// JSONの定義に一致しないドキュメントはエラーとする
+    fn read_exported_log<'a, R: Read + 'a>(
+        &'a self,
+        reader: R,
+        path: &str,
+    ) -> Result<EventRecords<'a>, String> {
+        if evt_log::is_evt_file(Path::new(path)) {
+            let records = evt_log::read_evt_log(reader, Path::new(path))?;
+            return Ok(Box::new(
+                records.map(|record| App::to_serialized_record(record?)),
+            ));
+        }
+        match &self.json_mapping {
+            Some(json_mapping) => {
+                let docs = json_log::read_json_log(reader);
+                Ok(Box::new(docs.map(move |doc| {
+                    let data = json_mapping
+                        .convert(&doc?)
+                        .ok_or_else(|| "No JSON mapping matched the document.".to_string())?;
+                    App::to_serialized_record(data)
+                })))
+            }
+            None => {
+                let records = xml_log::read_xml_log(reader)?;
+                Ok(Box::new(
+                    records.map(|record| App::to_serialized_record(record?)),
+                ))
+            }
+        }
+    }
+
+    /// 変換したレコードからタイムスタンプとレコードIDを取得する。タイムスタンプが無いレコードはエラーとする
+    fn to_serialized_record(data: Value) -> Result<SerializedEvtxRecord<Value>, String> {
+        let timestamp = message::get_event_time(&data)
+            .ok_or_else(|| "The record does not have a valid timestamp.".to_string())?;
+        let event_record_id = utils::get_event_value("Event.System.EventRecordID", &data)
+            .and_then(utils::value_to_string)
+            .and_then(|id| id.parse().ok())
+            .unwrap_or_default();
+        Ok(SerializedEvtxRecord {
+            event_record_id,
+            timestamp,
+            data,
+        })
+    }
+
     async fn create_rec_infos(
         records_per_detect: Vec<Value>,
         path: &dyn Display,
@@ -698,6 +1179,71 @@ impl App {
         ret
     }
 
+    /// 読み込んだルールが検知しうるEventIDとChannelの一覧を作成する。
+    /// --auto-eid-filterが指定されていない場合はtarget_event_IDs.txtで除外されるEventIDを対象とするルールを警告する。
+    fn get_all_targets(&self, rules: &[RuleNode]) -> RuleTargets {
+        let mut all_targets = RuleTargets {
+            eventids: Some(HashSet::new()),
+            channels: Some(HashSet::new()),
+        };
+        let mut filtered_rule_cnt = 0;
+        let (auto_eid_filter, deep_scan, verbose) = {
+            let args = &configs::CONFIG.read().unwrap().args;
+            (args.auto_eid_filter, args.deep_scan, args.verbose)
+        };
+        for rule in rules {
+            let targets = get_rule_targets(rule);
+            all_targets.union(&targets);
+            if auto_eid_filter || deep_scan {
+                continue;
+            }
+
+            let filtered_ids: Vec<&String> = targets
+                .eventids
+                .iter()
+                .flatten()
+                .filter(|eid| !utils::is_target_event_id(eid))
+                .sorted()
+                .collect();
+            if filtered_ids.is_empty() {
+                continue;
+            }
+            filtered_rule_cnt += 1;
+            let errmsg = format!(
+                "The rule targets event IDs filtered out by target_event_IDs.txt. (EventID: {}, FilePath: {})",
+                filtered_ids.iter().join(", "),
+                rule.rulepath
+            );
+            if verbose {
+                AlertMessage::warn(&errmsg).ok();
+            }
+            if !*QUIET_ERRORS_FLAG {
+                ERROR_LOG_STACK
+                    .lock()
+                    .unwrap()
+                    .push(format!("[WARN] {}", errmsg));
+            }
+        }
+        if filtered_rule_cnt > 0 {
+            AlertMessage::warn(&format!(
+                "{} rules target event IDs that are filtered out by target_event_IDs.txt. Use --auto-eid-filter or -D, --deep-scan to scan these events.",
+                filtered_rule_cnt
+            ))
+            .ok();
+            println!();
+        }
+        all_targets
+    }
+
+    /// --auto-eid-filterが指定された場合は読み込んだルールのEventIDとChannel、それ以外の場合はtarget_event_IDs.txtを元にフィルタする。
+    /// target_event_IDs.txtはWindows Vista以降のイベントIDのため、.evtのレコードはtarget_event_IDs.txtではフィルタしない
+    fn is_target_event(&self, data: &Value, is_evt: bool) -> bool {
+        if configs::CONFIG.read().unwrap().args.auto_eid_filter {
+            return self.rule_targets.is_target(data);
+        }
+        is_evt || self._is_target_event_id(data)
+    }
+
     // target_eventids.txtの設定を元にフィルタする。
     fn _is_target_event_id(&self, data: &Value) -> bool {
         let eventid = utils::get_event_value(&utils::get_event_id_key(), data);
@@ -712,22 +1258,12 @@ impl App {
         }
     }
 
-    fn evtx_to_jsons(&self, evtx_filepath: PathBuf) -> Option<EvtxParser<File>> {
-        match EvtxParser::from_path(evtx_filepath) {
-            Ok(evtx_parser) => {
-                // parserのデフォルト設定を変更
-                let mut parse_config = ParserSettings::default();
-                parse_config = parse_config.separate_json_attributes(true); // XMLのattributeをJSONに変換する時のルールを設定
-                parse_config = parse_config.num_threads(0); // 設定しないと遅かったので、設定しておく。
-
-                let evtx_parser = evtx_parser.with_configuration(parse_config);
-                Option::Some(evtx_parser)
-            }
-            Err(e) => {
-                eprintln!("{}", e);
-                Option::None
-            }
-        }
+    fn create_parser_settings() -> ParserSettings {
+        // parserのデフォルト設定を変更
+        let mut parse_config = ParserSettings::default();
+        parse_config = parse_config.separate_json_attributes(true); // XMLのattributeをJSONに変換する時のルールを設定
+        parse_config = parse_config.num_threads(0); // 設定しないと遅かったので、設定しておく。
+        parse_config
     }
 
     fn _output_with_omikuji(&self, omikuji: Omikuji) {
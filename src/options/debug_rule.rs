@@ -0,0 +1,188 @@
+use crate::detections::rule::{create_rule, get_detection_keys, SelectionTrace};
+use crate::detections::utils::{self, write_color_buffer};
+use evtx::{EvtxParser, ParserSettings};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use termcolor::{BufferWriter, Color, ColorChoice};
+use yaml_rust::{Yaml, YamlLoader};
+
+/// --debug-ruleで1つのレコードに対するルールの評価過程を出力する構造体
+#[derive(Debug)]
+pub struct DebugRule {
+    pub title: String,
+    pub result: bool,
+    /// aggregation conditionがあるルールかどうか
+    pub has_agg_condition: bool,
+    /// 出力する行と、その行の評価結果(評価結果が無い行はNone)
+    pub lines: Vec<(Option<bool>, String)>,
+}
+
+impl DebugRule {
+    /// ルールファイルとevtxファイル内の指定したレコードIDのレコードを読み込み、ルールの評価過程を出力する
+    pub fn run(rule_path: &Path, evtx_path: &Path, record_id: u64) -> Result<DebugRule, String> {
+        let rule_str = fs::read_to_string(rule_path).map_err(|e| {
+            format!(
+                "Failed to read the rule file. ({}) {}",
+                rule_path.display(),
+                e
+            )
+        })?;
+        let yaml = YamlLoader::load_from_str(&rule_str)
+            .map_err(|e| {
+                format!(
+                    "Failed to parse the rule file. ({}) {}",
+                    rule_path.display(),
+                    e
+                )
+            })?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format!("The rule file is empty. ({})", rule_path.display()))?;
+        let record = DebugRule::find_record(evtx_path, record_id)?;
+        let ret = DebugRule::debug(&rule_path.display().to_string(), yaml, record)?;
+        ret.output(record_id, evtx_path);
+        Ok(ret)
+    }
+
+    /// evtxファイルから指定したレコードIDのレコードを取得する
+    pub fn find_record(evtx_path: &Path, record_id: u64) -> Result<Value, String> {
+        let parser = EvtxParser::from_path(evtx_path).map_err(|e| {
+            format!(
+                "Failed to open the evtx file. ({}) {}",
+                evtx_path.display(),
+                e
+            )
+        })?;
+        let mut parser =
+            parser.with_configuration(ParserSettings::default().separate_json_attributes(true));
+        let record = parser
+            .records_json_value()
+            .filter_map(|record| record.ok())
+            .find(|record| record.event_record_id == record_id)
+            .map(|record| record.data)
+            .ok_or_else(|| {
+                format!(
+                    "The record ID {} was not found in {}.",
+                    record_id,
+                    evtx_path.display()
+                )
+            });
+        record
+    }
+
+    /// ルールを初期化してレコードを評価し、ノード毎の評価結果を出力用の行に変換する
+    pub fn debug(rulepath: &str, yaml: Yaml, record: Value) -> Result<DebugRule, String> {
+        let title = yaml["title"].as_str().unwrap_or("-").to_string();
+        let mut rule = create_rule(rulepath.to_string(), yaml);
+        rule.init().map_err(|err_msgs| err_msgs.join(" "))?;
+
+        let keys = get_detection_keys(&rule);
+        let record = utils::create_rec_info(record, rulepath.to_string(), &keys);
+        let trace = rule
+            .trace(&record)
+            .ok_or_else(|| "The rule does not have a condition.".to_string())?;
+
+        let mut lines = vec![];
+        DebugRule::format_trace(&trace, 0, &mut lines);
+        Ok(DebugRule {
+            title,
+            result: trace.result,
+            has_agg_condition: rule.has_agg_condition(),
+            lines,
+        })
+    }
+
+    /// ノードの評価結果を木構造のインデント付きの行に変換する
+    fn format_trace(trace: &SelectionTrace, depth: usize, lines: &mut Vec<(Option<bool>, String)>) {
+        let indent = "    ".repeat(depth);
+        let status = if trace.result { "MATCH" } else { "MISS" };
+        lines.push((
+            Some(trace.result),
+            format!("{}[{}] {}", indent, status, trace.label),
+        ));
+        for detail in trace.details.iter() {
+            lines.push((None, format!("{}    {}", indent, detail)));
+        }
+        for child in trace.children.iter() {
+            DebugRule::format_trace(child, depth + 1, lines);
+        }
+    }
+
+    /// 評価過程を標準出力に出力する
+    fn output(&self, record_id: u64, evtx_path: &Path) {
+        let stdout = BufferWriter::stdout(ColorChoice::Always);
+        write_color_buffer(
+            &stdout,
+            None,
+            &format!(
+                "Rule: {}\nRecord: {} ({})\n",
+                self.title,
+                record_id,
+                evtx_path.display()
+            ),
+            true,
+        )
+        .ok();
+        for (result, line) in self.lines.iter() {
+            let color = result.map(|result| if result { Color::Green } else { Color::Red });
+            write_color_buffer(&stdout, color, line, true).ok();
+        }
+        println!();
+        if self.has_agg_condition {
+            write_color_buffer(
+                &stdout,
+                None,
+                "The aggregation condition is not evaluated because only a single record is checked.",
+                true,
+            )
+            .ok();
+        }
+        write_color_buffer(
+            &stdout,
+            Some(if self.result {
+                Color::Green
+            } else {
+                Color::Red
+            }),
+            &format!("Result: {}", if self.result { "MATCH" } else { "NO MATCH" }),
+            true,
+        )
+        .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_rule() {
+        let rule = YamlLoader::load_from_str(
+            r#"
+title: Whoami
+detection:
+    selection:
+        Event.System.EventID: 1
+        Event.EventData.CommandLine|contains: whoami
+    condition: selection
+"#,
+        )
+        .unwrap()[0]
+            .clone();
+        let record: Value = serde_json::from_str(
+            r#"{"Event": {"System": {"EventID": 1}, "EventData": {"CommandLine": "ipconfig"}}}"#,
+        )
+        .unwrap();
+        let result = DebugRule::debug("debug_rule/whoami.yml", rule, record).unwrap();
+        assert_eq!(result.title, "Whoami");
+        assert!(!result.result);
+        assert!(!result.has_agg_condition);
+        let lines: Vec<&str> = result.lines.iter().map(|(_, l)| l.as_str()).collect();
+        assert_eq!(lines[0], "[MISS] selection");
+        assert_eq!(lines[1], "    [MISS] AND");
+        assert_eq!(lines[2], "        [MATCH] Event.System.EventID");
+        assert!(lines.contains(&"            value: ipconfig"));
+        assert!(lines.contains(&"            reason: the value does not match the pattern"));
+    }
+}
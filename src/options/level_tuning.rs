@@ -1,16 +1,52 @@
+use crate::detections::configs::CURRENT_EXE_PATH;
 use crate::detections::utils::write_color_buffer;
 use crate::detections::{configs, utils};
 use crate::filter::RuleExclude;
 use crate::yaml::ParseYaml;
 use hashbrown::HashMap;
+use std::fmt::Write as _;
 use std::fs::{self, File};
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use termcolor::{BufferWriter, ColorChoice};
+use yaml_rust::Yaml;
+
+/// --level-tuningで変更前のレベルを保存するファイル名。--level-tuningにこのファイルを指定すると元のレベルに戻せる
+const REVERT_FILE_NAME: &str = "level_tuning_revert.txt";
+
+/// --level-overlayでファイルの指定がない場合に読み込むファイル。--update-rulesで上書きされないようにrulesフォルダの外に置く
+const OVERLAY_FILE_PATH: &str = "config/level_tuning.txt";
 
 pub struct LevelTuning {}
 
 impl LevelTuning {
-    pub fn run(level_tuning_config_path: &str, rules_path: &str) -> Result<(), String> {
+    /// --level-tuningで指定されたファイルのパスを返す。ファイルの指定がない場合はrules/config/level_tuning.txtを返す
+    pub fn get_config_path(path: &Option<String>) -> String {
+        match path {
+            Some(path) => path.to_owned(),
+            _ => utils::get_rules_config_path("level_tuning.txt", "rules/config/level_tuning.txt")
+                .display()
+                .to_string(),
+        }
+    }
+
+    /// --level-overlayで指定されたファイルのパスを返す。ファイルの指定がない場合はconfig/level_tuning.txtを返す
+    pub fn get_overlay_path(path: &Option<String>) -> String {
+        match path {
+            Some(path) => path.to_owned(),
+            _ => {
+                utils::check_setting_path(&CURRENT_EXE_PATH.to_path_buf(), OVERLAY_FILE_PATH, true)
+                    .unwrap()
+                    .display()
+                    .to_string()
+            }
+        }
+    }
+
+    /// レベルチューニングのファイルを読み込み、ルールIDと変更後のレベルのマップを返す
+    pub fn read_tuning_file(
+        level_tuning_config_path: &str,
+    ) -> Result<HashMap<String, String>, String> {
         let read_result = utils::read_csv(level_tuning_config_path);
         if read_result.is_err() {
             return Result::Err(read_result.as_ref().unwrap_err().to_string());
@@ -45,6 +81,16 @@ impl LevelTuning {
             tuning_map.insert(id.to_string(), level.to_string());
             Ok(())
         })?;
+        Ok(tuning_map)
+    }
+
+    /// ルールファイルのレベルを書き換える。dry_runがtrueの場合は変更内容の表示のみ行い、ルールファイルは変更しない
+    pub fn run(
+        level_tuning_config_path: &str,
+        rules_path: &str,
+        dry_run: bool,
+    ) -> Result<(), String> {
+        let tuning_map = LevelTuning::read_tuning_file(level_tuning_config_path)?;
 
         // Read Rule files
         let mut rulefile_loader = ParseYaml::new();
@@ -56,6 +102,7 @@ impl LevelTuning {
         }
 
         // Convert rule files
+        let mut prev_levels = vec![];
         for (path, rule) in rulefile_loader.files {
             if let Some(new_level) = tuning_map.get(rule["id"].as_str().unwrap()) {
                 write_color_buffer(
@@ -65,35 +112,37 @@ impl LevelTuning {
                     true,
                 )
                 .ok();
-                let mut content = match fs::read_to_string(&path) {
-                    Ok(_content) => _content,
-                    Err(e) => return Result::Err(e.to_string()),
-                };
-                let past_level = "level: ".to_string() + rule["level"].as_str().unwrap();
-
-                if new_level.starts_with("informational") {
-                    content = content.replace(&past_level, "level: informational");
-                }
-                if new_level.starts_with("low") {
-                    content = content.replace(&past_level, "level: low");
-                }
-                if new_level.starts_with("medium") {
-                    content = content.replace(&past_level, "level: medium");
-                }
-                if new_level.starts_with("high") {
-                    content = content.replace(&past_level, "level: high");
-                }
-                if new_level.starts_with("critical") {
-                    content = content.replace(&past_level, "level: critical");
-                }
+                if !dry_run {
+                    let mut content = match fs::read_to_string(&path) {
+                        Ok(_content) => _content,
+                        Err(e) => return Result::Err(e.to_string()),
+                    };
+                    let past_level = "level: ".to_string() + rule["level"].as_str().unwrap();
+
+                    if new_level.starts_with("informational") {
+                        content = content.replace(&past_level, "level: informational");
+                    }
+                    if new_level.starts_with("low") {
+                        content = content.replace(&past_level, "level: low");
+                    }
+                    if new_level.starts_with("medium") {
+                        content = content.replace(&past_level, "level: medium");
+                    }
+                    if new_level.starts_with("high") {
+                        content = content.replace(&past_level, "level: high");
+                    }
+                    if new_level.starts_with("critical") {
+                        content = content.replace(&past_level, "level: critical");
+                    }
 
-                let mut file = match File::options().write(true).truncate(true).open(&path) {
-                    Ok(file) => file,
-                    Err(e) => return Result::Err(e.to_string()),
-                };
+                    let mut file = match File::options().write(true).truncate(true).open(&path) {
+                        Ok(file) => file,
+                        Err(e) => return Result::Err(e.to_string()),
+                    };
 
-                file.write_all(content.as_bytes()).unwrap();
-                file.flush().unwrap();
+                    file.write_all(content.as_bytes()).unwrap();
+                    file.flush().unwrap();
+                }
                 write_color_buffer(
                     &BufferWriter::stdout(ColorChoice::Always),
                     None,
@@ -105,11 +154,79 @@ impl LevelTuning {
                     true,
                 )
                 .ok();
+                prev_levels.push((
+                    rule["id"].as_str().unwrap().to_string(),
+                    rule["level"].as_str().unwrap().to_string(),
+                ));
             }
         }
         println!();
+        if dry_run {
+            println!(
+                "Dry run: {} rules would be changed. No rule files were modified.",
+                prev_levels.len()
+            );
+            println!();
+        } else if !prev_levels.is_empty() {
+            let revert_path =
+                LevelTuning::save_prev_levels(level_tuning_config_path, &prev_levels)?;
+            println!(
+                "Saved the original levels to {}. Run --level-tuning {} to restore them.",
+                revert_path.display(),
+                revert_path.display()
+            );
+            println!();
+        }
         Result::Ok(())
     }
+
+    /// 変更前のレベルをレベルチューニングのファイルと同じ形式でファイルに保存する。
+    /// 繰り返し実行しても最初の元のレベルに戻せるように、既に保存されているルールIDのレベルは上書きしない
+    fn save_prev_levels(
+        level_tuning_config_path: &str,
+        prev_levels: &[(String, String)],
+    ) -> Result<PathBuf, String> {
+        let revert_path = Path::new(level_tuning_config_path).with_file_name(REVERT_FILE_NAME);
+        let mut levels: Vec<(String, String)> = if revert_path.exists() {
+            utils::read_csv(&revert_path.display().to_string())?
+                .into_iter()
+                .filter_map(|line| Some((line.first()?.to_owned(), line.get(1)?.to_owned())))
+                .collect()
+        } else {
+            vec![]
+        };
+        for (id, level) in prev_levels {
+            if !levels.iter().any(|(saved_id, _)| saved_id == id) {
+                levels.push((id.to_owned(), level.to_owned()));
+            }
+        }
+        let mut content = "id,next_level\n".to_string();
+        for (id, level) in levels {
+            writeln!(content, "{},{}", id, level).ok();
+        }
+        fs::write(&revert_path, content).map_err(|e| {
+            format!(
+                "Failed to save the original levels. ({}) {}",
+                revert_path.display(),
+                e
+            )
+        })?;
+        Ok(revert_path)
+    }
+
+    /// --level-overlayで指定されたレベルをルールに適用する。ルールファイルは変更しない
+    pub fn apply_level(yaml: Yaml, level: &str) -> Yaml {
+        match yaml {
+            Yaml::Hash(mut hash) => {
+                hash.insert(
+                    Yaml::String("level".to_string()),
+                    Yaml::String(level.to_string()),
+                );
+                Yaml::Hash(hash)
+            }
+            _ => yaml,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -120,7 +237,7 @@ mod tests {
     #[test]
     fn rule_level_failed_to_open_file() -> Result<(), String> {
         let level_tuning_config_path = "./none.txt";
-        let res = LevelTuning::run(level_tuning_config_path, "");
+        let res = LevelTuning::run(level_tuning_config_path, "", false);
         let expected = Result::Err("Cannot open file. [file:./none.txt]".to_string());
         assert_eq!(res, expected);
         Ok(())
@@ -129,7 +246,7 @@ mod tests {
     #[test]
     fn rule_level_id_error_file() -> Result<(), String> {
         let level_tuning_config_path = "./test_files/config/level_tuning_error1.txt";
-        let res = LevelTuning::run(level_tuning_config_path, "");
+        let res = LevelTuning::run(level_tuning_config_path, "", false);
         let expected = Result::Err("Failed to read level tuning file. 12345678-1234-1234-1234-12 is not correct id format, fix it.".to_string());
         assert_eq!(res, expected);
         Ok(())
@@ -138,7 +255,7 @@ mod tests {
     #[test]
     fn rule_level_level_error_file() -> Result<(), String> {
         let level_tuning_config_path = "./test_files/config/level_tuning_error2.txt";
-        let res = LevelTuning::run(level_tuning_config_path, "");
+        let res = LevelTuning::run(level_tuning_config_path, "", false);
         let expected = Result::Err(
             "level tuning file's level must in informational, low, medium, high, critical"
                 .to_string(),
@@ -149,7 +266,16 @@ mod tests {
 
     #[test]
     fn test_level_tuning_update_rule_files() {
-        let level_tuning_config_path = "./test_files/config/level_tuning.txt";
+        let dir = std::env::temp_dir().join("hayabusa_test_level_tuning_update_rule_files");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let level_tuning_config_path = dir.join("level_tuning.txt");
+        fs::write(
+            &level_tuning_config_path,
+            "id,next_level\n12345678-1234-1234-1234-123456789012,high\n",
+        )
+        .unwrap();
+        let level_tuning_config_path = level_tuning_config_path.to_str().unwrap();
         let rule_str = r#"
         id: 12345678-1234-1234-1234-123456789012
         level: informational
@@ -160,16 +286,46 @@ mod tests {
         level: high
         "#;
 
-        let path = "test_files/rules/level_tuning_test.yml";
+        let path = dir.join("level_tuning_test.yml");
+        let path = path.to_str().unwrap();
         let mut file = File::create(path).unwrap();
         let buf = rule_str.as_bytes();
         file.write_all(buf).unwrap();
         file.flush().unwrap();
 
-        let res = LevelTuning::run(level_tuning_config_path, path);
+        // dry runではルールファイルは変更されない
+        let res = LevelTuning::run(level_tuning_config_path, path, true);
+        assert_eq!(res, Ok(()));
+        assert_eq!(fs::read_to_string(path).unwrap(), rule_str);
+
+        let res = LevelTuning::run(level_tuning_config_path, path, false);
         assert_eq!(res, Ok(()));
 
         assert_eq!(fs::read_to_string(path).unwrap(), expected_rule);
-        fs::remove_file(path).unwrap();
+
+        // 再度実行しても最初の元のレベルは上書きされない
+        let res = LevelTuning::run(level_tuning_config_path, path, false);
+        assert_eq!(res, Ok(()));
+        let revert_path = dir.join("level_tuning_revert.txt");
+        let revert_path = revert_path.to_str().unwrap();
+        assert_eq!(
+            fs::read_to_string(revert_path).unwrap(),
+            "id,next_level\n12345678-1234-1234-1234-123456789012,informational\n"
+        );
+
+        // 保存された変更前のレベルで元に戻せる
+        let res = LevelTuning::run(revert_path, path, false);
+        assert_eq!(res, Ok(()));
+        assert_eq!(fs::read_to_string(path).unwrap(), rule_str);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_apply_level() {
+        let rule =
+            yaml_rust::YamlLoader::load_from_str("id: test\nlevel: low\n").unwrap()[0].clone();
+        let rule = LevelTuning::apply_level(rule, "high");
+        assert_eq!(rule["level"].as_str().unwrap(), "high");
+        assert_eq!(rule["id"].as_str().unwrap(), "test");
     }
 }
@@ -0,0 +1,335 @@
+use crate::detections::rule::{create_rule, get_rule_targets};
+use crate::filter::{is_match_tag, RuleExclude};
+use crate::yaml::ParseYaml;
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::{ContentArrangement, Table};
+use csv::WriterBuilder;
+use regex::Regex;
+use serde_json::json;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use yaml_rust::Yaml;
+
+/// 一覧に出力するルールの情報
+#[derive(Debug, Clone)]
+pub struct RuleInfo {
+    pub id: String,
+    pub title: String,
+    pub level: String,
+    pub status: String,
+    pub author: String,
+    pub tags: Vec<String>,
+    /// conditionから判定したルールの対象のChannel。制約がない場合は空
+    pub channels: Vec<String>,
+    /// conditionから判定したルールの対象のEventID。制約がない場合は空
+    pub eventids: Vec<String>,
+    pub rulepath: String,
+}
+
+impl RuleInfo {
+    pub fn new(rulepath: &str, yaml: &Yaml) -> RuleInfo {
+        let get_str = |key: &str| yaml[key].as_str().unwrap_or("-").to_string();
+        let mut rule = create_rule(rulepath.to_string(), yaml.clone());
+        let (mut channels, mut eventids) = (vec![], vec![]);
+        // 読み込みに失敗したルールも一覧には表示するため、エラーの場合は対象のChannelとEventIDを空とする
+        if rule.init().is_ok() {
+            let targets = get_rule_targets(&rule);
+            channels = targets.channels.unwrap_or_default().into_iter().collect();
+            eventids = targets.eventids.unwrap_or_default().into_iter().collect();
+            channels.sort();
+            eventids.sort();
+        }
+        RuleInfo {
+            id: get_str("id"),
+            title: get_str("title"),
+            level: get_str("level"),
+            status: get_str("status"),
+            author: get_str("author"),
+            tags: yaml["tags"]
+                .as_vec()
+                .map(|tags| {
+                    tags.iter()
+                        .filter_map(|tag| tag.as_str().map(|s| s.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            channels,
+            eventids,
+            rulepath: rulepath.to_string(),
+        }
+    }
+
+    /// 出力する各列の値。値が複数ある列は"¦"で連結する
+    fn to_columns(&self) -> Vec<String> {
+        let join = |values: &[String]| {
+            if values.is_empty() {
+                "-".to_string()
+            } else {
+                values.join(" ¦ ")
+            }
+        };
+        vec![
+            self.id.to_owned(),
+            self.title.to_owned(),
+            self.level.to_owned(),
+            self.status.to_owned(),
+            self.author.to_owned(),
+            join(&self.channels),
+            join(&self.eventids),
+            join(&self.tags),
+            self.rulepath.to_owned(),
+        ]
+    }
+}
+
+/// --list-rulesで指定する絞り込み条件。同じ項目の条件はOR、異なる項目の条件はANDで判定する
+#[derive(Debug, Default)]
+pub struct RuleListFilter {
+    tags: Vec<String>,
+    levels: Vec<String>,
+    statuses: Vec<String>,
+    channels: Vec<String>,
+    eventids: Vec<String>,
+    authors: Vec<String>,
+    titles: Vec<Regex>,
+}
+
+impl RuleListFilter {
+    /// "tag:attack.t1003"のような"<項目>:<値>"形式の条件をパースする
+    pub fn parse(filters: &[String]) -> Result<RuleListFilter, String> {
+        let mut ret = RuleListFilter::default();
+        for filter in filters {
+            let (key, value) = filter.split_once(':').ok_or_else(|| {
+                format!(
+                    "Invalid filter: {}. Please specify the filter as <field>:<value>.",
+                    filter
+                )
+            })?;
+            let value = value.trim().to_string();
+            match key.trim().to_lowercase().as_str() {
+                "tag" => ret.tags.push(value.to_lowercase()),
+                "level" => ret.levels.push(value.to_lowercase()),
+                "status" => ret.statuses.push(value.to_lowercase()),
+                "channel" => ret.channels.push(value.to_lowercase()),
+                "eid" | "eventid" => ret.eventids.push(value),
+                "author" => ret.authors.push(value.to_lowercase()),
+                "title" => ret.titles.push(
+                    Regex::new(&value)
+                        .map_err(|e| format!("Invalid title regex: {}. {}", value, e))?,
+                ),
+                _ => {
+                    return Err(format!(
+                        "Unknown filter field: {}. Available fields are tag, level, status, channel, eid, author and title.",
+                        key
+                    ))
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    pub fn is_match(&self, info: &RuleInfo) -> bool {
+        let match_tag = |filter: &String| info.tags.iter().any(|tag| is_match_tag(tag, filter));
+        let match_any = |filters: &[String], pred: &dyn Fn(&String) -> bool| {
+            filters.is_empty() || filters.iter().any(pred)
+        };
+        match_any(&self.tags, &match_tag)
+            && match_any(&self.levels, &|f| info.level.to_lowercase() == *f)
+            && match_any(&self.statuses, &|f| info.status.to_lowercase() == *f)
+            && match_any(&self.channels, &|f| {
+                info.channels.iter().any(|c| c.to_lowercase().contains(f))
+            })
+            && match_any(&self.eventids, &|f| info.eventids.contains(f))
+            && match_any(&self.authors, &|f| info.author.to_lowercase().contains(f))
+            && (self.titles.is_empty() || self.titles.iter().any(|re| re.is_match(&info.title)))
+    }
+}
+
+const HEADERS: [&str; 9] = [
+    "ID", "Title", "Level", "Status", "Author", "Channel", "EventID", "Tags", "RulePath",
+];
+
+/// --list-rulesで読み込んだルールを絞り込んで一覧を出力する構造体
+#[derive(Debug, Default)]
+pub struct ListRules {
+    pub rules: Vec<RuleInfo>,
+}
+
+impl ListRules {
+    pub fn run(rules_path: &[PathBuf], filters: &[String]) -> Result<ListRules, String> {
+        let filter = RuleListFilter::parse(filters)?;
+        let mut rulefile_loader = ParseYaml::new();
+        rulefile_loader
+            .read_rules(rules_path, "informational", &RuleExclude::default())
+            .map_err(|e| e.to_string())?;
+        let mut rules: Vec<RuleInfo> = rulefile_loader
+            .files
+            .iter()
+            .map(|(rulepath, yaml)| RuleInfo::new(rulepath, yaml))
+            .filter(|info| filter.is_match(info))
+            .collect();
+        rules.sort_by(|a, b| a.rulepath.cmp(&b.rulepath));
+        Ok(ListRules { rules })
+    }
+
+    /// ルールの一覧を表形式で標準出力に出力する
+    pub fn output_table(&self) {
+        let mut tb = Table::new();
+        tb.load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(HEADERS.to_vec());
+        for info in self.rules.iter() {
+            tb.add_row(info.to_columns());
+        }
+        println!("{tb}");
+        println!("Rules: {}", self.rules.len());
+    }
+
+    /// ルールの一覧をCSV形式で出力する
+    pub fn output_csv<W: Write>(&self, writer: W) -> Result<(), String> {
+        let mut wtr = WriterBuilder::new().from_writer(writer);
+        wtr.write_record(HEADERS).map_err(|e| e.to_string())?;
+        for info in self.rules.iter() {
+            wtr.write_record(info.to_columns())
+                .map_err(|e| e.to_string())?;
+        }
+        wtr.flush().map_err(|e| e.to_string())
+    }
+
+    /// ルールの一覧をJSON形式(jsonlがtrueの場合はJSONL形式)で出力する
+    pub fn output_json<W: Write>(&self, mut writer: W, jsonl: bool) -> Result<(), String> {
+        let values: Vec<serde_json::Value> = self
+            .rules
+            .iter()
+            .map(|info| {
+                json!({
+                    "ID": info.id,
+                    "Title": info.title,
+                    "Level": info.level,
+                    "Status": info.status,
+                    "Author": info.author,
+                    "Channel": info.channels,
+                    "EventID": info.eventids,
+                    "Tags": info.tags,
+                    "RulePath": info.rulepath,
+                })
+            })
+            .collect();
+        let output = if jsonl {
+            values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<String>>()
+                .join("\n")
+        } else {
+            serde_json::to_string_pretty(&values).map_err(|e| e.to_string())?
+        };
+        writeln!(writer, "{}", output).map_err(|e| e.to_string())
+    }
+
+    /// 出力先のファイルが指定されている場合はCSVもしくはJSON形式でファイルに、指定されていない場合は表形式で標準出力に出力する
+    pub fn output(
+        &self,
+        output_path: Option<&Path>,
+        json: bool,
+        jsonl: bool,
+    ) -> Result<(), String> {
+        match output_path {
+            Some(path) => {
+                let file = File::create(path).map_err(|e| {
+                    format!("Failed to create the file. ({}) {}", path.display(), e)
+                })?;
+                let writer = BufWriter::new(file);
+                if json || jsonl {
+                    self.output_json(writer, jsonl)?;
+                } else {
+                    self.output_csv(writer)?;
+                }
+                println!("Saved {} rules to {}", self.rules.len(), path.display());
+                Ok(())
+            }
+            None => {
+                self.output_table();
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn create_info(rule_str: &str) -> RuleInfo {
+        let yaml = YamlLoader::load_from_str(rule_str).unwrap()[0].clone();
+        RuleInfo::new("list_rules/test.yml", &yaml)
+    }
+
+    #[test]
+    fn test_rule_list_filter() {
+        let info = create_info(
+            r#"
+title: Mimikatz Credential Dumping
+id: 0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e
+level: high
+status: test
+author: Yamato Security
+tags:
+    - attack.credential_access
+    - attack.t1003.001
+detection:
+    selection:
+        Event.System.Channel: Microsoft-Windows-Sysmon/Operational
+        Event.System.EventID: 1
+        Event.EventData.CommandLine|contains: sekurlsa
+    condition: selection
+"#,
+        );
+        assert_eq!(info.eventids, vec!["1"]);
+        assert_eq!(info.channels, vec!["microsoft-windows-sysmon/operational"]);
+
+        let is_match = |filters: &[&str]| {
+            let filters: Vec<String> = filters.iter().map(|f| f.to_string()).collect();
+            RuleListFilter::parse(&filters).unwrap().is_match(&info)
+        };
+        assert!(is_match(&[]));
+        assert!(is_match(&["tag:T1003"]));
+        assert!(is_match(&["tag:attack.credential_access"]));
+        assert!(!is_match(&["tag:t1003.002"]));
+        assert!(is_match(&["channel:sysmon", "eid:1"]));
+        assert!(!is_match(&["channel:sysmon", "eid:4688"]));
+        assert!(is_match(&["status:test", "author:yamato"]));
+        assert!(is_match(&["level:critical", "level:high"]));
+        assert!(is_match(&["title:^Mimikatz"]));
+        assert!(!is_match(&["title:^Credential"]));
+
+        assert!(RuleListFilter::parse(&["tag".to_string()]).is_err());
+        assert!(RuleListFilter::parse(&["unknown:1".to_string()]).is_err());
+        assert!(RuleListFilter::parse(&["title:(".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_output_csv_and_json() {
+        let list = ListRules {
+            rules: vec![create_info(
+                "title: Logon\nid: abc\nlevel: low\ndetection:\n    selection:\n        Event.System.EventID: 4624\n    condition: selection\n",
+            )],
+        };
+        let mut csv = vec![];
+        list.output_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "ID,Title,Level,Status,Author,Channel,EventID,Tags,RulePath\nabc,Logon,low,-,-,-,4624,-,list_rules/test.yml\n"
+        );
+
+        let mut jsonl = vec![];
+        list.output_json(&mut jsonl, true).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&jsonl).unwrap();
+        assert_eq!(value["EventID"], json!(["4624"]));
+        assert_eq!(value["Channel"], json!([]));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::detections::utils;
+use hashbrown::HashMap;
+use std::path::Path;
+use yaml_rust::Yaml;
+
+/// logsourceから追加する条件のselection名
+pub const LOGSOURCE_SELECTION_NAME: &str = "hayabusa_logsource";
+
+/// エラーメッセージに表示するファイルの種類
+const LOGSOURCE_MAPPING_FILE_KIND: &str = "logsource mapping";
+
+/// logsourceの項目
+const LOGSOURCE_FIELDS: [&str; 3] = ["product", "service", "category"];
+
+/// logsource_mapping.yamlの1つの定義
+#[derive(Debug, Clone)]
+struct LogsourceMappingEntry {
+    /// 小文字に変換したlogsourceの項目と値
+    logsource: Vec<(String, String)>,
+    /// ルールに追加するChannelやEventIDの条件
+    conditions: Yaml,
+    /// Sigmaのフィールド名とeventkey_alias.txtのエイリアス(もしくはフルパス)の対応
+    fieldmappings: HashMap<String, String>,
+}
+
+/// --logsource-mappingでChannelの条件が無いSigmaルールのlogsourceをChannelとEventIDの条件に変換する設定
+#[derive(Debug, Default)]
+pub struct LogsourceMapping {
+    entries: Vec<LogsourceMappingEntry>,
+}
+
+impl LogsourceMapping {
+    /// --logsource-mappingで指定されたファイルのパスを返す。ファイルの指定がない場合はrules/config/logsource_mapping.yamlを返し、
+    /// 存在しない場合は同梱しているconfig/logsource_mapping.yamlを返す
+    pub fn get_config_path(path: &Option<String>) -> String {
+        match path {
+            Some(path) => path.to_owned(),
+            _ => utils::get_rules_config_path(
+                "logsource_mapping.yaml",
+                "config/logsource_mapping.yaml",
+            )
+            .display()
+            .to_string(),
+        }
+    }
+
+    /// logsource_mapping.yamlを読み込む
+    pub fn load(path: &str) -> Result<LogsourceMapping, String> {
+        let content = utils::read_config_file(Path::new(path), LOGSOURCE_MAPPING_FILE_KIND)?;
+        LogsourceMapping::parse(&content).map_err(|e| format!("{} ({})", e, path))
+    }
+
+    /// 定義の一覧をパースする。各定義にはlogsourceの項目と追加する条件が必須
+    pub fn parse(content: &str) -> Result<LogsourceMapping, String> {
+        let mut ret = LogsourceMapping::default();
+        let entries = utils::parse_yaml_list(content, LOGSOURCE_MAPPING_FILE_KIND)?;
+        for (i, entry) in entries.iter().enumerate() {
+            let logsource: Vec<(String, String)> = LOGSOURCE_FIELDS
+                .iter()
+                .filter_map(|field| {
+                    entry["logsource"][*field]
+                        .as_str()
+                        .map(|value| (field.to_string(), value.to_lowercase()))
+                })
+                .collect();
+            if logsource.is_empty() {
+                return Err(format!(
+                    "The mapping #{} does not have a product, service or category in logsource.",
+                    i + 1
+                ));
+            }
+            if !matches!(entry["conditions"].as_hash(), Some(h) if !h.is_empty()) {
+                return Err(format!(
+                    "The mapping #{} does not have any conditions.",
+                    i + 1
+                ));
+            }
+            let fieldmappings = entry["fieldmappings"]
+                .as_hash()
+                .map(|fields| {
+                    fields
+                        .iter()
+                        .filter_map(|(k, v)| {
+                            Some((k.as_str()?.to_string(), v.as_str()?.to_string()))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            ret.entries.push(LogsourceMappingEntry {
+                logsource,
+                conditions: entry["conditions"].clone(),
+                fieldmappings,
+            });
+        }
+        Ok(ret)
+    }
+
+    /// Channelの条件が無いルールに、logsourceに一致する定義の条件を追加してフィールド名を変換する。
+    /// 変換した場合はtrueを返す
+    pub fn apply(&self, yaml: Yaml) -> (Yaml, bool) {
+        if LogsourceMapping::has_channel_condition(&yaml["detection"]) {
+            return (yaml, false);
+        }
+        let entry = match self.find_entry(&yaml["logsource"]) {
+            Some(entry) => entry,
+            None => return (yaml, false),
+        };
+        let mut rule = match yaml {
+            Yaml::Hash(rule) => rule,
+            _ => return (yaml, false),
+        };
+        let detection_key = Yaml::String("detection".to_string());
+        let mut detection = match rule.get(&detection_key) {
+            Some(Yaml::Hash(detection)) => detection.clone(),
+            _ => return (Yaml::Hash(rule), false),
+        };
+        for (name, selection) in detection.iter_mut() {
+            if !matches!(name.as_str(), Some("condition") | Some("timeframe")) {
+                *selection = LogsourceMapping::map_fields(selection, &entry.fieldmappings);
+            }
+        }
+        let condition_key = Yaml::String("condition".to_string());
+        let condition = match detection.get(&condition_key) {
+            Some(Yaml::String(condition)) => {
+                Yaml::String(LogsourceMapping::wrap_condition(condition))
+            }
+            Some(Yaml::Array(conditions)) => Yaml::Array(
+                conditions
+                    .iter()
+                    .map(|c| {
+                        Yaml::String(LogsourceMapping::wrap_condition(
+                            c.as_str().unwrap_or_default(),
+                        ))
+                    })
+                    .collect(),
+            ),
+            _ => return (Yaml::Hash(rule), false),
+        };
+        detection.insert(condition_key, condition);
+        detection.insert(
+            Yaml::String(LOGSOURCE_SELECTION_NAME.to_string()),
+            entry.conditions.clone(),
+        );
+        rule.insert(detection_key, Yaml::Hash(detection));
+        (Yaml::Hash(rule), true)
+    }
+
+    /// ルールのlogsourceに一致する定義のうち、最も多くの項目が一致する定義を返す
+    fn find_entry(&self, logsource: &Yaml) -> Option<&LogsourceMappingEntry> {
+        let mut ret: Option<&LogsourceMappingEntry> = None;
+        for entry in self.entries.iter() {
+            let is_match = entry.logsource.iter().all(|(field, value)| {
+                logsource[field.as_str()]
+                    .as_str()
+                    .map(|v| v.to_lowercase() == *value)
+                    .unwrap_or(false)
+            });
+            let is_more_specific = match ret {
+                Some(r) => r.logsource.len() < entry.logsource.len(),
+                None => true,
+            };
+            if is_match && is_more_specific {
+                ret = Some(entry);
+            }
+        }
+        ret
+    }
+
+    /// detection内にChannelの条件があるかを判定する
+    fn has_channel_condition(detection: &Yaml) -> bool {
+        match detection {
+            Yaml::Hash(hash) => hash.iter().any(|(key, value)| {
+                let field = key
+                    .as_str()
+                    .unwrap_or_default()
+                    .split('|')
+                    .next()
+                    .unwrap_or_default();
+                field == "Channel"
+                    || field == "Event.System.Channel"
+                    || LogsourceMapping::has_channel_condition(value)
+            }),
+            Yaml::Array(values) => values.iter().any(LogsourceMapping::has_channel_condition),
+            _ => false,
+        }
+    }
+
+    /// selection内のフィールド名をfieldmappingsに従って変換する。"|"以降の修飾子はそのまま残す
+    fn map_fields(selection: &Yaml, fieldmappings: &HashMap<String, String>) -> Yaml {
+        match selection {
+            Yaml::Hash(hash) => Yaml::Hash(
+                hash.iter()
+                    .map(|(key, value)| {
+                        let key = match key.as_str() {
+                            Some(key_str) => {
+                                let (field, modifiers) = match key_str.split_once('|') {
+                                    Some((field, modifiers)) => (field, format!("|{}", modifiers)),
+                                    None => (key_str, String::default()),
+                                };
+                                match fieldmappings.get(field) {
+                                    Some(mapped) => {
+                                        Yaml::String(format!("{}{}", mapped, modifiers))
+                                    }
+                                    None => key.clone(),
+                                }
+                            }
+                            None => key.clone(),
+                        };
+                        (key, value.clone())
+                    })
+                    .collect(),
+            ),
+            Yaml::Array(values) => Yaml::Array(
+                values
+                    .iter()
+                    .map(|value| LogsourceMapping::map_fields(value, fieldmappings))
+                    .collect(),
+            ),
+            _ => selection.clone(),
+        }
+    }
+
+    /// conditionにlogsourceのselectionの条件を追加する。aggregation conditionは"|"以降に残す
+    fn wrap_condition(condition: &str) -> String {
+        match condition.split_once('|') {
+            Some((condition, aggregation)) => format!(
+                "{} and ({}) |{}",
+                LOGSOURCE_SELECTION_NAME,
+                condition.trim(),
+                aggregation
+            ),
+            None => format!("{} and ({})", LOGSOURCE_SELECTION_NAME, condition.trim()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detections::rule::{create_rule, get_detection_keys};
+    use crate::detections::utils;
+    use serde_json::Value;
+    use yaml_rust::YamlLoader;
+
+    const MAPPING: &str = r#"
+- logsource:
+      product: windows
+      category: process_creation
+  conditions:
+      Event.System.Channel: Microsoft-Windows-Sysmon/Operational
+      Event.System.EventID: 1
+- logsource:
+      product: windows
+      service: security
+      category: process_creation
+  conditions:
+      Event.System.Channel: Security
+      Event.System.EventID: 4688
+  fieldmappings:
+      Image: Event.EventData.NewProcessName
+- logsource:
+      product: windows
+  conditions:
+      Event.System.Channel: Application
+"#;
+
+    fn load_rule(rule_str: &str) -> Yaml {
+        YamlLoader::load_from_str(rule_str).unwrap()[0].clone()
+    }
+
+    fn is_match(rule: Yaml, record: &str) -> bool {
+        let mut rule = create_rule("sigma.yml".to_string(), rule);
+        assert!(rule.init().is_ok());
+        let keys = get_detection_keys(&rule);
+        let record: Value = serde_json::from_str(record).unwrap();
+        let record = utils::create_rec_info(record, "test.evtx".to_string(), &keys);
+        rule.select(&record)
+    }
+
+    #[test]
+    fn test_apply_logsource_mapping() {
+        let mapping = LogsourceMapping::parse(MAPPING).unwrap();
+        let rule = load_rule(
+            r#"
+title: Whoami
+logsource:
+    product: windows
+    category: process_creation
+detection:
+    selection:
+        Event.EventData.Image|endswith: '\whoami.exe'
+    condition: selection
+"#,
+        );
+        let (rule, applied) = mapping.apply(rule);
+        assert!(applied);
+        assert_eq!(
+            rule["detection"]["condition"].as_str().unwrap(),
+            "hayabusa_logsource and (selection)"
+        );
+        let sysmon = r#"{"Event": {"System": {"Channel": "Microsoft-Windows-Sysmon/Operational", "EventID": 1}, "EventData": {"Image": "C:\\Windows\\System32\\whoami.exe"}}}"#;
+        let security = r#"{"Event": {"System": {"Channel": "Security", "EventID": 1}, "EventData": {"Image": "C:\\Windows\\System32\\whoami.exe"}}}"#;
+        assert!(is_match(rule.clone(), sysmon));
+        assert!(!is_match(rule, security));
+
+        // 最も多くの項目が一致する定義のフィールド名の変換が適用され、aggregation conditionは維持される
+        let rule = load_rule(
+            r#"
+title: Whoami
+logsource:
+    product: windows
+    service: Security
+    category: process_creation
+detection:
+    selection:
+        Image|endswith: '\whoami.exe'
+    condition: selection | count() > 1
+"#,
+        );
+        let (rule, applied) = mapping.apply(rule);
+        assert!(applied);
+        assert_eq!(
+            rule["detection"]["condition"].as_str().unwrap(),
+            "hayabusa_logsource and (selection) | count() > 1"
+        );
+        assert_eq!(
+            rule["detection"]["selection"]["Event.EventData.NewProcessName|endswith"]
+                .as_str()
+                .unwrap(),
+            "\\whoami.exe"
+        );
+        assert_eq!(
+            rule["detection"][LOGSOURCE_SELECTION_NAME]["Event.System.EventID"]
+                .as_i64()
+                .unwrap(),
+            4688
+        );
+
+        // Channelの条件があるルールとlogsourceが一致しないルールは変換しない
+        let rule = load_rule(
+            "logsource:\n    product: windows\ndetection:\n    selection:\n        - Channel: System\n    condition: selection\n",
+        );
+        assert!(!mapping.apply(rule).1);
+        let rule = load_rule(
+            "logsource:\n    product: linux\ndetection:\n    selection:\n        EventID: 1\n    condition: selection\n",
+        );
+        assert!(!mapping.apply(rule).1);
+    }
+
+    #[test]
+    fn test_load_default_logsource_mapping() {
+        let mapping = LogsourceMapping::load("./config/logsource_mapping.yaml").unwrap();
+        assert!(!mapping.entries.is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid_logsource_mapping() {
+        assert!(LogsourceMapping::parse("product: windows").is_err());
+        assert!(LogsourceMapping::parse("- logsource:\n      product: windows\n").is_err());
+        assert!(LogsourceMapping::parse("- conditions:\n      Channel: Security\n").is_err());
+        assert!(LogsourceMapping::parse("").unwrap().entries.is_empty());
+        assert!(LogsourceMapping::load("test_files/config/logsource_mapping.yaml").is_ok());
+        assert!(LogsourceMapping::load("test_files/config/not_exist.yaml").is_err());
+    }
+}
@@ -1,3 +1,14 @@
+pub mod debug_rule;
 pub mod level_tuning;
+pub mod list_rules;
+pub mod logsource_mapping;
 pub mod profile;
+pub mod rule_cache;
+pub mod rule_overrides;
+pub mod rule_profiling;
+pub mod rule_template;
+pub mod suppression_list;
+pub mod test_rules;
+pub mod throttle;
 pub mod update_rules;
+pub mod validate_rules;
@@ -48,9 +48,11 @@ lazy_static! {
         "%RecordInformation%",
         "%RuleFile%",
         "%EvtxFile%",
+        "%RulesRevision%",
         "%MitreTactics%",
         "%MitreTags%",
-        "%OtherTags%"
+        "%OtherTags%",
+        "%MatchedFields%"
     ];
     pub static ref PRELOAD_PROFILE_REGEX: RegexSet = RegexSet::new(&*PRELOAD_PROFILE).unwrap();
 }
@@ -121,6 +123,9 @@ pub fn load_profile(
                         v.as_str().unwrap().to_string(),
                     );
                 });
+            if conf.explain {
+                add_matched_fields_column(&mut ret);
+            }
             Some(ret)
         } else {
             let profile_names: Vec<&str> = profile_data
@@ -148,10 +153,20 @@ pub fn load_profile(
                     v.as_str().unwrap().to_string(),
                 );
             });
+        if conf.explain {
+            add_matched_fields_column(&mut ret);
+        }
         Some(ret)
     }
 }
 
+/// --explainが指定された場合に、プロファイルに%MatchedFields%が含まれていなければMatchedFieldsの列を追加する関数
+fn add_matched_fields_column(profile: &mut LinkedHashMap<String, String>) {
+    if !profile.values().any(|v| v.contains("%MatchedFields%")) {
+        profile.insert("MatchedFields".to_string(), "%MatchedFields%".to_string());
+    }
+}
+
 /// デフォルトプロファイルを設定する関数
 pub fn set_default_profile(default_profile_path: &str, profile_path: &str) -> Result<(), String> {
     let profile_data: Vec<Yaml> = match read_profile_data(profile_path) {
@@ -0,0 +1,306 @@
+use hashbrown::HashMap;
+use linked_hash_map::LinkedHashMap;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use yaml_rust::{ScanError, Yaml, YamlLoader};
+
+/// キャッシュファイルの形式を変更した場合はこの値を変更する
+const CACHE_FORMAT_VERSION: u64 = 3;
+
+/// 1ルールファイル分のキャッシュ
+#[derive(Debug, Clone)]
+struct RuleCacheEntry {
+    hash: String,
+    docs: Vec<Yaml>,
+}
+
+/// パース済みのルールファイル(YAML)をディスク上に保存するキャッシュ。
+/// 更新日時やサイズが同じまま内容が変わった場合に古いルールを使わないように、ファイルパスとファイル内容のSHA256が一致した場合のみキャッシュを利用する。
+/// hayabusaのバージョンもしくはルールのconfigフォルダ内のファイルが変更された場合はキャッシュ全体を破棄する。
+///
+/// RuleNodeの作成(正規表現のコンパイル)はシリアライズできず、configフォルダの内容や実行時のオプションにも依存するため、
+/// キャッシュするのはYAMLのパース結果のみとし、RuleNodeの作成は毎回行う。
+pub struct RuleCache {
+    path: PathBuf,
+    fingerprint: String,
+    entries: HashMap<String, RuleCacheEntry>,
+    updated: bool,
+    pub hit_count: u128,
+    pub miss_count: u128,
+}
+
+impl RuleCache {
+    /// キャッシュファイルを読み込む。読み込みに失敗した場合や無効なキャッシュの場合は空のキャッシュを返す
+    pub fn load(path: &Path, config_dir: &Path) -> RuleCache {
+        let fingerprint = RuleCache::create_fingerprint(config_dir);
+        let mut cache = RuleCache {
+            path: path.to_path_buf(),
+            fingerprint,
+            entries: HashMap::new(),
+            updated: false,
+            hit_count: 0,
+            miss_count: 0,
+        };
+        let cache_json: Option<Value> = File::open(path)
+            .ok()
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).ok());
+        let cache_json = match cache_json {
+            Some(v) => v,
+            None => return cache,
+        };
+        if cache_json["format"].as_u64() != Some(CACHE_FORMAT_VERSION)
+            || cache_json["fingerprint"].as_str() != Some(&cache.fingerprint)
+        {
+            return cache;
+        }
+        if let Some(rules) = cache_json["rules"].as_object() {
+            for (rulepath, entry) in rules {
+                let docs: Option<Vec<Yaml>> = entry["docs"]
+                    .as_array()
+                    .and_then(|docs| docs.iter().map(json_to_yaml).collect());
+                if let (Some(hash), Some(docs)) = (entry["hash"].as_str(), docs) {
+                    cache.entries.insert(
+                        rulepath.to_owned(),
+                        RuleCacheEntry {
+                            hash: hash.to_string(),
+                            docs,
+                        },
+                    );
+                }
+            }
+        }
+        cache
+    }
+
+    /// ルールファイルの内容をYAMLとしてパースする。内容のハッシュがキャッシュと一致する場合はキャッシュの内容を返す
+    pub fn load_yaml_docs(&mut self, path: &Path, content: &str) -> Result<Vec<Yaml>, ScanError> {
+        let key = path.display().to_string();
+        let hash = hex::encode(Sha256::digest(content.as_bytes()));
+        if let Some(entry) = self.entries.get(&key) {
+            if entry.hash == hash {
+                self.hit_count += 1;
+                return Ok(entry.docs.clone());
+            }
+        }
+
+        self.miss_count += 1;
+        let docs = YamlLoader::load_from_str(content)?;
+        self.entries.insert(
+            key,
+            RuleCacheEntry {
+                hash,
+                docs: docs.clone(),
+            },
+        );
+        self.updated = true;
+        Ok(docs)
+    }
+
+    /// キャッシュをファイルに保存する。存在しなくなったルールファイルのキャッシュは削除する。
+    /// バンドル内のファイルは"<バンドルのパス>/<バンドル内のパス>"で保存しているため、パスの親にファイル(バンドル)が存在する場合は残す
+    pub fn save(&mut self) -> Result<(), String> {
+        let before_cnt = self.entries.len();
+        self.entries.retain(|rulepath, _| {
+            Path::new(rulepath)
+                .ancestors()
+                .any(|ancestor| ancestor.is_file())
+        });
+        if !self.updated && before_cnt == self.entries.len() {
+            return Ok(());
+        }
+
+        let mut rules = Map::new();
+        for (rulepath, entry) in self.entries.iter() {
+            rules.insert(
+                rulepath.to_owned(),
+                json!({
+                    "hash": entry.hash,
+                    "docs": entry.docs.iter().map(yaml_to_json).collect::<Vec<Value>>(),
+                }),
+            );
+        }
+        let cache_json = json!({
+            "format": CACHE_FORMAT_VERSION,
+            "fingerprint": self.fingerprint,
+            "rules": rules,
+        });
+
+        if let Some(parent) = self.path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+        }
+        let mut wtr = BufWriter::new(File::create(&self.path).map_err(|e| e.to_string())?);
+        serde_json::to_writer(&mut wtr, &cache_json).map_err(|e| e.to_string())?;
+        wtr.flush().map_err(|e| e.to_string())?;
+        self.updated = false;
+        Ok(())
+    }
+
+    /// hayabusaのバージョンとconfigフォルダ内の各ファイルの内容からキャッシュの有効性を判定するための値を作成する
+    fn create_fingerprint(config_dir: &Path) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+        let mut config_files: Vec<PathBuf> = fs::read_dir(config_dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|p| p.is_file())
+                    .collect()
+            })
+            .unwrap_or_default();
+        config_files.sort();
+        for config_file in config_files {
+            hasher.update(config_file.display().to_string().as_bytes());
+            hasher.update(fs::read(&config_file).unwrap_or_default());
+        }
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// YamlをJSONに変換する。YAMLのパース結果と完全に一致するように復元できる形式にする
+fn yaml_to_json(yaml: &Yaml) -> Value {
+    match yaml {
+        Yaml::Real(s) => json!({ "real": s }),
+        Yaml::Integer(i) => json!(i),
+        Yaml::String(s) => json!(s),
+        Yaml::Boolean(b) => json!(b),
+        Yaml::Array(arr) => Value::Array(arr.iter().map(yaml_to_json).collect()),
+        Yaml::Hash(hash) => json!({
+            "hash": hash
+                .iter()
+                .map(|(k, v)| json!([yaml_to_json(k), yaml_to_json(v)]))
+                .collect::<Vec<Value>>()
+        }),
+        Yaml::Alias(i) => json!({ "alias": i }),
+        Yaml::Null => Value::Null,
+        Yaml::BadValue => json!({ "badvalue": null }),
+    }
+}
+
+/// yaml_to_jsonで変換したJSONをYamlに戻す
+fn json_to_yaml(value: &Value) -> Option<Yaml> {
+    match value {
+        Value::Null => Some(Yaml::Null),
+        Value::Bool(b) => Some(Yaml::Boolean(*b)),
+        Value::Number(n) => n.as_i64().map(Yaml::Integer),
+        Value::String(s) => Some(Yaml::String(s.to_owned())),
+        Value::Array(arr) => arr
+            .iter()
+            .map(json_to_yaml)
+            .collect::<Option<Vec<Yaml>>>()
+            .map(Yaml::Array),
+        Value::Object(obj) => {
+            if let Some(real) = obj.get("real") {
+                return real.as_str().map(|s| Yaml::Real(s.to_string()));
+            }
+            if let Some(alias) = obj.get("alias") {
+                return alias.as_u64().map(|i| Yaml::Alias(i as usize));
+            }
+            if obj.contains_key("badvalue") {
+                return Some(Yaml::BadValue);
+            }
+            let mut hash = LinkedHashMap::new();
+            for pair in obj.get("hash")?.as_array()? {
+                hash.insert(json_to_yaml(pair.get(0)?)?, json_to_yaml(pair.get(1)?)?);
+            }
+            Some(Yaml::Hash(hash))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_yaml_json_round_trip() {
+        let content = fs::read_to_string("test_files/rules/yaml/1.yml").unwrap();
+        let docs = YamlLoader::load_from_str(&content).unwrap();
+        for doc in docs {
+            assert_eq!(json_to_yaml(&yaml_to_json(&doc)), Some(doc));
+        }
+
+        let doc =
+            &YamlLoader::load_from_str("real: 1.50\nnum: 7\nkeys:\n  3: x\n  b: ~\n").unwrap()[0];
+        let restored = json_to_yaml(&yaml_to_json(doc)).unwrap();
+        assert_eq!(&restored, doc);
+        assert_eq!(restored["real"].as_str(), None);
+        assert_eq!(restored["real"].as_f64(), Some(1.5));
+    }
+
+    #[test]
+    fn test_rule_cache_hit_and_invalidation() {
+        let dir = std::env::temp_dir().join("hayabusa_test_rule_cache_hit_and_invalidation");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let rule_path = dir.join("rule.yml");
+        let cache_path = dir.join("cache.json");
+        fs::write(&rule_path, "title: before\n").unwrap();
+
+        let mut cache = RuleCache::load(&cache_path, Path::new("test_files/config"));
+        let docs = cache
+            .load_yaml_docs(&rule_path, &fs::read_to_string(&rule_path).unwrap())
+            .unwrap();
+        assert_eq!(docs[0]["title"].as_str(), Some("before"));
+        assert_eq!((cache.hit_count, cache.miss_count), (0, 1));
+        cache.save().unwrap();
+
+        // 内容が同じ場合はキャッシュを利用する
+        let mut cache = RuleCache::load(&cache_path, Path::new("test_files/config"));
+        let docs = cache
+            .load_yaml_docs(&rule_path, &fs::read_to_string(&rule_path).unwrap())
+            .unwrap();
+        assert_eq!(docs[0]["title"].as_str(), Some("before"));
+        assert_eq!((cache.hit_count, cache.miss_count), (1, 0));
+
+        // サイズが同じでも内容が変わった場合はキャッシュを利用しない
+        fs::write(&rule_path, "title: after_\n").unwrap();
+        let docs = cache
+            .load_yaml_docs(&rule_path, &fs::read_to_string(&rule_path).unwrap())
+            .unwrap();
+        assert_eq!(docs[0]["title"].as_str(), Some("after_"));
+        assert_eq!(cache.miss_count, 1);
+
+        // configフォルダが異なる場合はキャッシュ全体を破棄する
+        let cache = RuleCache::load(&cache_path, Path::new("test_files/rules"));
+        assert!(cache.entries.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rule_cache_keeps_bundle_entries() {
+        let dir = std::env::temp_dir().join("hayabusa_test_rule_cache_keeps_bundle_entries");
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let bundle_path = dir.join("rules.zip");
+        let cache_path = dir.join("cache.json");
+        fs::write(&bundle_path, "").unwrap();
+
+        let mut cache = RuleCache::load(&cache_path, Path::new("test_files/config"));
+        cache
+            .load_yaml_docs(&bundle_path.join("bundle/rule.yml"), "title: bundle\n")
+            .unwrap();
+        cache
+            .load_yaml_docs(&dir.join("deleted.yml"), "title: deleted\n")
+            .unwrap();
+        cache.save().unwrap();
+
+        // バンドル内のファイルは内容のハッシュで判定し、存在しないファイルのキャッシュのみ削除される
+        let mut cache = RuleCache::load(&cache_path, Path::new("test_files/config"));
+        assert_eq!(cache.entries.len(), 1);
+        cache
+            .load_yaml_docs(&bundle_path.join("bundle/rule.yml"), "title: bundle\n")
+            .unwrap();
+        assert_eq!((cache.hit_count, cache.miss_count), (1, 0));
+        assert!(!cache.updated);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
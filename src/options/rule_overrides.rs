@@ -0,0 +1,240 @@
+use crate::detections::configs::IDS_REGEX;
+use crate::detections::utils;
+use hashbrown::{HashMap, HashSet};
+use std::path::Path;
+use yaml_rust::{Yaml, YamlLoader};
+
+/// 配列に値を追加する場合にキーの末尾に付ける文字(例: tags+)
+const APPEND_SUFFIX: char = '+';
+
+/// --rule-overridesで指定されたルールIDごとの上書き設定。
+/// マッピングは再帰的にマージし、それ以外の値は置き換える。キーの末尾に"+"を付けた場合は配列に値を追加する
+#[derive(Debug, Default)]
+pub struct RuleOverrides {
+    overrides: HashMap<String, Yaml>,
+    /// 上書き設定を適用したルールのID
+    applied_ids: HashSet<String>,
+}
+
+impl RuleOverrides {
+    /// 上書き設定のファイルを読み込む
+    pub fn load(path: &Path) -> Result<RuleOverrides, Vec<String>> {
+        let content = utils::read_config_file(path, "rule overrides").map_err(|e| vec![e])?;
+        RuleOverrides::parse(&content)
+    }
+
+    /// 上書き設定をパースして検証する。不正な設定がある場合は全てのエラーメッセージを返す
+    pub fn parse(content: &str) -> Result<RuleOverrides, Vec<String>> {
+        let docs = YamlLoader::load_from_str(content)
+            .map_err(|e| vec![format!("Failed to parse the rule overrides file. {}", e)])?;
+        let mut ret = RuleOverrides::default();
+        let mut errors = vec![];
+        let hash = match docs.first() {
+            Some(Yaml::Hash(hash)) => hash,
+            None => return Ok(ret),
+            Some(_) => {
+                return Err(vec![
+                    "The rule overrides file must be a mapping of rule ids.".to_string(),
+                ])
+            }
+        };
+        for (key, value) in hash.iter() {
+            let id = match key.as_str() {
+                Some(id) if IDS_REGEX.is_match(id) => id,
+                _ => {
+                    errors.push(format!(
+                        "Invalid rule id format: {}",
+                        key.as_str()
+                            .map(|id| id.to_string())
+                            .unwrap_or_else(|| format!("{:?}", key))
+                    ));
+                    continue;
+                }
+            };
+            let fields = match value.as_hash() {
+                Some(fields) => fields,
+                None => {
+                    errors.push(format!("The override of {} must be a mapping.", id));
+                    continue;
+                }
+            };
+            for (field, field_value) in fields.iter() {
+                let field = field.as_str().unwrap_or_default();
+                if field == "id" {
+                    errors.push(format!("The rule id cannot be overridden: {}", id));
+                }
+                RuleOverrides::validate_append(id, field, field_value, &mut errors);
+            }
+            ret.overrides.insert(id.to_string(), value.clone());
+        }
+        if errors.is_empty() {
+            Ok(ret)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// "+"で終わるキーの値が配列であることを再帰的に検証する
+    fn validate_append(id: &str, field: &str, value: &Yaml, errors: &mut Vec<String>) {
+        if field.ends_with(APPEND_SUFFIX) && value.as_vec().is_none() {
+            errors.push(format!("The value of {} in {} must be a list.", field, id));
+        }
+        if let Some(hash) = value.as_hash() {
+            for (key, child) in hash.iter() {
+                RuleOverrides::validate_append(id, key.as_str().unwrap_or_default(), child, errors);
+            }
+        }
+    }
+
+    /// ルールIDに対応する上書き設定があれば、ルールにマージして返す
+    pub fn apply(&mut self, yaml: Yaml) -> (Yaml, bool) {
+        let id = yaml["id"].as_str().unwrap_or_default().to_string();
+        match self.overrides.get(&id) {
+            Some(overlay) => {
+                let merged = RuleOverrides::merge(yaml, overlay);
+                self.applied_ids.insert(id);
+                (merged, true)
+            }
+            None => (yaml, false),
+        }
+    }
+
+    /// 読み込んだルールのいずれにも適用されなかった上書き設定のルールID
+    pub fn unapplied_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .overrides
+            .keys()
+            .filter(|id| !self.applied_ids.contains(*id))
+            .cloned()
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn merge(base: Yaml, overlay: &Yaml) -> Yaml {
+        match (base, overlay) {
+            (Yaml::Hash(mut base_hash), Yaml::Hash(overlay_hash)) => {
+                for (key, value) in overlay_hash.iter() {
+                    if let Some(append_key) =
+                        key.as_str().and_then(|k| k.strip_suffix(APPEND_SUFFIX))
+                    {
+                        let append_key = Yaml::String(append_key.to_string());
+                        let mut values = match base_hash.remove(&append_key) {
+                            Some(Yaml::Array(values)) => values,
+                            Some(Yaml::Null) | None => vec![],
+                            Some(other) => vec![other],
+                        };
+                        values.extend(value.as_vec().cloned().unwrap_or_default());
+                        base_hash.insert(append_key, Yaml::Array(values));
+                    } else if let Some(base_value) = base_hash.get_mut(key) {
+                        let prev = std::mem::replace(base_value, Yaml::Null);
+                        *base_value = RuleOverrides::merge(prev, value);
+                    } else {
+                        base_hash.insert(key.clone(), value.clone());
+                    }
+                }
+                Yaml::Hash(base_hash)
+            }
+            (_, overlay) => overlay.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_rule_overrides() {
+        let mut overrides = RuleOverrides::parse(
+            r#"
+6f2a4c3e-0d1b-4b7a-9c55-1e2f3a4b5c6d:
+    falsepositives:
+        - backup software
+    tags+:
+        - local.reviewed
+    details: 'Cmd: %CommandLine%'
+    detection:
+        filter_backup:
+            Image|endswith: '\backup.exe'
+        condition: selection and not filter_backup
+0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e:
+    level: low
+"#,
+        )
+        .unwrap();
+        let rule = YamlLoader::load_from_str(
+            r#"
+title: Whoami
+id: 6f2a4c3e-0d1b-4b7a-9c55-1e2f3a4b5c6d
+tags:
+    - attack.discovery
+falsepositives:
+    - unknown
+detection:
+    selection:
+        CommandLine|contains: whoami
+    condition: selection
+"#,
+        )
+        .unwrap()[0]
+            .clone();
+
+        let (rule, applied) = overrides.apply(rule);
+        assert!(applied);
+        assert_eq!(rule["title"].as_str().unwrap(), "Whoami");
+        assert_eq!(
+            rule["falsepositives"].as_vec().unwrap(),
+            &vec![Yaml::String("backup software".to_string())]
+        );
+        assert_eq!(rule["tags"].as_vec().unwrap().len(), 2);
+        assert!(rule["tags+"].is_badvalue());
+        assert_eq!(rule["details"].as_str().unwrap(), "Cmd: %CommandLine%");
+        assert_eq!(
+            rule["detection"]["selection"]["CommandLine|contains"]
+                .as_str()
+                .unwrap(),
+            "whoami"
+        );
+        assert_eq!(
+            rule["detection"]["filter_backup"]["Image|endswith"]
+                .as_str()
+                .unwrap(),
+            "\\backup.exe"
+        );
+        assert_eq!(
+            rule["detection"]["condition"].as_str().unwrap(),
+            "selection and not filter_backup"
+        );
+        assert_eq!(
+            overrides.unapplied_ids(),
+            vec!["0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e"]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_rule_overrides() {
+        let errors = RuleOverrides::parse(
+            r#"
+not-an-id:
+    level: low
+6f2a4c3e-0d1b-4b7a-9c55-1e2f3a4b5c6d:
+    id: 0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e
+    tags+: local.reviewed
+0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e: low
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "Invalid rule id format: not-an-id",
+                "The rule id cannot be overridden: 6f2a4c3e-0d1b-4b7a-9c55-1e2f3a4b5c6d",
+                "The value of tags+ in 6f2a4c3e-0d1b-4b7a-9c55-1e2f3a4b5c6d must be a list.",
+                "The override of 0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e must be a mapping.",
+            ]
+        );
+        assert!(RuleOverrides::parse("- level: low").is_err());
+        assert!(RuleOverrides::parse("").unwrap().unapplied_ids().is_empty());
+    }
+}
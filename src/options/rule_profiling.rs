@@ -0,0 +1,223 @@
+use crate::detections::rule::RuleNode;
+use crate::detections::utils::write_color_buffer;
+use comfy_table::modifiers::UTF8_ROUND_CORNERS;
+use comfy_table::presets::UTF8_FULL;
+use comfy_table::{CellAlignment, Table};
+use csv::WriterBuilder;
+use serde_json::{json, Value};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use termcolor::{BufferWriter, ColorChoice};
+
+const HEADER: [&str; 8] = [
+    "Rank",
+    "Elapsed(ms)",
+    "Evaluated",
+    "Matched",
+    "Avg(us)",
+    "RuleTitle",
+    "RuleID",
+    "RulePath",
+];
+
+/// 1ルール分のプロファイリング結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleProfileRecord {
+    pub rulepath: String,
+    pub title: String,
+    pub id: String,
+    pub elapsed_ms: f64,
+    pub evaluated_records: u64,
+    pub matched_records: u64,
+}
+
+impl RuleProfileRecord {
+    /// 1レコードあたりの平均評価時間(マイクロ秒)
+    pub fn avg_us(&self) -> f64 {
+        if self.evaluated_records == 0 {
+            return 0.0;
+        }
+        self.elapsed_ms * 1000.0 / self.evaluated_records as f64
+    }
+}
+
+pub struct RuleProfiling {}
+
+impl RuleProfiling {
+    /// ルール毎の計測結果を評価時間の降順に並べて返す関数
+    pub fn create_records(rules: &[RuleNode]) -> Vec<RuleProfileRecord> {
+        let mut records: Vec<RuleProfileRecord> = rules
+            .iter()
+            .map(|rule| RuleProfileRecord {
+                rulepath: rule.rulepath.to_owned(),
+                title: rule.yaml["title"].as_str().unwrap_or("-").to_string(),
+                id: rule.yaml["id"].as_str().unwrap_or("-").to_string(),
+                elapsed_ms: rule.profile.elapsed.as_secs_f64() * 1000.0,
+                evaluated_records: rule.profile.evaluated_records,
+                matched_records: rule.profile.matched_records,
+            })
+            .collect();
+        records.sort_by(|a, b| {
+            b.elapsed_ms
+                .partial_cmp(&a.elapsed_ms)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| a.rulepath.cmp(&b.rulepath))
+        });
+        records
+    }
+
+    /// 計測結果を出力する関数。ファイルの指定がない場合は標準出力に表形式で出力し、拡張子が.jsonの場合はJSON、それ以外はCSVで保存する
+    pub fn output(rules: &[RuleNode], output_path: Option<&PathBuf>) -> Result<(), String> {
+        let records = RuleProfiling::create_records(rules);
+        match output_path {
+            Some(path) => {
+                let file = File::create(path).map_err(|e| {
+                    format!(
+                        "Failed to create the rule profiling file. ({}) {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+                let is_json = path
+                    .extension()
+                    .map(|ext| ext.eq_ignore_ascii_case("json"))
+                    .unwrap_or(false);
+                let mut wtr = BufWriter::new(file);
+                let output = if is_json {
+                    RuleProfiling::to_json(&records)
+                } else {
+                    RuleProfiling::to_csv(&records)?
+                };
+                wtr.write_all(output.as_bytes())
+                    .map_err(|e| e.to_string())?;
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    None,
+                    &format!("Rule profiling results saved to: {}", path.display()),
+                    true,
+                )
+                .ok();
+            }
+            None => RuleProfiling::print_table(&records),
+        }
+        Ok(())
+    }
+
+    fn to_row(rank: usize, record: &RuleProfileRecord) -> Vec<String> {
+        vec![
+            rank.to_string(),
+            format!("{:.3}", record.elapsed_ms),
+            record.evaluated_records.to_string(),
+            record.matched_records.to_string(),
+            format!("{:.3}", record.avg_us()),
+            record.title.to_owned(),
+            record.id.to_owned(),
+            record.rulepath.to_owned(),
+        ]
+    }
+
+    fn to_csv(records: &[RuleProfileRecord]) -> Result<String, String> {
+        let mut wtr = WriterBuilder::new().from_writer(vec![]);
+        wtr.write_record(HEADER).map_err(|e| e.to_string())?;
+        for (i, record) in records.iter().enumerate() {
+            wtr.write_record(RuleProfiling::to_row(i + 1, record))
+                .map_err(|e| e.to_string())?;
+        }
+        let bytes = wtr.into_inner().map_err(|e| e.to_string())?;
+        Ok(String::from_utf8(bytes).unwrap_or_default())
+    }
+
+    fn to_json(records: &[RuleProfileRecord]) -> String {
+        let values: Vec<Value> = records
+            .iter()
+            .enumerate()
+            .map(|(i, record)| {
+                json!({
+                    "Rank": i + 1,
+                    "ElapsedMs": record.elapsed_ms,
+                    "Evaluated": record.evaluated_records,
+                    "Matched": record.matched_records,
+                    "AvgUs": record.avg_us(),
+                    "RuleTitle": record.title,
+                    "RuleID": record.id,
+                    "RulePath": record.rulepath,
+                })
+            })
+            .collect();
+        serde_json::to_string_pretty(&Value::Array(values)).unwrap_or_default()
+    }
+
+    fn print_table(records: &[RuleProfileRecord]) {
+        let mut tb = Table::new();
+        tb.load_preset(UTF8_FULL)
+            .apply_modifier(UTF8_ROUND_CORNERS)
+            .set_header(HEADER);
+        for (i, record) in records.iter().enumerate() {
+            tb.add_row(RuleProfiling::to_row(i + 1, record));
+        }
+        for col in 0..5 {
+            if let Some(column) = tb.column_mut(col) {
+                column.set_cell_alignment(CellAlignment::Right);
+            }
+        }
+        println!("Rule profiling results:");
+        println!("{}", tb);
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detections::rule::create_rule;
+    use std::time::Duration;
+    use yaml_rust::YamlLoader;
+
+    fn create_profiled_rule(title: &str, elapsed_ms: u64, evaluated: u64) -> RuleNode {
+        let yaml = YamlLoader::load_from_str(&format!(
+            "title: {}\nid: {}-id\ndetection:\n    selection:\n        EventID: 1\n    condition: selection\n",
+            title, title
+        ))
+        .unwrap();
+        let mut rule = create_rule(format!("rules/{}.yml", title), yaml[0].clone());
+        rule.profile.elapsed = Duration::from_millis(elapsed_ms);
+        rule.profile.evaluated_records = evaluated;
+        rule
+    }
+
+    #[test]
+    fn test_create_records_sorted_by_elapsed() {
+        let rules = vec![
+            create_profiled_rule("fast", 1, 100),
+            create_profiled_rule("slow", 30, 100),
+            create_profiled_rule("middle", 10, 0),
+        ];
+        let records = RuleProfiling::create_records(&rules);
+        let titles: Vec<&str> = records.iter().map(|r| r.title.as_str()).collect();
+        assert_eq!(titles, vec!["slow", "middle", "fast"]);
+        assert_eq!(records[0].id, "slow-id");
+        assert_eq!(records[0].avg_us(), 300.0);
+        assert_eq!(records[1].avg_us(), 0.0);
+    }
+
+    #[test]
+    fn test_output_csv_and_json() {
+        let rules = vec![
+            create_profiled_rule("fast", 1, 10),
+            create_profiled_rule("slow", 2, 10),
+        ];
+        let records = RuleProfiling::create_records(&rules);
+        let csv = RuleProfiling::to_csv(&records).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Rank,Elapsed(ms),Evaluated,Matched,Avg(us),RuleTitle,RuleID,RulePath"
+        );
+        assert_eq!(lines[1], "1,2.000,10,0,200.000,slow,slow-id,rules/slow.yml");
+
+        let json: Value = serde_json::from_str(&RuleProfiling::to_json(&records)).unwrap();
+        assert_eq!(json[1]["RuleTitle"], "fast");
+        assert_eq!(json[1]["Rank"], 2);
+    }
+}
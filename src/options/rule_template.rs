@@ -0,0 +1,249 @@
+use crate::detections::configs::EventKeyAliasConfig;
+use crate::detections::utils::write_color_buffer;
+use crate::options::debug_rule::DebugRule;
+use chrono::Local;
+use serde_json::Value;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use termcolor::{BufferWriter, ColorChoice};
+use uuid::Uuid;
+
+const CHANNEL_KEY: &str = "Event.System.Channel";
+const EVENT_ID_KEY: &str = "Event.System.EventID";
+
+/// --rule-templateでevtxファイル内の1つのレコードからルールのひな形を作成する構造体
+pub struct RuleTemplate {}
+
+impl RuleTemplate {
+    /// evtxファイルから指定したレコードIDのレコードを読み込み、ルールのひな形を出力先のファイルもしくは標準出力に出力する
+    pub fn run(
+        evtx_path: &Path,
+        record_id: u64,
+        output_path: Option<&Path>,
+        eventkey_alias: &EventKeyAliasConfig,
+    ) -> Result<(), String> {
+        let record = DebugRule::find_record(evtx_path, record_id)?;
+        let evtx_name = evtx_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let template = RuleTemplate::create(&record, record_id, &evtx_name, eventkey_alias)?;
+        match output_path {
+            Some(path) => {
+                fs::write(path, template).map_err(|e| {
+                    format!("Failed to write the rule file. ({}) {}", path.display(), e)
+                })?;
+                write_color_buffer(
+                    &BufferWriter::stdout(ColorChoice::Always),
+                    None,
+                    &format!("Created the rule template: {}", path.display()),
+                    true,
+                )
+                .ok();
+            }
+            None => print!("{}", template),
+        }
+        Ok(())
+    }
+
+    /// レコードのChannel、EventID、EventDataの各フィールドをselectionに記載したルールのひな形を作成する
+    pub fn create(
+        record: &Value,
+        record_id: u64,
+        evtx_name: &str,
+        eventkey_alias: &EventKeyAliasConfig,
+    ) -> Result<String, String> {
+        let event = &record["Event"];
+        let channel = event["System"]["Channel"].as_str().unwrap_or_default();
+        let (event_id_pipe, event_id) = RuleTemplate::to_yaml_value(&event["System"]["EventID"])
+            .ok_or_else(|| "The record does not have an EventID.".to_string())?;
+
+        let mut selection = vec![];
+        if !channel.is_empty() {
+            selection.push((
+                RuleTemplate::to_field_name(CHANNEL_KEY, eventkey_alias),
+                RuleTemplate::quote(channel),
+            ));
+        }
+        selection.push((
+            RuleTemplate::to_field_name(EVENT_ID_KEY, eventkey_alias) + event_id_pipe,
+            event_id.to_owned(),
+        ));
+        if let Some(event_data) = event["EventData"].as_object() {
+            for (key, value) in event_data.iter() {
+                // パイプなどとして解釈される文字を含むフィールド名と、空の値は条件にしない
+                if !key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    continue;
+                }
+                if let Some((pipe, value)) = RuleTemplate::to_yaml_value(value) {
+                    let event_key = format!("Event.EventData.{}", key);
+                    selection.push((
+                        RuleTemplate::to_field_name(&event_key, eventkey_alias) + pipe,
+                        value,
+                    ));
+                }
+            }
+        }
+
+        let mut ret = String::default();
+        let title = if channel.is_empty() {
+            format!("EventID {}", event_id.trim_matches('\''))
+        } else {
+            format!("{} EventID {}", channel, event_id.trim_matches('\''))
+        };
+        writeln!(ret, "title: {}", RuleTemplate::quote(&title)).ok();
+        writeln!(ret, "id: {}", Uuid::new_v4()).ok();
+        writeln!(ret, "status: experimental").ok();
+        writeln!(
+            ret,
+            "description: {}",
+            RuleTemplate::quote(&format!(
+                "Created from EventRecordID {} in {}.",
+                record_id, evtx_name
+            ))
+        )
+        .ok();
+        writeln!(ret, "author: ''").ok();
+        writeln!(ret, "date: {}", Local::now().format("%Y/%m/%d")).ok();
+        writeln!(ret, "logsource:\n    product: windows").ok();
+        writeln!(ret, "detection:\n    selection:").ok();
+        for (field, value) in selection.iter() {
+            writeln!(ret, "        {}: {}", field, value).ok();
+        }
+        writeln!(ret, "    condition: selection").ok();
+        writeln!(ret, "falsepositives:\n    - unknown").ok();
+        writeln!(ret, "level: medium").ok();
+        writeln!(ret, "ruletype: Hayabusa").ok();
+        Ok(ret)
+    }
+
+    /// eventkey_alias.txtにエイリアスがあればエイリアスを、EventData配下であればフィールド名のみを返す
+    fn to_field_name(event_key: &str, eventkey_alias: &EventKeyAliasConfig) -> String {
+        if let Some(alias) = eventkey_alias.get_alias(event_key) {
+            return alias.to_owned();
+        }
+        // "."を含まないフィールド名はEvent.EventData配下として扱われるため、別のキーのエイリアスと重複しない場合は省略する
+        match event_key.strip_prefix("Event.EventData.") {
+            Some(key) if eventkey_alias.get_event_key(key).is_none() => key.to_string(),
+            _ => event_key.to_string(),
+        }
+    }
+
+    /// JSONの値をselectionに記載するフィールド名に付けるパイプと値に変換する。条件にできない値の場合はNoneを返す
+    fn to_yaml_value(value: &Value) -> Option<(&'static str, String)> {
+        match value {
+            Value::Number(n) => Some(("", n.to_string())),
+            Value::String(s) if !s.is_empty() => {
+                // ワイルドカードの直前の"\"はエスケープ文字として扱われ、"\"に続くワイルドカードの文字を
+                // そのまま表すことができないため、正規表現で完全一致の条件にする
+                if s.contains("\\*") || s.contains("\\?") {
+                    return Some((
+                        "|re",
+                        RuleTemplate::quote(&format!("^{}$", regex::escape(s))),
+                    ));
+                }
+                // ワイルドカードとして扱われる文字はエスケープする。それ以外の"\"はそのまま文字として扱われる
+                Some((
+                    "",
+                    RuleTemplate::quote(&s.replace('*', "\\*").replace('?', "\\?")),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    fn quote(value: &str) -> String {
+        format!("'{}'", value.replace('\'', "''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::detections::configs::{self, IDS_REGEX};
+    use crate::detections::rule::{create_rule, get_detection_keys};
+    use crate::detections::utils;
+    use yaml_rust::YamlLoader;
+
+    #[test]
+    fn test_create_rule_template() {
+        let record: Value = serde_json::from_str(
+            r#"{
+                "Event": {
+                    "System": {"Channel": "Microsoft-Windows-Sysmon/Operational", "EventID": 1},
+                    "EventData": {
+                        "Image": "C:\\Windows\\System32\\cmd.exe",
+                        "ShareName": "\\\\server\\share",
+                        "TargetFilename": "\\\\?\\C:\\Temp\\*.txt",
+                        "CommandLine": "cmd.exe /c \"echo it's *\"",
+                        "ParentImage": "",
+                        "Hashes": null
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut alias = EventKeyAliasConfig::new();
+        alias.add_alias("Channel", CHANNEL_KEY);
+        alias.add_alias("EventID", EVENT_ID_KEY);
+        alias.add_alias("Image", "Event.EventData.Image");
+        alias.add_alias("NewProcessName", "Event.EventData.Image");
+        alias.add_alias("CommandLine", "Event.EventData.ProcessCommandLine");
+
+        let template = RuleTemplate::create(&record, 1234, "sysmon.evtx", &alias).unwrap();
+        let yaml = YamlLoader::load_from_str(&template).unwrap()[0].clone();
+        assert!(IDS_REGEX.is_match(yaml["id"].as_str().unwrap()));
+        assert_eq!(
+            yaml["title"].as_str().unwrap(),
+            "Microsoft-Windows-Sysmon/Operational EventID 1"
+        );
+        let selection = &yaml["detection"]["selection"];
+        assert_eq!(
+            selection["Channel"].as_str().unwrap(),
+            "Microsoft-Windows-Sysmon/Operational"
+        );
+        assert_eq!(selection["EventID"].as_i64().unwrap(), 1);
+        assert_eq!(
+            selection["Image"].as_str().unwrap(),
+            "C:\\Windows\\System32\\cmd.exe"
+        );
+        // CommandLineは別のキーのエイリアスのため、フルパスで記載する
+        assert_eq!(
+            selection["Event.EventData.CommandLine"].as_str().unwrap(),
+            "cmd.exe /c \"echo it's \\*\""
+        );
+        // UNCパスの"\\"はそのまま記載する
+        assert_eq!(
+            selection["ShareName"].as_str().unwrap(),
+            "\\\\server\\share"
+        );
+        // "\"の直後にワイルドカードの文字がある場合は正規表現で記載する
+        assert_eq!(
+            selection["TargetFilename|re"].as_str().unwrap(),
+            r"^\\\\\?\\C:\\Temp\\\*\.txt$"
+        );
+        assert!(selection["ParentImage"].is_badvalue());
+        assert!(selection["Hashes"].is_badvalue());
+
+        // 作成したルールが読み込めて、元のレコードを検知できること
+        let template =
+            RuleTemplate::create(&record, 1234, "sysmon.evtx", &configs::EVENTKEY_ALIAS).unwrap();
+        let yaml = YamlLoader::load_from_str(&template).unwrap()[0].clone();
+        let mut rule = create_rule("sysmon.yml".to_string(), yaml);
+        assert!(rule.init().is_ok());
+        let keys = get_detection_keys(&rule);
+        let record_info = utils::create_rec_info(record.clone(), "sysmon.evtx".to_string(), &keys);
+        assert!(rule.select(&record_info));
+
+        // ワイルドカードの文字が文字として扱われ、異なる値は検知しないこと
+        let mut other_record = record;
+        other_record["Event"]["EventData"]["TargetFilename"] =
+            Value::String("\\\\?\\C:\\Temp\\a.txt".to_string());
+        let record_info = utils::create_rec_info(other_record, "sysmon.evtx".to_string(), &keys);
+        assert!(!rule.select(&record_info));
+    }
+}
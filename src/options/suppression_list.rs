@@ -0,0 +1,248 @@
+use crate::detections::configs::IDS_REGEX;
+use crate::detections::utils;
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use yaml_rust::Yaml;
+
+lazy_static! {
+    /// --suppression-listで読み込んだ抑制条件。ルールの読み込み時に設定する
+    pub static ref SUPPRESSION_LIST: RwLock<Option<SuppressionList>> = RwLock::new(None);
+}
+
+/// エラーメッセージに表示するファイルの種類
+const SUPPRESSION_LIST_FILE_KIND: &str = "suppression list";
+
+/// 1つの抑制条件。全てのフィールドの値が一致した場合に検知を抑制する
+#[derive(Debug)]
+struct SuppressionEntry {
+    /// フィールド名と、一致とする値(大文字小文字を区別しない)の一覧
+    fields: Vec<(String, Vec<String>)>,
+}
+
+impl SuppressionEntry {
+    fn is_match(&self, record: &Value) -> bool {
+        self.fields.iter().all(|(field, values)| {
+            utils::get_event_value(field, record)
+                .and_then(utils::value_to_string)
+                .map(|value| values.contains(&value.to_lowercase()))
+                .unwrap_or(false)
+        })
+    }
+
+    /// aggregation conditionの検知はレコードを持たないため、全てのフィールドがcount byのフィールドの場合のみ判定する
+    fn is_match_agg(&self, by_field: Option<&str>, key: &str) -> bool {
+        let by_field = match by_field {
+            Some(by_field) => utils::resolve_event_key(by_field),
+            None => return false,
+        };
+        self.fields.iter().all(|(field, values)| {
+            utils::resolve_event_key(field) == by_field && values.contains(&key.to_lowercase())
+        })
+    }
+}
+
+/// --suppression-listで指定されたルールIDとフィールドの値の条件に一致する検知を出力しないための抑制条件
+#[derive(Debug, Default)]
+pub struct SuppressionList {
+    /// ルールIDごとの抑制条件
+    entries: HashMap<String, Vec<SuppressionEntry>>,
+    /// 抑制した検知の数
+    suppressed_cnt: AtomicU64,
+}
+
+impl SuppressionList {
+    /// 抑制条件のファイルを読み込む
+    pub fn load(path: &Path) -> Result<SuppressionList, Vec<String>> {
+        let content =
+            utils::read_config_file(path, SUPPRESSION_LIST_FILE_KIND).map_err(|e| vec![e])?;
+        SuppressionList::parse(&content)
+    }
+
+    /// 抑制条件をパースして検証する。不正な条件がある場合は全てのエラーメッセージを返す
+    pub fn parse(content: &str) -> Result<SuppressionList, Vec<String>> {
+        let mut ret = SuppressionList::default();
+        let mut errors = vec![];
+        let entries =
+            utils::parse_yaml_list(content, SUPPRESSION_LIST_FILE_KIND).map_err(|e| vec![e])?;
+        for (i, entry) in entries.iter().enumerate() {
+            let id = match entry["id"].as_str() {
+                Some(id) if IDS_REGEX.is_match(id) => id,
+                _ => {
+                    errors.push(format!(
+                        "The entry #{} does not have a valid rule id.",
+                        i + 1
+                    ));
+                    continue;
+                }
+            };
+            let fields: Vec<(String, Vec<String>)> = entry["fields"]
+                .as_hash()
+                .map(|fields| {
+                    fields
+                        .iter()
+                        .filter_map(|(field, values)| {
+                            let values = match values {
+                                Yaml::Array(values) => values.iter().collect(),
+                                value => vec![value],
+                            };
+                            let values: Option<Vec<String>> = values
+                                .into_iter()
+                                .map(|value| match value {
+                                    Yaml::String(s) => Some(s.to_lowercase()),
+                                    Yaml::Integer(n) => Some(n.to_string()),
+                                    Yaml::Real(r) => Some(r.to_string()),
+                                    Yaml::Boolean(b) => Some(b.to_string()),
+                                    _ => None,
+                                })
+                                .collect();
+                            Some((field.as_str()?.to_string(), values?))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            // 値がスカラーもしくはスカラーの配列ではないフィールドがある場合もエラーとする
+            let field_cnt = entry["fields"].as_hash().map_or(0, |h| h.len());
+            if fields.is_empty() || fields.len() != field_cnt {
+                errors.push(format!(
+                    "The entry #{} must have the fields to match as a mapping of field names and values. ({})",
+                    i + 1,
+                    id
+                ));
+                continue;
+            }
+            ret.entries
+                .entry(id.to_string())
+                .or_default()
+                .push(SuppressionEntry { fields });
+        }
+        if errors.is_empty() {
+            Ok(ret)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// ルールIDとレコードが抑制条件に一致する場合は、抑制した検知の数を加算してtrueを返す
+    pub fn is_suppressed(&self, rule_id: &str, record: &Value) -> bool {
+        let is_match = self
+            .entries
+            .get(rule_id)
+            .map(|entries| entries.iter().any(|entry| entry.is_match(record)))
+            .unwrap_or(false);
+        if is_match {
+            self.suppressed_cnt.fetch_add(1, Ordering::Relaxed);
+        }
+        is_match
+    }
+
+    /// aggregation conditionの検知が抑制条件に一致する場合は、抑制した検知の数を加算してtrueを返す
+    pub fn is_suppressed_agg(&self, rule_id: &str, by_field: Option<&str>, key: &str) -> bool {
+        let is_match = self
+            .entries
+            .get(rule_id)
+            .map(|entries| {
+                entries
+                    .iter()
+                    .any(|entry| entry.is_match_agg(by_field, key))
+            })
+            .unwrap_or(false);
+        if is_match {
+            self.suppressed_cnt.fetch_add(1, Ordering::Relaxed);
+        }
+        is_match
+    }
+
+    /// 抑制した検知の数
+    pub fn suppressed_count(&self) -> u64 {
+        self.suppressed_cnt.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suppression_list() {
+        let suppression_list = SuppressionList::parse(
+            r#"
+- id: 5b0b75dc-9190-4047-b9a8-14164cee8a31
+  fields:
+      Event.System.Computer: BACKUP01
+      Event.EventData.Image:
+          - 'C:\Tools\backup.exe'
+          - 'C:\Tools\restore.exe'
+- id: 0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e
+  fields:
+      Event.EventData.TargetUserName: admin
+"#,
+        )
+        .unwrap();
+        let record = |computer: &str, image: &str| -> Value {
+            serde_json::from_str(&format!(
+                r#"{{"Event": {{"System": {{"Computer": "{}"}}, "EventData": {{"Image": "{}"}}}}}}"#,
+                computer, image
+            ))
+            .unwrap()
+        };
+        let rule_id = "5b0b75dc-9190-4047-b9a8-14164cee8a31";
+        assert!(suppression_list
+            .is_suppressed(rule_id, &record("backup01", "C:\\\\Tools\\\\backup.exe")));
+        assert!(suppression_list
+            .is_suppressed(rule_id, &record("BACKUP01", "C:\\\\Tools\\\\Restore.exe")));
+        // 全てのフィールドが一致しない場合と、ルールIDが異なる場合は抑制しない
+        assert!(
+            !suppression_list.is_suppressed(rule_id, &record("WKS01", "C:\\\\Tools\\\\backup.exe"))
+        );
+        assert!(!suppression_list.is_suppressed(
+            "0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e",
+            &record("BACKUP01", "C:\\\\Tools\\\\backup.exe")
+        ));
+
+        // aggregation conditionの検知はcount byのフィールドのみで判定する
+        let agg_rule_id = "0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e";
+        assert!(suppression_list.is_suppressed_agg(
+            agg_rule_id,
+            Some("Event.EventData.TargetUserName"),
+            "Admin"
+        ));
+        assert!(!suppression_list.is_suppressed_agg(agg_rule_id, None, "admin"));
+        assert!(!suppression_list.is_suppressed_agg(
+            rule_id,
+            Some("Event.System.Computer"),
+            "BACKUP01"
+        ));
+        assert_eq!(suppression_list.suppressed_count(), 3);
+    }
+
+    #[test]
+    fn test_parse_invalid_suppression_list() {
+        let errors = SuppressionList::parse(
+            r#"
+- id: not-an-id
+  fields:
+      Computer: BACKUP01
+- id: 5b0b75dc-9190-4047-b9a8-14164cee8a31
+- id: 0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e
+  fields:
+      Computer:
+          nested: value
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "The entry #1 does not have a valid rule id.",
+                "The entry #2 must have the fields to match as a mapping of field names and values. (5b0b75dc-9190-4047-b9a8-14164cee8a31)",
+                "The entry #3 must have the fields to match as a mapping of field names and values. (0b4d9a3b-2b3f-4c1f-8a6e-1f2a3b4c5d6e)",
+            ]
+        );
+        assert!(SuppressionList::parse("id: 5b0b75dc-9190-4047-b9a8-14164cee8a31").is_err());
+        assert!(SuppressionList::parse("").unwrap().entries.is_empty());
+    }
+}
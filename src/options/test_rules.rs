@@ -0,0 +1,332 @@
+use crate::detections::rule::{create_rule, get_detection_keys};
+use crate::detections::utils::{self, write_color_buffer};
+use crate::filter::RuleExclude;
+use crate::yaml::ParseYaml;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use termcolor::{BufferWriter, Color, ColorChoice};
+use yaml_rust::Yaml;
+
+/// ルールファイルと同じフォルダに配置するサンプルイベントのファイルの拡張子(例: rule.yml -> rule.tests.json)
+const SIDECAR_EXTENSION: &str = "tests.json";
+
+/// 1ルール分のテスト用のサンプルイベント
+#[derive(Debug, Default)]
+pub struct RuleTestSamples {
+    /// 検知されるべきイベント
+    pub positive: Vec<Value>,
+    /// 検知されてはいけないイベント
+    pub negative: Vec<Value>,
+    /// サンプルイベントの読み込みに失敗した際のエラーメッセージ
+    pub errors: Vec<String>,
+}
+
+impl RuleTestSamples {
+    pub fn is_empty(&self) -> bool {
+        self.positive.is_empty() && self.negative.is_empty() && self.errors.is_empty()
+    }
+
+    /// ルールのtestsセクションとサイドカーファイル(<ルール名>.tests.json)からサンプルイベントを読み込む。
+    /// testsセクションではpositive/negativeの配列にJSON文字列でイベントを記載し、サイドカーファイルでは同じ構造をJSONで記載する。
+    pub fn load(rulepath: &str, yaml: &Yaml) -> RuleTestSamples {
+        let mut samples = RuleTestSamples::default();
+        for (kind, samples_yaml) in [
+            ("positive", &yaml["tests"]["positive"]),
+            ("negative", &yaml["tests"]["negative"]),
+        ] {
+            let sample_strs = match samples_yaml {
+                Yaml::BadValue => continue,
+                Yaml::Array(arr) => arr.iter().map(|s| s.as_str()).collect(),
+                _ => vec![None],
+            };
+            for (i, sample_str) in sample_strs.into_iter().enumerate() {
+                let parsed = sample_str
+                    .ok_or_else(|| "the sample must be a JSON string".to_string())
+                    .and_then(|s| serde_json::from_str(s).map_err(|e| e.to_string()));
+                samples.push(kind, i, parsed);
+            }
+        }
+
+        let sidecar_path = RuleTestSamples::sidecar_path(rulepath);
+        if sidecar_path.exists() {
+            let sidecar: Result<Value, String> = fs::read_to_string(&sidecar_path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str(&s).map_err(|e| e.to_string()));
+            match sidecar {
+                Ok(sidecar) => {
+                    for kind in ["positive", "negative"] {
+                        let offset = samples.get_mut(kind).len();
+                        for (i, sample) in sidecar[kind]
+                            .as_array()
+                            .cloned()
+                            .unwrap_or_default()
+                            .into_iter()
+                            .enumerate()
+                        {
+                            samples.push(kind, offset + i, Ok(sample));
+                        }
+                    }
+                }
+                Err(e) => samples.errors.push(format!(
+                    "Failed to read the sample file. ({}) {}",
+                    sidecar_path.display(),
+                    e
+                )),
+            }
+        }
+        samples
+    }
+
+    fn sidecar_path(rulepath: &str) -> PathBuf {
+        Path::new(rulepath).with_extension(SIDECAR_EXTENSION)
+    }
+
+    fn get_mut(&mut self, kind: &str) -> &mut Vec<Value> {
+        if kind == "positive" {
+            &mut self.positive
+        } else {
+            &mut self.negative
+        }
+    }
+
+    fn push(&mut self, kind: &str, index: usize, sample: Result<Value, String>) {
+        match sample {
+            Ok(sample) => self.get_mut(kind).push(sample),
+            Err(e) => self.errors.push(format!(
+                "Failed to parse {} sample #{}. {}",
+                kind,
+                index + 1,
+                e
+            )),
+        }
+    }
+}
+
+/// 1ルール分のテスト結果
+#[derive(Debug)]
+pub struct RuleTestResult {
+    pub rulepath: String,
+    pub title: String,
+    pub failures: Vec<String>,
+}
+
+impl RuleTestResult {
+    pub fn is_passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// --test-rulesでルールに記載されたサンプルイベントを用いてルールをテストする構造体
+#[derive(Debug, Default)]
+pub struct TestRules {
+    pub results: Vec<RuleTestResult>,
+    /// サンプルイベントが記載されていないルールの数
+    pub no_test_cnt: usize,
+}
+
+impl TestRules {
+    /// 指定されたパスのルールを読み込み、サンプルイベントが記載されているルールを全てテストする
+    pub fn run(rules_path: &[PathBuf]) -> Result<TestRules, String> {
+        let mut rulefile_loader = ParseYaml::new();
+        rulefile_loader
+            .read_rules(rules_path, "informational", &RuleExclude::default())
+            .map_err(|e| e.to_string())?;
+
+        let mut ret = TestRules::default();
+        for (rulepath, yaml) in rulefile_loader.files {
+            match TestRules::test_rule(&rulepath, &yaml) {
+                Some(result) => ret.results.push(result),
+                None => ret.no_test_cnt += 1,
+            }
+        }
+        ret.results.sort_by(|a, b| a.rulepath.cmp(&b.rulepath));
+        Ok(ret)
+    }
+
+    /// 1ルール分のテストを実行する。サンプルイベントが無い場合はNoneを返す
+    pub fn test_rule(rulepath: &str, yaml: &Yaml) -> Option<RuleTestResult> {
+        let samples = RuleTestSamples::load(rulepath, yaml);
+        if samples.is_empty() {
+            return None;
+        }
+        let mut result = RuleTestResult {
+            rulepath: rulepath.to_string(),
+            title: yaml["title"].as_str().unwrap_or("-").to_string(),
+            failures: samples.errors.clone(),
+        };
+
+        let mut rule = create_rule(rulepath.to_string(), yaml.clone());
+        if let Err(err_msgs) = rule.init() {
+            result.failures.extend(err_msgs);
+            return Some(result);
+        }
+
+        if rule.has_agg_condition() {
+            // aggregation conditionがある場合はサンプルイベント全体で条件を満たすかを判定する
+            if !samples.positive.is_empty()
+                && TestRules::detect_aggregation(rulepath, yaml, &samples.positive) == 0
+            {
+                result.failures.push(
+                    "The positive samples did not satisfy the aggregation condition.".to_string(),
+                );
+            }
+            if !samples.negative.is_empty()
+                && TestRules::detect_aggregation(rulepath, yaml, &samples.negative) > 0
+            {
+                result
+                    .failures
+                    .push("The negative samples satisfied the aggregation condition.".to_string());
+            }
+        } else {
+            let keys = get_detection_keys(&rule);
+            for (kind, samples, expect) in [
+                ("positive", &samples.positive, true),
+                ("negative", &samples.negative, false),
+            ] {
+                for (i, sample) in samples.iter().enumerate() {
+                    let record =
+                        utils::create_rec_info(sample.clone(), rulepath.to_string(), &keys);
+                    if rule.select(&record) != expect {
+                        result.failures.push(format!(
+                            "The {} sample #{} was {}.",
+                            kind,
+                            i + 1,
+                            if expect { "not detected" } else { "detected" }
+                        ));
+                    }
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// 新しくルールを作成してサンプルイベントを全て評価し、aggregation conditionを満たした件数を返す
+    fn detect_aggregation(rulepath: &str, yaml: &Yaml, samples: &[Value]) -> usize {
+        let mut rule = create_rule(rulepath.to_string(), yaml.clone());
+        if rule.init().is_err() {
+            return 0;
+        }
+        let keys = get_detection_keys(&rule);
+        for sample in samples {
+            let record = utils::create_rec_info(sample.clone(), rulepath.to_string(), &keys);
+            rule.select(&record);
+        }
+        rule.judge_satisfy_aggcondition().len()
+    }
+
+    pub fn failed_count(&self) -> usize {
+        self.results.iter().filter(|r| !r.is_passed()).count()
+    }
+
+    /// テスト結果を標準出力に出力する
+    pub fn output(&self) {
+        let stdout = BufferWriter::stdout(ColorChoice::Always);
+        for result in self.results.iter() {
+            let (status, color) = if result.is_passed() {
+                ("PASS", Color::Green)
+            } else {
+                ("FAIL", Color::Red)
+            };
+            write_color_buffer(
+                &stdout,
+                Some(color),
+                &format!("[{}] {} ({})", status, result.title, result.rulepath),
+                true,
+            )
+            .ok();
+            for failure in result.failures.iter() {
+                write_color_buffer(&stdout, None, &format!("    {}", failure), true).ok();
+            }
+        }
+        if !self.results.is_empty() {
+            println!();
+        }
+        write_color_buffer(
+            &stdout,
+            None,
+            &format!(
+                "Tested rules: {} / Passed: {} / Failed: {} / Rules without tests: {}",
+                self.results.len(),
+                self.results.len() - self.failed_count(),
+                self.failed_count(),
+                self.no_test_cnt
+            ),
+            true,
+        )
+        .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn load_rule(rule_str: &str) -> Yaml {
+        YamlLoader::load_from_str(rule_str).unwrap()[0].clone()
+    }
+
+    #[test]
+    fn test_rule_with_embedded_samples() {
+        let rule = load_rule(
+            r#"
+title: Logon
+detection:
+    selection:
+        Event.System.EventID: 4624
+    condition: selection
+tests:
+    positive:
+        - '{"Event": {"System": {"EventID": 4624}}}'
+    negative:
+        - '{"Event": {"System": {"EventID": 4625}}}'
+        - '{"Event": {"System": {"EventID": 4624}}}'
+        - '{"Event": '
+"#,
+        );
+        let result = TestRules::test_rule("test_rules/logon.yml", &rule).unwrap();
+        assert_eq!(result.title, "Logon");
+        assert_eq!(result.failures.len(), 2);
+        assert!(result.failures[0].starts_with("Failed to parse negative sample #3."));
+        assert_eq!(result.failures[1], "The negative sample #2 was detected.");
+
+        let rule = load_rule("title: No tests\ndetection:\n    selection:\n        EventID: 1\n");
+        assert!(TestRules::test_rule("test_rules/no_tests.yml", &rule).is_none());
+    }
+
+    #[test]
+    fn test_rule_with_aggregation() {
+        let rule = load_rule(
+            r#"
+title: Many failed logons
+detection:
+    selection:
+        Event.System.EventID: 4625
+    condition: selection | count() >= 2
+tests:
+    positive:
+        - '{"Event": {"System": {"EventID": 4625}}}'
+        - '{"Event": {"System": {"EventID": 4625}}}'
+    negative:
+        - '{"Event": {"System": {"EventID": 4625}}}'
+        - '{"Event": {"System": {"EventID": 4624}}}'
+"#,
+        );
+        let result = TestRules::test_rule("test_rules/agg.yml", &rule).unwrap();
+        assert!(result.is_passed(), "{:?}", result.failures);
+    }
+
+    #[test]
+    fn test_run_with_sidecar_file() {
+        let result = TestRules::run(&[PathBuf::from("test_files/rules/test_rules")]).unwrap();
+        assert_eq!(result.no_test_cnt, 0);
+        assert_eq!(result.results.len(), 1);
+        assert!(
+            result.results[0].is_passed(),
+            "{:?}",
+            result.results[0].failures
+        );
+        assert_eq!(result.failed_count(), 0);
+    }
+}
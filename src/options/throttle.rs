@@ -0,0 +1,284 @@
+use crate::detections::message::DetectInfo;
+use crate::detections::utils::format_time;
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use hashbrown::HashMap;
+use itertools::Itertools;
+use linked_hash_map::LinkedHashMap;
+
+/// --dedup-windowで追加する列
+pub const DEDUP_COLUMNS: [(&str, &str); 3] = [
+    ("Count", "%Count%"),
+    ("FirstTimestamp", "%FirstTimestamp%"),
+    ("LastTimestamp", "%LastTimestamp%"),
+];
+
+/// 重複の判定に用いない、レコードごとに値が異なる列のエイリアス
+const DEDUP_IGNORED_ALIASES: [&str; 2] = ["%Timestamp%", "%RecordID%"];
+
+/// --max-per-ruleと--dedup-windowで間引いた検知の数
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThrottleSummary {
+    /// --max-per-ruleの上限を超えたため出力しなかった検知の数
+    pub throttled_cnt: u128,
+    /// --dedup-windowで1行にまとめた検知の数(まとめた先の行は含まない)
+    pub deduplicated_cnt: u128,
+}
+
+/// 検知結果を出力する前に、ルールとコンピュータごとの出力数の上限と、同一の検知の集約を適用する構造体
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    /// ルールとコンピュータごとに出力する検知の上限
+    pub max_per_rule: Option<usize>,
+    /// 同一の検知を1行にまとめる期間
+    pub dedup_window: Option<Duration>,
+}
+
+/// 出力する1行と、まとめた検知の数、最後の検知の時刻
+struct ThrottledRow {
+    time: DateTime<Utc>,
+    detect_info: DetectInfo,
+    count: u128,
+    last_time: DateTime<Utc>,
+}
+
+impl Throttle {
+    pub fn new(max_per_rule: Option<usize>, dedup_window_secs: Option<u64>) -> Throttle {
+        Throttle {
+            max_per_rule,
+            dedup_window: dedup_window_secs.map(|secs| Duration::seconds(secs as i64)),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_per_rule.is_some() || self.dedup_window.is_some()
+    }
+
+    /// --dedup-windowが指定されている場合は、出力するプロファイルに集約した数と最初と最後の時刻の列を追加する
+    pub fn add_dedup_columns(&self, profile: &mut LinkedHashMap<String, String>) {
+        if self.dedup_window.is_some() {
+            for (key, alias) in DEDUP_COLUMNS {
+                profile.insert(key.to_string(), alias.to_string());
+            }
+        }
+    }
+
+    /// 時刻順に検知を走査し、同一の検知の集約と出力数の上限を適用してmessagesを置き換える
+    pub fn apply(
+        &self,
+        messages: &DashMap<DateTime<Utc>, Vec<DetectInfo>>,
+        profile: &LinkedHashMap<String, String>,
+    ) -> ThrottleSummary {
+        let mut summary = ThrottleSummary::default();
+        if !self.is_enabled() {
+            return summary;
+        }
+        let ignored_keys: Vec<&String> = profile
+            .iter()
+            .filter(|(_, v)| DEDUP_IGNORED_ALIASES.iter().any(|alias| v.contains(alias)))
+            .map(|(k, _)| k)
+            .collect();
+        let times: Vec<DateTime<Utc>> = messages.iter().map(|e| *e.key()).sorted().collect();
+
+        let mut rows: Vec<ThrottledRow> = vec![];
+        // 同一の検知と判定するキーと、まとめる先の行のインデックス
+        let mut dedup_rows: HashMap<String, usize> = HashMap::new();
+        for time in times {
+            let (_, detect_infos) = messages.remove(&time).unwrap();
+            for detect_info in detect_infos {
+                if let Some(window) = self.dedup_window {
+                    let dedup_key = Throttle::create_dedup_key(&detect_info, &ignored_keys);
+                    if let Some(row) = dedup_rows.get(&dedup_key).map(|idx| &mut rows[*idx]) {
+                        if time - row.time <= window {
+                            row.count += 1;
+                            row.last_time = time;
+                            summary.deduplicated_cnt += 1;
+                            continue;
+                        }
+                    }
+                    dedup_rows.insert(dedup_key, rows.len());
+                }
+                rows.push(ThrottledRow {
+                    time,
+                    detect_info,
+                    count: 1,
+                    last_time: time,
+                });
+            }
+        }
+
+        // ルールとコンピュータごとの出力済みの行数
+        let mut output_cnt: HashMap<(String, String), usize> = HashMap::new();
+        for mut row in rows {
+            if let Some(max_per_rule) = self.max_per_rule {
+                let cnt = output_cnt
+                    .entry((
+                        row.detect_info.rulepath.to_owned(),
+                        row.detect_info.computername.to_owned(),
+                    ))
+                    .or_insert(0);
+                if *cnt >= max_per_rule {
+                    summary.throttled_cnt += row.count;
+                    continue;
+                }
+                *cnt += 1;
+            }
+            if self.dedup_window.is_some() {
+                let values = [
+                    row.count.to_string(),
+                    format_time(&row.time, false),
+                    format_time(&row.last_time, false),
+                ];
+                for ((key, _), value) in DEDUP_COLUMNS.iter().zip(values) {
+                    row.detect_info.ext_field.insert(key.to_string(), value);
+                }
+            }
+            messages.entry(row.time).or_default().push(row.detect_info);
+        }
+        summary
+    }
+
+    /// ルール、コンピュータ名と、時刻とレコードID以外の出力する値が同じ検知を同一とする
+    fn create_dedup_key(detect_info: &DetectInfo, ignored_keys: &[&String]) -> String {
+        let values = detect_info
+            .ext_field
+            .iter()
+            .filter(|(k, _)| !ignored_keys.contains(k))
+            .map(|(_, v)| v.as_str())
+            .join("\u{1f}");
+        format!(
+            "{}\u{1f}{}\u{1f}{}",
+            detect_info.rulepath, detect_info.computername, values
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn create_detect_info(rulepath: &str, computer: &str, time: &str, details: &str) -> DetectInfo {
+        DetectInfo {
+            rulepath: rulepath.to_string(),
+            ruletitle: rulepath.to_string(),
+            level: "high".to_string(),
+            computername: computer.to_string(),
+            eventid: "4625".to_string(),
+            detail: details.to_string(),
+            record_information: None,
+            ext_field: LinkedHashMap::from_iter([
+                ("Timestamp".to_string(), time.to_string()),
+                ("Computer".to_string(), computer.to_string()),
+                ("Details".to_string(), details.to_string()),
+            ]),
+            matched_fields: vec![],
+        }
+    }
+
+    fn insert(
+        messages: &DashMap<DateTime<Utc>, Vec<DetectInfo>>,
+        secs: u32,
+        rulepath: &str,
+        computer: &str,
+        details: &str,
+    ) {
+        let time = Utc.ymd(2022, 10, 18).and_hms(0, 0, secs);
+        messages.entry(time).or_default().push(create_detect_info(
+            rulepath,
+            computer,
+            &time.to_string(),
+            details,
+        ));
+    }
+
+    fn create_profile() -> LinkedHashMap<String, String> {
+        LinkedHashMap::from_iter([
+            ("Timestamp".to_string(), "%Timestamp%".to_string()),
+            ("Computer".to_string(), "%Computer%".to_string()),
+            ("Details".to_string(), "%Details%".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let messages = DashMap::new();
+        insert(&messages, 0, "a.yml", "PC01", "User: admin");
+        insert(&messages, 10, "a.yml", "PC01", "User: admin");
+        insert(&messages, 20, "a.yml", "PC01", "User: guest");
+        insert(&messages, 30, "a.yml", "PC02", "User: admin");
+        // 最初の検知から期間を過ぎたため、新しい行となる
+        insert(&messages, 59, "a.yml", "PC01", "User: admin");
+        insert(&messages, 59, "b.yml", "PC01", "User: admin");
+        let throttle = Throttle::new(None, Some(30));
+        let mut profile = create_profile();
+        throttle.add_dedup_columns(&mut profile);
+        assert_eq!(
+            profile.keys().collect::<Vec<&String>>(),
+            vec![
+                "Timestamp",
+                "Computer",
+                "Details",
+                "Count",
+                "FirstTimestamp",
+                "LastTimestamp"
+            ]
+        );
+
+        let summary = throttle.apply(&messages, &profile);
+        assert_eq!(
+            summary,
+            ThrottleSummary {
+                throttled_cnt: 0,
+                deduplicated_cnt: 1
+            }
+        );
+        let first = messages
+            .get(&Utc.ymd(2022, 10, 18).and_hms(0, 0, 0))
+            .unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].ext_field["Count"], "2");
+        assert_eq!(
+            first[0].ext_field["LastTimestamp"],
+            format_time(&Utc.ymd(2022, 10, 18).and_hms(0, 0, 10), false)
+        );
+        assert!(messages
+            .get(&Utc.ymd(2022, 10, 18).and_hms(0, 0, 10))
+            .is_none());
+        assert_eq!(messages.iter().map(|e| e.value().len()).sum::<usize>(), 5);
+    }
+
+    #[test]
+    fn test_max_per_rule() {
+        let messages = DashMap::new();
+        for secs in 0..5 {
+            insert(
+                &messages,
+                secs,
+                "a.yml",
+                "PC01",
+                &format!("User: user{}", secs),
+            );
+        }
+        insert(&messages, 6, "a.yml", "PC02", "User: admin");
+        insert(&messages, 7, "b.yml", "PC01", "User: admin");
+        let summary = Throttle::new(Some(2), None).apply(&messages, &create_profile());
+        assert_eq!(
+            summary,
+            ThrottleSummary {
+                throttled_cnt: 3,
+                deduplicated_cnt: 0
+            }
+        );
+        // 上限はルールとコンピュータごとに適用され、時刻の早い検知が残る
+        let mut times: Vec<u32> = messages
+            .iter()
+            .map(|e| e.key().timestamp() as u32 % 60)
+            .collect();
+        times.sort_unstable();
+        assert_eq!(times, vec![0, 1, 6, 7]);
+        assert!(messages
+            .iter()
+            .all(|e| !e.value()[0].ext_field.contains_key("Count")));
+    }
+}
@@ -1,11 +1,16 @@
+use crate::detections::configs;
 use crate::detections::message::AlertMessage;
 use crate::detections::utils::write_color_buffer;
 use crate::filter;
-use crate::yaml::ParseYaml;
+use crate::yaml::{read_archive_entries, ParseYaml};
 use chrono::{DateTime, Local, TimeZone};
-use git2::Repository;
+use git2::{Commit, Oid, Repository};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
 use std::fs::{self};
-use std::path::Path;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
 
 use hashbrown::{HashMap, HashSet};
 use std::cmp::Ordering;
@@ -16,9 +21,34 @@ use std::fs::create_dir;
 
 use termcolor::{BufferWriter, ColorChoice};
 
+/// --update-rules-fromで--manifestの指定がない場合に、アーカイブと同じフォルダから読み込むマニフェストのファイル名
+const MANIFEST_FILE_NAME: &str = "SHA256SUMS";
+
+/// ルールの更新前のリビジョンを保存するファイル名(rulesフォルダの.gitフォルダ内に作成する)
+const PREV_REVISION_FILE_NAME: &str = "HAYABUSA_PREV_REVISION";
+
+lazy_static! {
+    /// 検知に使用するrulesフォルダのリビジョン。-rを複数指定した場合は最初に指定したフォルダのリビジョンとする。gitリポジトリでない場合はNone
+    pub static ref RULES_REVISION: Option<String> = CONFIG_RULES_PATH
+        .as_ref()
+        .and_then(|path| UpdateRules::get_rules_revision(path));
+    static ref CONFIG_RULES_PATH: Option<PathBuf> =
+        configs::CONFIG.read().unwrap().args.rules.first().cloned();
+}
+
 pub struct UpdateRules {}
 
 impl UpdateRules {
+    /// rulesフォルダのルールファイルやリビジョンを変更するオプションが指定されているかを判定する
+    pub fn is_rules_changing_option() -> bool {
+        let args = &configs::CONFIG.read().unwrap().args;
+        args.update_rules
+            || args.update_rules_from.is_some()
+            || args.pin_rules.is_some()
+            || args.rollback_rules
+            || args.level_tuning.is_some()
+    }
+
     /// update rules(hayabusa-rules subrepository)
     pub fn update_rules(rule_path: &str) -> Result<String, git2::Error> {
         let mut result;
@@ -38,11 +68,17 @@ impl UpdateRules {
             result = UpdateRules::clone_rules(Path::new(rule_path));
         } else if hayabusa_rule_repo.is_ok() {
             // case of exist hayabusa-rules repository
-            UpdateRules::_repo_main_reset_hard(hayabusa_rule_repo.as_ref().unwrap())?;
+            let hayabusa_rule_repo = hayabusa_rule_repo.unwrap();
+            // --pin-rulesで固定したリビジョンもmainへのリセットで失われるため、リセット前のHEADを控えておく
+            let prev_revision = UpdateRules::get_head_commit_id(&hayabusa_rule_repo);
+            UpdateRules::_repo_main_reset_hard(&hayabusa_rule_repo)?;
             // case of failed fetching origin/main, git clone is not executed so network error has occurred possibly.
             prev_modified_rules = UpdateRules::get_updated_rules(rule_path, &prev_modified_time);
             prev_modified_time = fs::metadata(rule_path).unwrap().modified().unwrap();
-            result = UpdateRules::pull_repository(&hayabusa_rule_repo.unwrap());
+            result = UpdateRules::pull_repository(&hayabusa_rule_repo);
+            if result.is_ok() {
+                UpdateRules::save_prev_revision(&hayabusa_rule_repo, prev_revision);
+            }
         } else {
             // case of no exist hayabusa-rules repository in rules.
             // execute update because submodule information exists if hayabusa repository exists submodule information.
@@ -61,9 +97,13 @@ impl UpdateRules {
                 for mut submodule in submodules {
                     submodule.update(true, None)?;
                     let submodule_repo = submodule.open()?;
-                    if let Err(e) = UpdateRules::pull_repository(&submodule_repo) {
-                        AlertMessage::alert(&format!("Failed submodule update. {}", e)).ok();
-                        is_success_submodule_update = false;
+                    let prev_revision = UpdateRules::get_head_commit_id(&submodule_repo);
+                    match UpdateRules::pull_repository(&submodule_repo) {
+                        Ok(_) => UpdateRules::save_prev_revision(&submodule_repo, prev_revision),
+                        Err(e) => {
+                            AlertMessage::alert(&format!("Failed submodule update. {}", e)).ok();
+                            is_success_submodule_update = false;
+                        }
                     }
                 }
                 if is_success_submodule_update {
@@ -94,6 +134,429 @@ impl UpdateRules {
         result
     }
 
+    /// update rules from a local archive(zip, tar.gz or git bundle) without network access.
+    /// アーカイブのSHA-256ハッシュをマニフェストで検証してから、rulesフォルダを展開したルールで置き換える
+    pub fn update_rules_from_archive(
+        rule_path: &str,
+        archive_path: &Path,
+        manifest_path: Option<&Path>,
+    ) -> Result<String, String> {
+        let manifest_path = manifest_path
+            .map(|path| path.to_path_buf())
+            .unwrap_or_else(|| archive_path.with_file_name(MANIFEST_FILE_NAME));
+        UpdateRules::verify_manifest(archive_path, &manifest_path)?;
+        write_color_buffer(
+            &BufferWriter::stdout(ColorChoice::Always),
+            None,
+            &format!(
+                "Verified the SHA-256 hash of {} with {}.",
+                archive_path.display(),
+                manifest_path.display()
+            ),
+            true,
+        )
+        .ok();
+        if UpdateRules::is_same_folder(archive_path, &manifest_path) {
+            AlertMessage::warn(
+                "The manifest is in the same folder as the archive, so only a corrupted archive can be detected. Specify a manifest obtained from a trusted source with --manifest to detect a tampered archive.",
+            )
+            .ok();
+        }
+
+        // 展開に失敗した場合に既存のrulesフォルダが壊れないように、別のフォルダに展開してから置き換える
+        let rule_path = rule_path.trim_end_matches(['/', '\\']);
+        let staging_path = PathBuf::from(format!("{}.update", rule_path));
+        if staging_path.exists() {
+            fs::remove_dir_all(&staging_path).map_err(|e| {
+                format!(
+                    "Failed to remove the folder. ({}) {}",
+                    staging_path.display(),
+                    e
+                )
+            })?;
+        }
+        let extracted = UpdateRules::extract_rules(archive_path, &staging_path).and_then(|_| {
+            if UpdateRules::get_updated_rules(
+                staging_path.to_str().unwrap_or_default(),
+                &SystemTime::UNIX_EPOCH,
+            )
+            .is_empty()
+            {
+                Err(format!(
+                    "The archive does not contain any rule files. ({})",
+                    archive_path.display()
+                ))
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(e) = extracted {
+            fs::remove_dir_all(&staging_path).ok();
+            return Err(e);
+        }
+
+        let prev_modified_rules = if Path::new(rule_path).exists() {
+            UpdateRules::get_updated_rules(rule_path, &SystemTime::UNIX_EPOCH)
+        } else {
+            HashSet::default()
+        };
+        let backup_path = UpdateRules::replace_rules_dir(&staging_path, Path::new(rule_path))?;
+        if let Some(backup_path) = backup_path {
+            write_color_buffer(
+                &BufferWriter::stdout(ColorChoice::Always),
+                None,
+                &format!(
+                    "The previous rules folder was saved to {}.",
+                    backup_path.display()
+                ),
+                true,
+            )
+            .ok();
+        }
+        let updated_modified_rules =
+            UpdateRules::get_updated_rules(rule_path, &SystemTime::UNIX_EPOCH);
+        UpdateRules::print_diff_modified_rule_dates(prev_modified_rules, updated_modified_rules)
+            .map_err(|e| e.to_string())
+    }
+
+    /// sha256sumコマンドの出力形式("<ハッシュ値>  <ファイル名>")のマニフェストに記載されたハッシュ値とアーカイブのハッシュ値を比較する。
+    /// マニフェストがアーカイブと同じ場所から入手したものである場合は、改ざんではなく破損のみを検出できる
+    fn verify_manifest(archive_path: &Path, manifest_path: &Path) -> Result<(), String> {
+        let manifest = fs::read_to_string(manifest_path).map_err(|e| {
+            format!(
+                "Failed to read the manifest file. ({}) {}",
+                manifest_path.display(),
+                e
+            )
+        })?;
+        let archive_name = archive_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let expected_hash = manifest
+            .lines()
+            .find_map(|line| {
+                let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+                let name = name.trim_start().trim_start_matches('*');
+                let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+                (name == archive_name).then(|| hash.to_lowercase())
+            })
+            .ok_or_else(|| {
+                format!(
+                    "{} is not listed in the manifest file. ({})",
+                    archive_name,
+                    manifest_path.display()
+                )
+            })?;
+
+        let mut hasher = Sha256::new();
+        let mut archive = fs::File::open(archive_path).map_err(|e| {
+            format!(
+                "Failed to open the archive. ({}) {}",
+                archive_path.display(),
+                e
+            )
+        })?;
+        io::copy(&mut archive, &mut hasher).map_err(|e| e.to_string())?;
+        let actual_hash = hex::encode(hasher.finalize());
+        if actual_hash != expected_hash {
+            return Err(format!(
+                "The SHA-256 hash of {} does not match the manifest. (expected: {} actual: {})",
+                archive_path.display(),
+                expected_hash,
+                actual_hash
+            ));
+        }
+        Ok(())
+    }
+
+    /// アーカイブを指定したフォルダに展開する。git bundleの場合はgitコマンドでcloneする
+    fn extract_rules(archive_path: &Path, dest_path: &Path) -> Result<(), String> {
+        let is_git_bundle = archive_path.extension().unwrap_or_default() == "bundle";
+        if is_git_bundle {
+            // libgit2はgit bundleに対応していないため、gitコマンドを使用する
+            let status = Command::new("git")
+                .args(["clone", "--quiet"])
+                .arg(archive_path)
+                .arg(dest_path)
+                .status()
+                .map_err(|e| format!("Failed to run git to clone the bundle. {}", e))?;
+            if !status.success() {
+                return Err(format!(
+                    "Failed to git clone the bundle. ({})",
+                    archive_path.display()
+                ));
+            }
+            fs::remove_dir_all(dest_path.join(".git")).ok();
+            return Ok(());
+        }
+
+        let mut entries: Vec<(PathBuf, Vec<u8>)> = read_archive_entries(archive_path)
+            .map_err(|e| {
+                format!(
+                    "Failed to read the archive. ({}) {}",
+                    archive_path.display(),
+                    e
+                )
+            })?
+            .into_iter()
+            .filter_map(|(name, content)| {
+                UpdateRules::to_entry_path(&name).map(|path| (path, content))
+            })
+            .filter(|(path, _)| !path.components().any(|c| c.as_os_str() == ".git"))
+            .collect();
+        // GitHubからダウンロードしたアーカイブのように全体が1つのフォルダに格納されている場合は、そのフォルダを取り除く
+        let top_dir = entries
+            .first()
+            .and_then(|(path, _)| path.components().next())
+            .map(|c| c.as_os_str().to_owned());
+        if let Some(top_dir) = top_dir {
+            if entries
+                .iter()
+                .all(|(path, _)| path.components().count() > 1 && path.starts_with(&top_dir))
+            {
+                for (path, _) in entries.iter_mut() {
+                    *path = path.strip_prefix(&top_dir).unwrap().to_path_buf();
+                }
+            }
+        }
+        for (path, content) in entries {
+            let file_path = dest_path.join(path);
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            fs::write(&file_path, content).map_err(|e| {
+                format!("Failed to write the file. ({}) {}", file_path.display(), e)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// アーカイブ内のファイル名を展開先の相対パスに変換する。展開先の外を指すパスはNoneを返す
+    fn to_entry_path(name: &str) -> Option<PathBuf> {
+        let mut ret = PathBuf::new();
+        for component in Path::new(&name.replace('\\', "/")).components() {
+            match component {
+                Component::Normal(c) => ret.push(c),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        if ret.as_os_str().is_empty() {
+            None
+        } else {
+            Some(ret)
+        }
+    }
+
+    /// 2つのファイルが同じフォルダにあるかを判定する
+    fn is_same_folder(path1: &Path, path2: &Path) -> bool {
+        let get_folder = |path: &Path| {
+            let folder = path.parent().unwrap_or_else(|| Path::new(""));
+            let folder = if folder.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                folder
+            };
+            fs::canonicalize(folder).unwrap_or_else(|_| folder.to_path_buf())
+        };
+        get_folder(path1) == get_folder(path2)
+    }
+
+    /// 展開したフォルダでrulesフォルダを置き換え、元のrulesフォルダを日時を付けたフォルダに残してそのパスを返す。
+    /// 置き換えに失敗した場合は元のrulesフォルダに戻す
+    fn replace_rules_dir(
+        staging_path: &Path,
+        rules_path: &Path,
+    ) -> Result<Option<PathBuf>, String> {
+        let has_prev_rules = rules_path.exists();
+        if !has_prev_rules {
+            fs::rename(staging_path, rules_path).map_err(|e| {
+                format!(
+                    "Failed to replace the rules folder. ({}) {}",
+                    rules_path.display(),
+                    e
+                )
+            })?;
+            return Ok(None);
+        }
+        let backup_path = PathBuf::from(format!(
+            "{}.old.{}",
+            rules_path.display(),
+            Local::now().format("%Y%m%d%H%M%S")
+        ));
+        if backup_path.exists() {
+            return Err(format!(
+                "The backup folder of the rules already exists. ({})",
+                backup_path.display()
+            ));
+        }
+        // アーカイブに含まれないrules/configのファイルは引き継ぎ、置き換えられるファイルは警告する
+        let replaced_configs = UpdateRules::merge_prev_config(
+            &rules_path.join("config"),
+            &staging_path.join("config"),
+        )?;
+        fs::rename(rules_path, &backup_path).map_err(|e| {
+            format!(
+                "Failed to replace the rules folder. ({}) {}",
+                rules_path.display(),
+                e
+            )
+        })?;
+        if let Err(e) = fs::rename(staging_path, rules_path) {
+            fs::rename(&backup_path, rules_path).ok();
+            return Err(format!(
+                "Failed to replace the rules folder. ({}) {}",
+                rules_path.display(),
+                e
+            ));
+        }
+        for config_path in replaced_configs {
+            AlertMessage::warn(&format!(
+                "{} was replaced with the file in the archive. The previous file is kept in {}.",
+                rules_path.join("config").join(&config_path).display(),
+                backup_path.join("config").join(&config_path).display()
+            ))
+            .ok();
+        }
+        Ok(Some(backup_path))
+    }
+
+    /// 以前のrules/configのうち新しいrules/configに無いファイルをコピーし、内容が異なるため置き換えられるファイルのconfigからの相対パスを返す
+    fn merge_prev_config(prev_dir: &Path, new_dir: &Path) -> Result<Vec<PathBuf>, String> {
+        let mut replaced = vec![];
+        let entries = match fs::read_dir(prev_dir) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(replaced),
+        };
+        for entry in entries.flatten() {
+            let prev_path = entry.path();
+            let new_path = new_dir.join(entry.file_name());
+            if prev_path.is_dir() {
+                replaced.extend(
+                    UpdateRules::merge_prev_config(&prev_path, &new_path)?
+                        .into_iter()
+                        .map(|path| Path::new(&entry.file_name()).join(path)),
+                );
+            } else if !new_path.exists() {
+                fs::create_dir_all(new_dir).map_err(|e| e.to_string())?;
+                fs::copy(&prev_path, &new_path).map_err(|e| {
+                    format!("Failed to copy the file. ({}) {}", prev_path.display(), e)
+                })?;
+            } else if fs::read(&prev_path).ok() != fs::read(&new_path).ok() {
+                replaced.push(PathBuf::from(entry.file_name()));
+            }
+        }
+        Ok(replaced)
+    }
+
+    /// rulesフォルダのgitリポジトリを指定したコミットもしくはタグに固定する
+    pub fn pin_rules(rule_path: &str, revision: &str) -> Result<String, git2::Error> {
+        let repo = Repository::open(Path::new(rule_path))?;
+        // ローカルに存在しないリビジョンの場合はoriginからmainブランチとタグを取得してから探す
+        let object = match repo.revparse_single(revision) {
+            Ok(object) => object,
+            Err(_) => {
+                repo.find_remote("origin")?.fetch(
+                    &["main", "+refs/tags/*:refs/tags/*"],
+                    None,
+                    None,
+                )?;
+                repo.revparse_single(revision)?
+            }
+        };
+        let commit = object.peel_to_commit()?;
+        UpdateRules::checkout_revision(rule_path, &repo, &commit)
+    }
+
+    /// rulesフォルダのgitリポジトリを更新前のリビジョンに戻す
+    pub fn rollback_rules(rule_path: &str) -> Result<String, git2::Error> {
+        let repo = Repository::open(Path::new(rule_path))?;
+        let prev_revision =
+            fs::read_to_string(repo.path().join(PREV_REVISION_FILE_NAME)).map_err(|_| {
+                git2::Error::from_str("There is no previous revision of the rules to roll back to.")
+            })?;
+        let commit = repo.find_commit(Oid::from_str(prev_revision.trim())?)?;
+        UpdateRules::checkout_revision(rule_path, &repo, &commit)
+    }
+
+    /// 指定したコミットをチェックアウトして、変更されたルールを出力する。
+    /// チェックアウト前のリビジョンは--rollback-rulesで戻せるように保存する
+    fn checkout_revision(
+        rule_path: &str,
+        repo: &Repository,
+        commit: &Commit,
+    ) -> Result<String, git2::Error> {
+        let prev_modified_rules =
+            UpdateRules::get_updated_rules(rule_path, &SystemTime::UNIX_EPOCH);
+        let prev_revision = UpdateRules::get_head_commit_id(repo);
+        repo.checkout_tree(
+            commit.as_object(),
+            Some(git2::build::CheckoutBuilder::default().force()),
+        )?;
+        repo.set_head_detached(commit.id())?;
+        UpdateRules::save_prev_revision(repo, prev_revision);
+        let updated_modified_rules =
+            UpdateRules::get_updated_rules(rule_path, &SystemTime::UNIX_EPOCH);
+        let result = UpdateRules::print_diff_modified_rule_dates(
+            prev_modified_rules,
+            updated_modified_rules,
+        );
+        if let Some(revision) = UpdateRules::get_rules_revision(Path::new(rule_path)) {
+            println!("Rules revision: {}", revision);
+        }
+        result
+    }
+
+    /// 現在のHEADのコミットIDを取得する
+    fn get_head_commit_id(repo: &Repository) -> Option<Oid> {
+        repo.head()
+            .and_then(|head| head.peel_to_commit())
+            .map(|commit| commit.id())
+            .ok()
+    }
+
+    /// 更新前のHEADのコミットIDを--rollback-rulesで戻すリビジョンとして保存する。
+    /// 更新でHEADが変わらなかった場合は、以前に保存したリビジョンを上書きしない
+    fn save_prev_revision(repo: &Repository, prev_revision: Option<Oid>) {
+        let prev_revision = match prev_revision {
+            Some(prev_revision) => prev_revision,
+            None => return,
+        };
+        if UpdateRules::get_head_commit_id(repo) == Some(prev_revision) {
+            return;
+        }
+        fs::write(
+            repo.path().join(PREV_REVISION_FILE_NAME),
+            prev_revision.to_string(),
+        )
+        .ok();
+    }
+
+    /// rulesフォルダのgitリポジトリの現在のリビジョンを取得する。
+    /// HEADを指すタグがある場合は"<タグ> (<コミットID>)"、無い場合はコミットIDのみを返す
+    pub fn get_rules_revision(rule_path: &Path) -> Option<String> {
+        let repo = Repository::open(rule_path).ok()?;
+        let commit = repo.head().ok()?.peel_to_commit().ok()?;
+        let commit_id = commit.id().to_string();
+        let short_id = &commit_id[..7];
+        let tag = repo.tag_names(None).ok().and_then(|tags| {
+            tags.iter_bytes()
+                .map(|tag| String::from_utf8_lossy(tag).to_string())
+                .find(|tag| {
+                    repo.revparse_single(&format!("refs/tags/{}", tag))
+                        .and_then(|object| object.peel_to_commit())
+                        .map(|tag_commit| tag_commit.id() == commit.id())
+                        .unwrap_or(false)
+                })
+        });
+        match tag {
+            Some(tag) => Some(format!("{} ({})", tag, short_id)),
+            None => Some(short_id.to_string()),
+        }
+    }
+
     /// hard reset in main branch
     fn _repo_main_reset_hard(input_repo: &Repository) -> Result<(), git2::Error> {
         let branch = input_repo
@@ -101,7 +564,10 @@ impl UpdateRules {
             .unwrap();
         let local_head = branch.get().target().unwrap();
         let object = input_repo.find_object(local_head, None).unwrap();
-        match input_repo.reset(&object, git2::ResetType::Hard, None) {
+        match input_repo
+            .reset(&object, git2::ResetType::Hard, None)
+            .and_then(|_| input_repo.set_head("refs/heads/main"))
+        {
             Ok(()) => Ok(()),
             _ => Err(git2::Error::from_str("Failed reset main branch in rules")),
         }
@@ -255,6 +721,8 @@ impl UpdateRules {
 #[cfg(test)]
 mod tests {
     use crate::options::update_rules::UpdateRules;
+    use std::fs;
+    use std::path::{Path, PathBuf};
     use std::time::SystemTime;
 
     #[test]
@@ -270,4 +738,195 @@ mod tests {
             UpdateRules::get_updated_rules("test_files/rules/level_yaml", &target_time);
         assert_eq!(prev_modified_rules2.len(), 0);
     }
+
+    #[test]
+    fn test_verify_manifest() {
+        let manifest = Path::new("test_files/rules/bundles/SHA256SUMS");
+        assert!(UpdateRules::verify_manifest(
+            Path::new("test_files/rules/bundles/rules.zip"),
+            manifest
+        )
+        .is_ok());
+        // マニフェストのハッシュ値と一致しない場合と、マニフェストに記載がない場合はエラーとする
+        assert!(UpdateRules::verify_manifest(
+            Path::new("test_files/rules/bundles/rules.tar.gz"),
+            manifest
+        )
+        .unwrap_err()
+        .starts_with("The SHA-256 hash of"));
+        assert!(UpdateRules::verify_manifest(
+            Path::new("test_files/rules/level_yaml/high.yml"),
+            manifest
+        )
+        .unwrap_err()
+        .starts_with("high.yml is not listed"));
+
+        assert_eq!(
+            UpdateRules::to_entry_path("./rules/a.yml"),
+            Some(PathBuf::from("rules/a.yml"))
+        );
+        assert_eq!(UpdateRules::to_entry_path("../a.yml"), None);
+        assert_eq!(UpdateRules::to_entry_path("/etc/a.yml"), None);
+    }
+
+    #[test]
+    fn test_update_rules_from_archive() {
+        let rules_path = std::env::temp_dir().join("hayabusa_test_update_rules_from_archive");
+        fs::remove_dir_all(&rules_path).ok();
+        fs::create_dir_all(rules_path.join("config")).unwrap();
+        fs::write(rules_path.join("old.yml"), "title: Old\nid: old\n").unwrap();
+        fs::write(rules_path.join("config").join("user.txt"), "user").unwrap();
+
+        let result = UpdateRules::update_rules_from_archive(
+            rules_path.to_str().unwrap(),
+            Path::new("test_files/rules/bundles/rules.zip"),
+            None,
+        );
+        assert_eq!(result.unwrap(), "Rule updated");
+        // アーカイブ直下のフォルダと.gitフォルダは展開されない
+        assert!(rules_path.join("logon.yml").exists());
+        assert!(rules_path.join("process_creation.yml").exists());
+        assert!(!rules_path.join("old.yml").exists());
+        assert!(!rules_path.join(".git").exists());
+        // アーカイブに含まれないrules/configのファイルは引き継ぎ、元のrulesフォルダは日時を付けたフォルダに残す
+        assert!(rules_path.join("config").join("user.txt").exists());
+        let backup_paths: Vec<PathBuf> = fs::read_dir(std::env::temp_dir())
+            .unwrap()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.to_string_lossy()
+                    .starts_with(&format!("{}.old.", rules_path.display()))
+            })
+            .collect();
+        assert_eq!(backup_paths.len(), 1);
+        assert!(backup_paths[0].join("old.yml").exists());
+        fs::remove_dir_all(&rules_path).ok();
+        fs::remove_dir_all(&backup_paths[0]).ok();
+    }
+
+    #[test]
+    fn test_merge_prev_config() {
+        let base_path = std::env::temp_dir().join("hayabusa_test_merge_prev_config");
+        fs::remove_dir_all(&base_path).ok();
+        let prev_dir = base_path.join("prev");
+        let new_dir = base_path.join("new");
+        fs::create_dir_all(prev_dir.join("sub")).unwrap();
+        fs::create_dir_all(&new_dir).unwrap();
+        fs::write(prev_dir.join("same.txt"), "same").unwrap();
+        fs::write(new_dir.join("same.txt"), "same").unwrap();
+        fs::write(prev_dir.join("changed.txt"), "user").unwrap();
+        fs::write(new_dir.join("changed.txt"), "archive").unwrap();
+        fs::write(prev_dir.join("sub").join("user.txt"), "user").unwrap();
+
+        let replaced = UpdateRules::merge_prev_config(&prev_dir, &new_dir).unwrap();
+        assert_eq!(replaced, vec![PathBuf::from("changed.txt")]);
+        assert_eq!(
+            fs::read_to_string(new_dir.join("sub").join("user.txt")).unwrap(),
+            "user"
+        );
+        assert_eq!(
+            fs::read_to_string(new_dir.join("changed.txt")).unwrap(),
+            "archive"
+        );
+        assert!(UpdateRules::is_same_folder(
+            &prev_dir.join("same.txt"),
+            &prev_dir.join("changed.txt")
+        ));
+        assert!(!UpdateRules::is_same_folder(
+            &prev_dir.join("same.txt"),
+            &new_dir.join("same.txt")
+        ));
+        fs::remove_dir_all(&base_path).ok();
+    }
+
+    /// ルールファイルを1つ書き込んでコミットし、コミットIDを返す
+    fn commit_rule(repo: &git2::Repository, title: &str) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        fs::write(
+            workdir.join("rule.yml"),
+            format!("title: {}\nid: rule\nruletype: Hayabusa\n", title),
+        )
+        .unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("rule.yml")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("hayabusa", "hayabusa@example.com").unwrap();
+        let parents: Vec<git2::Commit> = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .into_iter()
+            .collect();
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, title, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pin_and_rollback_rules() {
+        let rules_path = std::env::temp_dir().join("hayabusa_test_pin_and_rollback_rules");
+        fs::remove_dir_all(&rules_path).ok();
+        let repo = git2::Repository::init(&rules_path).unwrap();
+        let first = commit_rule(&repo, "First");
+        repo.tag_lightweight("v1.0.0", &repo.find_object(first, None).unwrap(), false)
+            .unwrap();
+        let second = commit_rule(&repo, "Second");
+        let rule_path = rules_path.to_str().unwrap();
+        assert_eq!(
+            UpdateRules::get_rules_revision(&rules_path).unwrap(),
+            second.to_string()[..7]
+        );
+
+        UpdateRules::pin_rules(rule_path, "v1.0.0").unwrap();
+        assert_eq!(
+            UpdateRules::get_rules_revision(&rules_path).unwrap(),
+            format!("v1.0.0 ({})", &first.to_string()[..7])
+        );
+        assert!(fs::read_to_string(rules_path.join("rule.yml"))
+            .unwrap()
+            .starts_with("title: First"));
+
+        // ロールバックで固定する前のリビジョンに戻り、再度ロールバックすると固定したリビジョンに戻る
+        UpdateRules::rollback_rules(rule_path).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), second);
+        UpdateRules::rollback_rules(rule_path).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), first);
+
+        assert!(UpdateRules::pin_rules(rule_path, "not-exist").is_err());
+        fs::remove_dir_all(&rules_path).ok();
+    }
+
+    #[test]
+    fn test_update_rules_saves_pinned_revision() {
+        let test_path =
+            std::env::temp_dir().join("hayabusa_test_update_rules_saves_pinned_revision");
+        fs::remove_dir_all(&test_path).ok();
+        let origin_path = test_path.join("origin");
+        let origin = git2::Repository::init_opts(
+            &origin_path,
+            git2::RepositoryInitOptions::new().initial_head("main"),
+        )
+        .unwrap();
+        let first = commit_rule(&origin, "First");
+        let second = commit_rule(&origin, "Second");
+        let rules_path = test_path.join("rules");
+        let repo = git2::Repository::clone(origin_path.to_str().unwrap(), &rules_path).unwrap();
+        let rule_path = rules_path.to_str().unwrap();
+
+        // 固定したリビジョンは、mainが最新で更新が無い場合もロールバックで戻せる
+        UpdateRules::pin_rules(rule_path, &first.to_string()).unwrap();
+        UpdateRules::update_rules(rule_path).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), second);
+        assert!(repo.head().unwrap().is_branch());
+        UpdateRules::rollback_rules(rule_path).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), first);
+
+        // 更新でHEADが変わらない場合は保存したリビジョンを上書きしない
+        UpdateRules::pin_rules(rule_path, "main").unwrap();
+        UpdateRules::update_rules(rule_path).unwrap();
+        UpdateRules::rollback_rules(rule_path).unwrap();
+        assert_eq!(repo.head().unwrap().target().unwrap(), first);
+        fs::remove_dir_all(&test_path).ok();
+    }
 }
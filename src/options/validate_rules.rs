@@ -0,0 +1,538 @@
+use crate::detections::configs::{EventKeyAliasConfig, IDS_REGEX, LEVELMAP};
+use crate::detections::rule::{create_rule, validate_filter};
+use crate::detections::utils::write_color_buffer;
+use crate::yaml::{is_rule_bundle, read_bundle_files};
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use termcolor::{BufferWriter, Color, ColorChoice};
+use yaml_rust::{Yaml, YamlLoader};
+
+lazy_static! {
+    static ref CONDITION_WORD_REGEX: Regex = Regex::new(r"\w+").unwrap();
+}
+
+/// ルールのstatusに指定できる値
+const VALID_STATUS: [&str; 5] = [
+    "stable",
+    "test",
+    "experimental",
+    "deprecated",
+    "unsupported",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticLevel {
+    Error,
+    Warning,
+}
+
+/// ルールファイルの検証結果の1件分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleDiagnostic {
+    pub level: DiagnosticLevel,
+    pub rulepath: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for RuleDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            DiagnosticLevel::Error => "ERROR",
+            DiagnosticLevel::Warning => "WARN",
+        };
+        match self.line {
+            Some(line) => write!(
+                f,
+                "{}:{}: [{}] {}",
+                self.rulepath, line, level, self.message
+            ),
+            None => write!(f, "{}: [{}] {}", self.rulepath, level, self.message),
+        }
+    }
+}
+
+/// --validate-rulesで指定されたフォルダ内のルールファイルを検証する構造体
+#[derive(Default)]
+pub struct ValidateRules {
+    pub diagnostics: Vec<RuleDiagnostic>,
+    pub rule_cnt: usize,
+    /// ルールIDと最初にそのIDが記載されていたルールファイルのパス
+    ids: HashMap<String, String>,
+}
+
+impl ValidateRules {
+    /// -rで指定された複数のパス(フォルダ、ファイル、ルールバンドル)のルールファイルを全て検証する
+    pub fn validate_paths(
+        paths: &[PathBuf],
+        eventkey_alias: &EventKeyAliasConfig,
+    ) -> Result<ValidateRules, String> {
+        let mut validator = ValidateRules::default();
+        for path in paths {
+            validator.validate_path(path, eventkey_alias)?;
+        }
+        Ok(validator)
+    }
+
+    fn validate_path(
+        &mut self,
+        path: &Path,
+        eventkey_alias: &EventKeyAliasConfig,
+    ) -> Result<(), String> {
+        if is_rule_bundle(path) {
+            let contents = read_bundle_files(path).map_err(|e| {
+                format!("Failed to read the rule bundle. ({}) {}", path.display(), e)
+            })?;
+            for (name, content) in contents {
+                let rulepath = path.join(name).display().to_string();
+                self.validate_file(&rulepath, &content, eventkey_alias);
+            }
+            return Ok(());
+        }
+
+        let mut rule_files = vec![];
+        ValidateRules::collect_rule_files(path, &mut rule_files).map_err(|e| {
+            format!(
+                "Failed to read the rules folder. ({}) {}",
+                path.display(),
+                e
+            )
+        })?;
+        rule_files.sort();
+
+        for rule_file in rule_files {
+            let rulepath = rule_file.display().to_string();
+            match fs::read_to_string(&rule_file) {
+                Ok(content) => self.validate_file(&rulepath, &content, eventkey_alias),
+                Err(e) => self.push(
+                    DiagnosticLevel::Error,
+                    &rulepath,
+                    None,
+                    format!("Failed to read the file. {}", e),
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// 検証対象のymlファイルを再帰的に取得する。ルールの読み込み時と同様に.gitフォルダとsigmacのテストファイルは対象外とする
+    fn collect_rule_files(path: &Path, rule_files: &mut Vec<PathBuf>) -> io::Result<()> {
+        if fs::metadata(path)?.is_file() {
+            if path.extension().unwrap_or_else(|| OsStr::new("")) == "yml" {
+                rule_files.push(path.to_path_buf());
+            }
+            return Ok(());
+        }
+        for entry in fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            let path_str = entry_path.to_str().unwrap_or_default();
+            if path_str.contains("/.git/")
+                || path_str.contains("\\.git\\")
+                || path_str.contains("rules/tools/sigmac/test_files")
+                || path_str.contains("rules\\tools\\sigmac\\test_files")
+            {
+                continue;
+            }
+            if entry_path.is_dir() {
+                ValidateRules::collect_rule_files(&entry_path, rule_files)?;
+            } else if entry_path.extension().unwrap_or_else(|| OsStr::new("")) == "yml" {
+                rule_files.push(entry_path);
+            }
+        }
+        Ok(())
+    }
+
+    /// 1ファイル分の内容を検証する
+    pub fn validate_file(
+        &mut self,
+        rulepath: &str,
+        content: &str,
+        eventkey_alias: &EventKeyAliasConfig,
+    ) {
+        let docs = match YamlLoader::load_from_str(content) {
+            Ok(docs) => docs,
+            Err(e) => {
+                self.push(
+                    DiagnosticLevel::Error,
+                    rulepath,
+                    Some(e.marker().line()),
+                    format!("Failed to parse yml. {}", e),
+                );
+                return;
+            }
+        };
+        for doc in docs {
+            self.rule_cnt += 1;
+            self.validate_rule(rulepath, content, &doc, eventkey_alias);
+        }
+    }
+
+    fn validate_rule(
+        &mut self,
+        rulepath: &str,
+        content: &str,
+        yaml: &Yaml,
+        eventkey_alias: &EventKeyAliasConfig,
+    ) {
+        // id
+        match yaml["id"].as_str() {
+            None => self.push(
+                DiagnosticLevel::Error,
+                rulepath,
+                None,
+                "Missing rule id.".to_string(),
+            ),
+            Some(id) if !IDS_REGEX.is_match(id) => self.push(
+                DiagnosticLevel::Error,
+                rulepath,
+                find_line(content, "id", None),
+                format!("Invalid rule id format: {}", id),
+            ),
+            Some(id) => {
+                if let Some(first_path) = self.ids.get(id) {
+                    let message =
+                        format!("Duplicate rule id: {} (also used in {})", id, first_path);
+                    self.push(
+                        DiagnosticLevel::Error,
+                        rulepath,
+                        find_line(content, "id", None),
+                        message,
+                    );
+                } else {
+                    self.ids.insert(id.to_string(), rulepath.to_string());
+                }
+            }
+        }
+
+        // フィルタールールはlevelとdetectionを持たないため、対象のルールIDとfilterのみを検証する
+        if yaml["filter"].as_hash().is_some() && yaml["detection"].is_badvalue() {
+            self.validate_filter(rulepath, content, yaml);
+            return;
+        }
+
+        // level
+        match yaml["level"].as_str() {
+            None => self.push(
+                DiagnosticLevel::Error,
+                rulepath,
+                None,
+                "Missing level.".to_string(),
+            ),
+            Some(level) if !LEVELMAP.contains_key(&level.to_uppercase()) => self.push(
+                DiagnosticLevel::Error,
+                rulepath,
+                find_line(content, "level", None),
+                format!(
+                    "Unknown level: {} (informational, low, medium, high or critical)",
+                    level
+                ),
+            ),
+            _ => {}
+        }
+
+        // status
+        if let Some(status) = yaml["status"].as_str() {
+            if !VALID_STATUS.contains(&status) {
+                self.push(
+                    DiagnosticLevel::Error,
+                    rulepath,
+                    find_line(content, "status", None),
+                    format!("Unknown status: {} ({})", status, VALID_STATUS.join(", ")),
+                );
+            }
+        }
+
+        // detection
+        let detection_line = find_line(content, "detection", None);
+        let mut rule = create_rule(rulepath.to_string(), yaml.clone());
+        if let Err(err_msgs) = rule.init() {
+            for err_msg in err_msgs {
+                self.push(DiagnosticLevel::Error, rulepath, detection_line, err_msg);
+            }
+        }
+        let detection = match yaml["detection"].as_hash() {
+            Some(detection) => detection,
+            None => return,
+        };
+
+        // conditionから参照されていないselection
+        if let Some(condition) = yaml["detection"]["condition"].as_str() {
+            let condition = condition.split('|').next().unwrap_or_default();
+            let referenced: HashSet<&str> = CONDITION_WORD_REGEX
+                .find_iter(condition)
+                .map(|m| m.as_str())
+                .collect();
+            for name in detection.keys().filter_map(|k| k.as_str()) {
+                if name == "condition" || name == "timeframe" || referenced.contains(name) {
+                    continue;
+                }
+                self.push(
+                    DiagnosticLevel::Warning,
+                    rulepath,
+                    find_line(content, name, Some("detection")),
+                    format!("Selection is not referenced in the condition: {}", name),
+                );
+            }
+        }
+
+        // eventkey_alias.txtに定義されていないエイリアス
+        let mut keys = vec![];
+        for (name, selection) in detection.iter() {
+            if name.as_str() != Some("condition") && name.as_str() != Some("timeframe") {
+                collect_selection_keys(selection, &mut keys);
+            }
+        }
+        let mut checked_keys = HashSet::new();
+        for key in keys {
+            if key.is_empty()
+                || key.contains('.')
+                || eventkey_alias.get_event_key(&key).is_some()
+                || !checked_keys.insert(key.to_owned())
+            {
+                continue;
+            }
+            self.push(
+                DiagnosticLevel::Warning,
+                rulepath,
+                find_line(content, &key, Some("detection")),
+                format!("Field is not defined in eventkey_alias.txt: {}", key),
+            );
+        }
+    }
+
+    fn validate_filter(&mut self, rulepath: &str, content: &str, yaml: &Yaml) {
+        let filter_line = find_line(content, "filter", None);
+        let rules = &yaml["filter"]["rules"];
+        let target_ids: Vec<&Yaml> = match rules.as_vec() {
+            Some(ids) => ids.iter().collect(),
+            None if rules.is_badvalue() => vec![],
+            None => vec![rules],
+        };
+        if target_ids.is_empty() {
+            self.push(
+                DiagnosticLevel::Error,
+                rulepath,
+                filter_line,
+                "Missing target rule ids in the filter.".to_string(),
+            );
+        }
+        for id in target_ids {
+            match id.as_str() {
+                Some(id) if IDS_REGEX.is_match(id) => {}
+                _ => self.push(
+                    DiagnosticLevel::Error,
+                    rulepath,
+                    find_line(content, "rules", Some("filter")),
+                    format!(
+                        "Invalid target rule id format in the filter: {}",
+                        id.as_str()
+                            .map(|id| id.to_string())
+                            .unwrap_or_else(|| format!("{:?}", id))
+                    ),
+                ),
+            }
+        }
+        if let Err(err_msgs) = validate_filter(yaml) {
+            for err_msg in err_msgs {
+                self.push(DiagnosticLevel::Error, rulepath, filter_line, err_msg);
+            }
+        }
+    }
+
+    fn push(
+        &mut self,
+        level: DiagnosticLevel,
+        rulepath: &str,
+        line: Option<usize>,
+        message: String,
+    ) {
+        self.diagnostics.push(RuleDiagnostic {
+            level,
+            rulepath: rulepath.to_string(),
+            line,
+            message,
+        });
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.level == DiagnosticLevel::Error)
+            .count()
+    }
+
+    pub fn warning_count(&self) -> usize {
+        self.diagnostics.len() - self.error_count()
+    }
+
+    /// 検証結果を標準出力に出力する
+    pub fn output(&self) {
+        let stdout = BufferWriter::stdout(ColorChoice::Always);
+        for diagnostic in self.diagnostics.iter() {
+            let color = match diagnostic.level {
+                DiagnosticLevel::Error => Color::Red,
+                DiagnosticLevel::Warning => Color::Yellow,
+            };
+            write_color_buffer(&stdout, Some(color), &diagnostic.to_string(), true).ok();
+        }
+        if !self.diagnostics.is_empty() {
+            println!();
+        }
+        write_color_buffer(
+            &stdout,
+            None,
+            &format!(
+                "Validated rules: {} / Errors: {} / Warnings: {}",
+                self.rule_cnt,
+                self.error_count(),
+                self.warning_count()
+            ),
+            true,
+        )
+        .ok();
+    }
+}
+
+/// selectionに記載されているフィールド名(パイプより前の部分)を取得する
+fn collect_selection_keys(selection: &Yaml, keys: &mut Vec<String>) {
+    match selection {
+        Yaml::Hash(hash) => {
+            for (key, value) in hash.iter() {
+                if let Some(key) = key.as_str() {
+                    keys.push(key.split('|').next().unwrap_or_default().to_string());
+                }
+                if let Yaml::Array(_) = value {
+                    collect_selection_keys(value, keys);
+                }
+            }
+        }
+        Yaml::Array(arr) => arr.iter().for_each(|v| collect_selection_keys(v, keys)),
+        _ => {}
+    }
+}
+
+/// 指定したキーが記載されている行番号(1始まり)を返す。parentを指定した場合はparentの行より後ろにあるインデントされた行から探す
+fn find_line(content: &str, key: &str, parent: Option<&str>) -> Option<usize> {
+    let start = match parent {
+        Some(parent) => find_line(content, parent, None)?,
+        None => 0,
+    };
+    content
+        .lines()
+        .enumerate()
+        .skip(start)
+        .find(|(_, line)| {
+            let trimmed = line.trim_start();
+            if (trimmed.len() != line.len()) != parent.is_some() {
+                return false;
+            }
+            let trimmed = trimmed.trim_start_matches("- ");
+            if let Some(rest) = trimmed.strip_prefix(key) {
+                rest.starts_with(':') || rest.starts_with('|')
+            } else {
+                false
+            }
+        })
+        .map(|(i, _)| i + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID_RULE: &str = r#"title: Valid rule
+id: 00000000-0000-0000-0000-000000000001
+status: test
+detection:
+    selection:
+        EventID: 4624
+    filter:
+        EventID|contains: '1'
+    condition: selection and not filter
+level: medium
+"#;
+
+    #[test]
+    fn test_validate_file() {
+        let alias = EventKeyAliasConfig::new();
+        let mut validator = ValidateRules::default();
+        validator.validate_file("valid.yml", VALID_RULE, &alias);
+        // エイリアスの定義がない場合はEventIDの警告のみ出力される
+        assert_eq!(validator.error_count(), 0);
+        assert_eq!(
+            validator.diagnostics[0].to_string(),
+            "valid.yml:6: [WARN] Field is not defined in eventkey_alias.txt: EventID"
+        );
+        assert_eq!(validator.warning_count(), 1);
+
+        let invalid_rule = VALID_RULE
+            .replace("status: test", "status: unknown")
+            .replace("level: medium", "level: urgent")
+            .replace(" and not filter", "");
+        validator.validate_file("invalid.yml", &invalid_rule, &alias);
+        let diagnostics: Vec<String> = validator.diagnostics[1..]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diagnostics,
+            vec![
+                "invalid.yml:2: [ERROR] Duplicate rule id: 00000000-0000-0000-0000-000000000001 (also used in valid.yml)",
+                "invalid.yml:10: [ERROR] Unknown level: urgent (informational, low, medium, high or critical)",
+                "invalid.yml:3: [ERROR] Unknown status: unknown (stable, test, experimental, deprecated, unsupported)",
+                "invalid.yml:7: [WARN] Selection is not referenced in the condition: filter",
+                "invalid.yml:6: [WARN] Field is not defined in eventkey_alias.txt: EventID",
+            ]
+        );
+        assert_eq!(validator.rule_cnt, 2);
+
+        // フィルタールールはlevelとdetectionが無くても、対象のルールIDとfilterのみを検証する
+        let mut validator = ValidateRules::default();
+        validator.validate_file(
+            "filter.yml",
+            "id: 00000000-0000-0000-0000-000000000002\nfilter:\n    rules:\n        - not-an-id\n    selection:\n        Event.System.EventID: 4624\n",
+            &alias,
+        );
+        assert_eq!(
+            validator
+                .diagnostics
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<String>>(),
+            vec!["filter.yml:3: [ERROR] Invalid target rule id format in the filter: not-an-id"]
+        );
+    }
+
+    #[test]
+    fn test_validate_paths() {
+        let validator = ValidateRules::validate_paths(
+            &[PathBuf::from("test_files/rules/yaml")],
+            &EventKeyAliasConfig::new(),
+        )
+        .unwrap();
+        let error_yml = Path::new("test_files/rules/yaml/error.yml")
+            .display()
+            .to_string();
+        assert!(validator.diagnostics.iter().any(|d| d.rulepath == error_yml
+            && d.level == DiagnosticLevel::Error
+            && d.line.is_some()
+            && d.message.starts_with("Failed to parse yml.")));
+        // idの無いルールはエラーとなる
+        assert!(validator
+            .diagnostics
+            .iter()
+            .any(|d| d.message == "Missing rule id."));
+
+        assert!(ValidateRules::validate_paths(
+            &[PathBuf::from("test_files/rules/not_exist")],
+            &EventKeyAliasConfig::new()
+        )
+        .is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::detections::message::{LOGONSUMMARY_FLAG, STATISTICS_FLAG};
 use crate::detections::{detection::EvtxRecordInfo, utils};
+use crate::input::evt_log::{LOGON_FAILURE_EVENT_IDS, LOGON_SUCCESS_EVENT_IDS};
 use hashbrown::HashMap;
 
 #[derive(Debug)]
@@ -106,8 +107,13 @@ impl EventStatistics {
     // Login event
     fn stats_login_eventid(&mut self, records: &[EvtxRecordInfo]) {
         for record in records.iter() {
+            // ログオンのイベントIDは他のチャネルでは別の意味を持つため、Securityチャネルのイベントのみ集計する
+            let is_security = utils::get_event_value("Event.System.Channel", &record.record)
+                .and_then(|channel| channel.as_str())
+                .map(|channel| channel.eq_ignore_ascii_case("Security"))
+                .unwrap_or(false);
             let evtid = utils::get_event_value("EventID", &record.record);
-            if evtid.is_none() {
+            if !is_security || evtid.is_none() {
                 continue;
             }
             let idnum: i64 = if evtid.unwrap().is_number() {
@@ -120,25 +126,71 @@ impl EventStatistics {
                     .parse::<i64>()
                     .unwrap_or_default()
             };
-            if !(idnum == 4624 || idnum == 4625) {
+            // .evtのログオン成功(528, 540)とログオン失敗(529-537, 539)のイベントも集計する
+            let is_success = idnum == 4624 || LOGON_SUCCESS_EVENT_IDS.contains(&(idnum as u32));
+            let is_failure = idnum == 4625 || LOGON_FAILURE_EVENT_IDS.contains(&(idnum as u32));
+            if !(is_success || is_failure) {
                 continue;
             }
 
-            let username = utils::get_event_value("TargetUserName", &record.record);
+            // TargetUserNameが無いイベントはn/aとして集計する
+            let username = utils::get_event_value("TargetUserName", &record.record)
+                .filter(|username| !username.is_null())
+                .map(|username| username.to_string())
+                .unwrap_or_else(|| "n/a".to_string());
             let countlist: [usize; 2] = [0, 0];
-            if idnum == 4624 {
-                let count: &mut [usize; 2] = self
-                    .stats_login_list
-                    .entry(username.unwrap().to_string())
-                    .or_insert(countlist);
+            let count: &mut [usize; 2] = self.stats_login_list.entry(username).or_insert(countlist);
+            if is_success {
                 count[0] += 1;
-            } else if idnum == 4625 {
-                let count: &mut [usize; 2] = self
-                    .stats_login_list
-                    .entry(username.unwrap().to_string())
-                    .or_insert(countlist);
+            } else {
                 count[1] += 1;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::detections::detection::EvtxRecordInfo;
+    use crate::timeline::statistics::EventStatistics;
+    use hashbrown::HashMap;
+    use serde_json::Value;
+
+    fn create_record(channel: &str, event_id: u32, event_data: &str) -> EvtxRecordInfo {
+        let record: Value = serde_json::from_str(&format!(
+            r#"{{"Event": {{"System": {{"Channel": "{}", "EventID": {}}}, "EventData": {{{}}}}}}}"#,
+            channel, event_id, event_data
+        ))
+        .unwrap();
+        EvtxRecordInfo {
+            evtx_filepath: "test.evt".to_string(),
+            record,
+            data_string: String::default(),
+            key_2_value: HashMap::new(),
+            record_information: None,
+        }
+    }
+
+    #[test]
+    fn test_stats_login_eventid() {
+        let mut stats = EventStatistics::new(
+            0,
+            String::default(),
+            String::default(),
+            String::default(),
+            HashMap::new(),
+            HashMap::new(),
+        );
+        let records = vec![
+            create_record("Security", 528, r#""TargetUserName": "admin""#),
+            create_record("Security", 529, r#""TargetUserName": "admin""#),
+            create_record("Security", 4625, ""),
+            // Security以外のチャネルの同じイベントIDは集計しない
+            create_record("Application", 528, r#""TargetUserName": "admin""#),
+        ];
+        stats.stats_login_eventid(&records);
+        assert_eq!(stats.stats_login_list.len(), 2);
+        assert_eq!(stats.stats_login_list["\"admin\""], [1, 1]);
+        assert_eq!(stats.stats_login_list["n/a"], [0, 1]);
+    }
+}
@@ -6,12 +6,18 @@ use crate::detections::configs::EXCLUDE_STATUS;
 use crate::detections::message::AlertMessage;
 use crate::detections::message::{ERROR_LOG_STACK, QUIET_ERRORS_FLAG};
 use crate::filter::RuleExclude;
+use crate::input::archive;
+use crate::options::level_tuning::LevelTuning;
+use crate::options::logsource_mapping::LogsourceMapping;
+use crate::options::rule_cache::RuleCache;
+use crate::options::rule_overrides::RuleOverrides;
 use hashbrown::HashMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::io;
 use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
+use yaml_rust::ScanError;
 use yaml_rust::Yaml;
 use yaml_rust::YamlLoader;
 
@@ -21,6 +27,19 @@ pub struct ParseYaml {
     pub rule_load_cnt: HashMap<String, u128>,
     pub rule_status_cnt: HashMap<String, u128>,
     pub errorrule_count: u128,
+    pub rule_cache: Option<RuleCache>,
+    /// 他のルールの検知を抑制するフィルタールールのファイルパスと内容
+    pub filter_rules: Vec<(String, Yaml)>,
+    /// --logsource-mappingで読み込んだlogsourceとChannelの対応
+    pub logsource_mapping: Option<LogsourceMapping>,
+    /// --rule-overridesで指定されたルールIDごとの上書き設定
+    pub rule_overrides: Option<RuleOverrides>,
+    /// --level-overlayで指定されたルールIDと変更後のレベルのマップ
+    pub level_overlay: HashMap<String, String>,
+    /// 読み込み済みのルールIDと読み込み元のマップ。異なる読み込み元の同じIDのルールを除外するために使う
+    loaded_rule_ids: HashMap<String, String>,
+    /// 読み込み中の読み込み元(-rで指定されたフォルダ、ファイル、ルールバンドル)
+    rule_source: String,
 }
 
 impl Default for ParseYaml {
@@ -37,9 +56,17 @@ impl ParseYaml {
             rule_load_cnt: HashMap::from([
                 ("excluded".to_string(), 0_u128),
                 ("noisy".to_string(), 0_u128),
+                ("filtered".to_string(), 0_u128),
             ]),
             rule_status_cnt: HashMap::from([("deprecated".to_string(), 0_u128)]),
             errorrule_count: 0,
+            rule_cache: None,
+            filter_rules: Vec::new(),
+            logsource_mapping: None,
+            rule_overrides: None,
+            level_overlay: HashMap::new(),
+            loaded_rule_ids: HashMap::new(),
+            rule_source: String::default(),
         }
     }
 
@@ -56,6 +83,14 @@ impl ParseYaml {
         Ok(file_content)
     }
 
+    /// ルールファイルの内容をYAMLとしてパースする。--rule-cacheが指定されている場合はキャッシュを利用する
+    fn load_yaml_docs(&mut self, path: &Path, content: &str) -> Result<Vec<Yaml>, ScanError> {
+        match self.rule_cache.as_mut() {
+            Some(cache) => cache.load_yaml_docs(path, content),
+            None => YamlLoader::load_from_str(content),
+        }
+    }
+
     pub fn read_dir<P: AsRef<Path>>(
         &mut self,
         path: P,
@@ -114,7 +149,7 @@ impl ParseYaml {
             }
 
             // ここも個別のファイルの読み込みは即終了としない。
-            let yaml_contents = YamlLoader::load_from_str(&read_content.unwrap());
+            let yaml_contents = self.load_yaml_docs(path.as_ref(), &read_content.unwrap());
             if yaml_contents.is_err() {
                 let errmsg = format!(
                     "Failed to parse yml: {}\n{} ",
@@ -200,7 +235,7 @@ impl ParseYaml {
                 }
 
                 // ここも個別のファイルの読み込みは即終了としない。
-                let yaml_contents = YamlLoader::load_from_str(&read_content.unwrap());
+                let yaml_contents = self.load_yaml_docs(&entry.path(), &read_content.unwrap());
                 if yaml_contents.is_err() {
                     let errmsg = format!(
                         "Failed to parse yml: {}\n{} ",
@@ -229,9 +264,114 @@ impl ParseYaml {
             })?;
         }
 
+        self.add_rule_docs(yaml_docs, level, exclude_ids);
+        io::Result::Ok(String::default())
+    }
+
+    /// 読み込んだルールのうち、除外設定やレベルの条件に一致するものをfilesに追加する
+    fn add_rule_docs(
+        &mut self,
+        yaml_docs: Vec<(String, Yaml)>,
+        level: &str,
+        exclude_ids: &RuleExclude,
+    ) {
         let files: Vec<(String, Yaml)> = yaml_docs
             .into_iter()
             .filter_map(|(filepath, yaml_doc)| {
+                // フィルタールールは検知ルールとしては読み込まず、対象のルールの初期化時に適用する
+                if yaml_doc["filter"].as_hash().is_some() && yaml_doc["detection"].is_badvalue() {
+                    let entry = self
+                        .rule_load_cnt
+                        .entry("suppression filter".to_string())
+                        .or_insert(0);
+                    *entry += 1;
+                    if configs::CONFIG.read().unwrap().args.verbose {
+                        println!("Loaded filter rule path: {}", filepath);
+                    }
+                    self.filter_rules.push((filepath, yaml_doc));
+                    return Option::None;
+                }
+
+                // 異なる読み込み元で同じIDのルールがある場合は、集計の前に後の読み込み元のルールを除外する
+                if let Some(rule_id) = yaml_doc["id"].as_str() {
+                    let prev_source = self
+                        .loaded_rule_ids
+                        .entry(rule_id.to_string())
+                        .or_insert_with(|| self.rule_source.to_owned());
+                    if *prev_source != self.rule_source {
+                        let errmsg = format!(
+                            "Skipped the rule with the duplicated id {}: {} (The rule in {} is used.)",
+                            rule_id, filepath, prev_source
+                        );
+                        if configs::CONFIG.read().unwrap().args.verbose {
+                            AlertMessage::warn(&errmsg).ok();
+                        }
+                        if !*QUIET_ERRORS_FLAG {
+                            ERROR_LOG_STACK
+                                .lock()
+                                .unwrap()
+                                .push(format!("[WARN] {}", errmsg));
+                        }
+                        let entry = self
+                            .rule_load_cnt
+                            .entry("duplicated".to_string())
+                            .or_insert(0);
+                        *entry += 1;
+                        return Option::None;
+                    }
+                }
+
+                // Channelの条件が無いSigmaルールは、logsourceからChannelとEventIDの条件を追加する
+                let yaml_doc = match self.logsource_mapping.as_ref() {
+                    Some(logsource_mapping) => {
+                        let (yaml_doc, applied) = logsource_mapping.apply(yaml_doc);
+                        if applied {
+                            let entry = self
+                                .rule_load_cnt
+                                .entry("logsource mapped".to_string())
+                                .or_insert(0);
+                            *entry += 1;
+                        }
+                        yaml_doc
+                    }
+                    None => yaml_doc,
+                };
+
+                // --rule-overridesの上書き設定があるルールは、除外設定などの判定の前にマージする
+                let yaml_doc = match self.rule_overrides.as_mut() {
+                    Some(rule_overrides) => {
+                        let (yaml_doc, applied) = rule_overrides.apply(yaml_doc);
+                        if applied {
+                            let entry = self
+                                .rule_load_cnt
+                                .entry("overridden".to_string())
+                                .or_insert(0);
+                            *entry += 1;
+                            if configs::CONFIG.read().unwrap().args.verbose {
+                                println!("Applied the rule override: {}", filepath);
+                            }
+                        }
+                        yaml_doc
+                    }
+                    None => yaml_doc,
+                };
+
+                // --level-overlayの指定があるルールは、除外設定やレベルの判定の前にレベルを変更する
+                let yaml_doc = match self
+                    .level_overlay
+                    .get(yaml_doc["id"].as_str().unwrap_or_default())
+                {
+                    Some(level) => {
+                        let entry = self
+                            .rule_load_cnt
+                            .entry("level tuned".to_string())
+                            .or_insert(0);
+                        *entry += 1;
+                        LevelTuning::apply_level(yaml_doc, level)
+                    }
+                    None => yaml_doc,
+                };
+
                 //除外されたルールは無視する
                 let rule_id = &yaml_doc["id"].as_str();
                 if rule_id.is_some() {
@@ -271,6 +411,16 @@ impl ParseYaml {
                     }
                 }
 
+                // タグ、ルールID、作者、logsourceの条件に一致しないルールは無視する
+                if exclude_ids.is_filtered(&yaml_doc) {
+                    let entry = self
+                        .rule_load_cnt
+                        .entry("filtered".to_string())
+                        .or_insert(0);
+                    *entry += 1;
+                    return Option::None;
+                }
+
                 self.rulecounter.insert(
                     yaml_doc["ruletype"].as_str().unwrap_or("Other").to_string(),
                     self.rulecounter
@@ -309,8 +459,111 @@ impl ParseYaml {
             })
             .collect();
         self.files.extend(files);
+    }
+
+    /// 複数のルールのフォルダ、ファイル、ルールバンドル(zip, tar.gz)を読み込む。
+    /// 異なる読み込み元で同じIDのルールがある場合は、先に指定された読み込み元のルールを優先する
+    pub fn read_rules(
+        &mut self,
+        paths: &[PathBuf],
+        level: &str,
+        exclude_ids: &RuleExclude,
+    ) -> io::Result<String> {
+        for path in paths {
+            self.rule_source = path.display().to_string();
+            if is_rule_bundle(path) {
+                self.read_bundle(path, level, exclude_ids)?;
+            } else {
+                self.read_dir(path, level, exclude_ids)?;
+            }
+        }
         io::Result::Ok(String::default())
     }
+
+    /// zipもしくはtar.gz形式のルールバンドルに含まれるymlファイルを読み込む
+    pub fn read_bundle(
+        &mut self,
+        path: &Path,
+        level: &str,
+        exclude_ids: &RuleExclude,
+    ) -> io::Result<String> {
+        let contents = match read_bundle_files(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                let errmsg = format!("Failed to read the rule bundle: {}\n{} ", path.display(), e);
+                if configs::CONFIG.read().unwrap().args.verbose {
+                    AlertMessage::alert(&errmsg)?;
+                }
+                if !*QUIET_ERRORS_FLAG {
+                    ERROR_LOG_STACK
+                        .lock()
+                        .unwrap()
+                        .push(format!("[ERROR] {}", errmsg));
+                }
+                return io::Result::Ok(String::default());
+            }
+        };
+
+        let mut yaml_docs = vec![];
+        for (name, content) in contents {
+            // バンドル内のファイルはバンドルのパスとバンドル内のパスを連結したパスで扱う
+            let filepath = Path::new(path).join(&name);
+            match self.load_yaml_docs(&filepath, &content) {
+                Ok(docs) => yaml_docs.extend(
+                    docs.into_iter()
+                        .map(|doc| (filepath.display().to_string(), doc)),
+                ),
+                Err(e) => {
+                    let errmsg = format!("Failed to parse yml: {}\n{} ", filepath.display(), e);
+                    if configs::CONFIG.read().unwrap().args.verbose {
+                        AlertMessage::warn(&errmsg)?;
+                    }
+                    if !*QUIET_ERRORS_FLAG {
+                        ERROR_LOG_STACK
+                            .lock()
+                            .unwrap()
+                            .push(format!("[WARN] {}", errmsg));
+                    }
+                    self.errorrule_count += 1;
+                }
+            }
+        }
+        self.add_rule_docs(yaml_docs, level, exclude_ids);
+        io::Result::Ok(String::default())
+    }
+}
+
+/// ルールバンドル(zip, tar.gz, tgz)のファイルかを判定する
+pub fn is_rule_bundle(path: &Path) -> bool {
+    let filename = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    path.is_file()
+        && (filename.ends_with(".zip")
+            || filename.ends_with(".tar.gz")
+            || filename.ends_with(".tgz"))
+}
+
+/// ルールバンドルに含まれるymlファイルのパスと内容を取得する。.gitフォルダ内のファイルは無視する
+pub fn read_bundle_files(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let mut ret = vec![];
+    for (name, content) in read_archive_entries(path)? {
+        if !name.ends_with(".yml") || name.split(['/', '\\']).any(|dir| dir == ".git") {
+            continue;
+        }
+        let content = String::from_utf8(content).map_err(|e| format!("{}: {}", name, e))?;
+        ret.push((name, content));
+    }
+    Ok(ret)
+}
+
+/// zipもしくはtar.gz形式のアーカイブに含まれる全てのファイルのパスと内容を取得する
+pub fn read_archive_entries(path: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut ret = vec![];
+    archive::for_each_archive_file(path, |_| true, |name, content| ret.push((name, content)))?;
+    Ok(ret)
 }
 
 #[cfg(test)]
@@ -322,7 +575,7 @@ mod tests {
     use crate::yaml;
     use crate::yaml::RuleExclude;
     use hashbrown::HashMap;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
     use yaml_rust::YamlLoader;
 
     #[test]
@@ -346,6 +599,7 @@ mod tests {
         let mut yaml = yaml::ParseYaml::new();
         let exclude_ids = RuleExclude {
             no_use_rule: HashMap::new(),
+            ..RuleExclude::default()
         };
         let _ = &yaml.read_dir("test_files/rules/yaml/", &String::default(), &exclude_ids);
         assert_ne!(yaml.files.len(), 0);
@@ -463,4 +717,96 @@ mod tests {
             1
         );
     }
+
+    #[test]
+    fn test_read_rules_with_duplicated_id() {
+        let mut yaml = yaml::ParseYaml::new();
+        let paths = [
+            PathBuf::from("test_files/rules/test_rules"),
+            PathBuf::from("test_files/rules/bundles/rules.zip"),
+        ];
+        yaml.read_rules(&paths, "", &RuleExclude::default())
+            .unwrap();
+        // 同じIDのルールは先に指定されたフォルダのルールが優先される
+        let titles: Vec<&str> = yaml
+            .files
+            .iter()
+            .map(|(_, doc)| doc["title"].as_str().unwrap())
+            .collect();
+        assert_eq!(titles, vec!["Suspicious process creation", "Logon"]);
+        assert_eq!(yaml.rule_load_cnt.get("duplicated").unwrap().to_owned(), 1);
+        // 除外した重複ルールはルールの種類やステータスの集計に含めない
+        assert_eq!(yaml.rulecounter.values().sum::<u128>(), 2);
+        assert_eq!(yaml.rule_status_cnt.values().sum::<u128>(), 2);
+        assert_eq!(
+            yaml.files[1].0,
+            Path::new("test_files/rules/bundles/rules.zip")
+                .join("bundle/logon.yml")
+                .display()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_read_bundle() {
+        assert!(yaml::is_rule_bundle(Path::new(
+            "test_files/rules/bundles/rules.tar.gz"
+        )));
+        assert!(!yaml::is_rule_bundle(Path::new("test_files/rules/bundles")));
+
+        let mut yaml = yaml::ParseYaml::new();
+        yaml.read_bundle(
+            Path::new("test_files/rules/bundles/rules.tar.gz"),
+            "",
+            &RuleExclude::default(),
+        )
+        .unwrap();
+        let mut titles: Vec<&str> = yaml
+            .files
+            .iter()
+            .map(|(_, doc)| doc["title"].as_str().unwrap())
+            .collect();
+        titles.sort_unstable();
+        assert_eq!(
+            titles,
+            vec!["Logon", "Suspicious process creation (bundle)"]
+        );
+    }
+
+    #[test]
+    fn test_read_dir_with_level_overlay() {
+        let mut yaml = yaml::ParseYaml::new();
+        yaml.level_overlay.insert(
+            "6f2a4c3e-0d1b-4b7a-9c55-1e2f3a4b5c6d".to_string(),
+            "critical".to_string(),
+        );
+        let path = Path::new("test_files/rules/test_rules");
+        yaml.read_dir(path, "CRITICAL", &RuleExclude::default())
+            .unwrap();
+        // ルールファイルのレベルはmediumだが、overlayのレベルで判定される
+        assert_eq!(yaml.files.len(), 1);
+        assert_eq!(yaml.files[0].1["level"].as_str().unwrap(), "critical");
+        assert_eq!(yaml.rule_load_cnt.get("level tuned").unwrap().to_owned(), 1);
+    }
+
+    #[test]
+    fn test_read_dir_with_filter_rules() {
+        let mut yaml = yaml::ParseYaml::new();
+        let paths = [
+            PathBuf::from("test_files/rules/test_rules"),
+            PathBuf::from("test_files/rules/filter_rules"),
+        ];
+        yaml.read_rules(&paths, "INFORMATIONAL", &RuleExclude::default())
+            .unwrap();
+        // フィルタールールは検知ルールとしては読み込まない
+        assert_eq!(yaml.files.len(), 1);
+        assert_eq!(yaml.filter_rules.len(), 1);
+        assert_eq!(
+            yaml.rule_load_cnt
+                .get("suppression filter")
+                .unwrap()
+                .to_owned(),
+            1
+        );
+    }
 }